@@ -0,0 +1,87 @@
+//! Time per frame with every line redrawn, drawn one line at a time and,
+//! with the `parallel` feature, all at once when VBlank starts. Run it with
+//! `cargo bench --features parallel`.
+use std::time::{Duration, Instant};
+use trashgb::Emulator;
+
+const FRAMES: u32 = 600;
+
+/// Increments SCX forever, so every frame has to be drawn again
+#[rustfmt::skip]
+const PROGRAM: [u8; 6] = [
+    0x21, 0x43, 0xFF, /* ld hl, SCX */
+    0x34,             /* inc [hl] */
+    0x18, 0xFD,       /* jr -3 */
+];
+
+/// The background, window and 40 objects filled with noisy tiles
+fn busy_emulator() -> Emulator {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+    /* Valid checksums keep the bad dump warnings out of the results */
+    rom[0x14D] = rom[0x134..0x14D]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+    let global = rom
+        .iter()
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    rom[0x14E..0x150].copy_from_slice(&global.to_be_bytes());
+    let mut emulator = Emulator::new(&rom).unwrap();
+    let mmu = &mut emulator.cpu_mut().mmu;
+
+    /* VRAM and OAM are only free to write with the LCD off */
+    mmu.write_byte(0xFF40, 0x00);
+    let mut seed: u32 = 1;
+    for address in 0x8000..0x9800 {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        mmu.write_byte(address, (seed >> 16) as u8);
+    }
+    for address in 0x9800..0xA000 {
+        mmu.write_byte(address, address as u8);
+    }
+    for index in 0..40 {
+        let sprite = 0xFE00 + index * 4;
+        mmu.write_byte(sprite, 16 + (index as u8 * 7) % 144);
+        mmu.write_byte(sprite + 1, 8 + (index as u8 * 13) % 160);
+        mmu.write_byte(sprite + 2, index as u8);
+    }
+    mmu.write_byte(0xFF47, 0b1110_0100);
+    mmu.write_byte(0xFF48, 0b1110_0100);
+    mmu.write_byte(0xFF4A, 72);
+    mmu.write_byte(0xFF4B, 87);
+    /* LCD, window from 0x9C00, tiles from 0x8000, objects and background on */
+    mmu.write_byte(0xFF40, 0b1111_0011);
+    emulator
+}
+
+fn time_frames(emulator: &mut Emulator) -> Duration {
+    let mut frame = vec![0; 160 * 144 * 4];
+    /* Warm up the caches and the thread pool */
+    for _ in 0..60 {
+        emulator.step_frame(&mut frame);
+    }
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        emulator.step_frame(&mut frame);
+    }
+    start.elapsed() / FRAMES
+}
+
+fn main() {
+    let serial = time_frames(&mut busy_emulator());
+    println!("serial:   {:?} per frame", serial);
+
+    #[cfg(feature = "parallel")]
+    {
+        let mut emulator = busy_emulator();
+        emulator.cpu_mut().set_parallel_render(true);
+        let parallel = time_frames(&mut emulator);
+        println!(
+            "parallel: {:?} per frame, {:.2}x",
+            parallel,
+            serial.as_secs_f64() / parallel.as_secs_f64()
+        );
+    }
+    #[cfg(not(feature = "parallel"))]
+    println!("parallel: build with --features parallel to compare");
+}
@@ -0,0 +1,828 @@
+use crate::mmu::Mmu;
+use std::collections::VecDeque;
+
+/// T-cycles per second
+const CLOCK_RATE: u32 = 4_194_304;
+
+/// Samples kept until drained, the oldest are dropped past this. A quarter
+/// second of stereo at 48kHz.
+const BUFFER_LEN: usize = 24_000;
+
+/* Waveforms for 12.5%, 25%, 50% and 75% duty, played from the high bit */
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/* NR43 divisor codes, in T-cycles */
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Turns a channel off once it runs out, when enabled by NRx4 bit 6
+#[derive(Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    /// Returns whether the counter just ran out
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = value & 0b0000_1000 != 0;
+        self.period = value & 0b0000_0111;
+    }
+
+    /// The upper five bits of NRx2 power the channel's DAC
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Channel 1's frequency sweep, channel 2 leaves it disabled
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) {
+        self.period = value >> 4 & 0b0111;
+        self.negate = value & 0b0000_1000 != 0;
+        self.shift = value & 0b0000_0111;
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    fn reload(&mut self) {
+        /* A period of 0 is treated as 8 */
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+}
+
+#[derive(Default)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    step: u8,
+    frequency: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Sweep,
+}
+
+impl Square {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        if self.length.counter == 0 {
+            self.length.counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.sweep.shadow = self.frequency;
+        self.sweep.reload();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 && self.sweep.next_frequency() > 2047 {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload();
+        if !self.sweep.enabled || self.sweep.period == 0 {
+            return;
+        }
+        let frequency = self.sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if self.sweep.shift != 0 {
+            self.sweep.shadow = frequency;
+            self.frequency = frequency;
+            /* The new frequency is checked for overflow again right away */
+            if self.sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.step = (self.step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        let high = DUTY_PATTERNS[self.duty as usize] >> (7 - self.step) & 1;
+        if self.enabled {
+            high * self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    /// NR32 output level, 0 mutes and 1-3 shift the sample right by 0-2
+    level: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    length: Length,
+}
+
+impl Wave {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length.counter == 0 {
+            self.length.counter = 256;
+        }
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn tick(&mut self, mut cycles: u32, wave_ram: &[u8]) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            /* Two samples per byte, high nibble first */
+            let byte = wave_ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.level == 0 {
+            return 0;
+        }
+        self.sample >> (self.level - 1)
+    }
+}
+
+#[derive(Default)]
+struct Noise {
+    enabled: bool,
+    /// 15-bit linear feedback shift register, outputs the inverse of bit 0
+    lfsr: u16,
+    shift: u8,
+    /// Feeds back into bit 6 too, for a 7-bit sequence
+    narrow: bool,
+    divisor: u8,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn write(&mut self, value: u8) {
+        self.shift = value >> 4;
+        self.narrow = value & 0b0000_1000 != 0;
+        self.divisor = value & 0b0000_0111;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        if self.length.counter == 0 {
+            self.length.counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            let bit = (self.lfsr ^ self.lfsr >> 1) & 1;
+            self.lfsr = self.lfsr >> 1 | bit << 14;
+            if self.narrow {
+                self.lfsr = self.lfsr & !(1 << 6) | bit << 6;
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+/// How much charge the DMG's output capacitors keep each T-cycle
+pub const DMG_CHARGE_FACTOR: f32 = 0.999958;
+
+/// The four sound channels, mixed to interleaved stereo samples
+pub struct Apu {
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    powered: bool,
+    /// Frame sequencer step, advanced at 512Hz by DIV bit 4 falling
+    sequencer: u8,
+    div_bit: bool,
+    /// NR50 master volume for each side, 0-7
+    left_volume: u8,
+    right_volume: u8,
+    /// NR51, bits 4-7 send channels 1-4 left and bits 0-3 send them right
+    panning: u8,
+    /// Channels left out of the mix, bits 0-3 for channels 1-4
+    muted: u8,
+    /// Channels mixed on their own when any are set, like `muted`
+    soloed: u8,
+    sample_rate: u32,
+    /// T-cycles toward the next sample, scaled by the sample rate
+    sample_clock: u32,
+    /// Charge factor of the high-pass filter on each side, `None` to leave
+    /// the DC offset in
+    high_pass: Option<f32>,
+    /// Charge on the left and right capacitors
+    capacitors: [f32; 2],
+    samples: VecDeque<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            square1: Square::default(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            powered: false,
+            sequencer: 0,
+            div_bit: false,
+            left_volume: 0,
+            right_volume: 0,
+            panning: 0,
+            muted: 0,
+            soloed: 0,
+            sample_rate: 48_000,
+            sample_clock: 0,
+            high_pass: Some(DMG_CHARGE_FACTOR),
+            capacitors: [0.0; 2],
+            samples: VecDeque::with_capacity(BUFFER_LEN),
+        }
+    }
+
+    /// Powers the APU back off, keeping the sample rate, filter and what's
+    /// muted
+    pub fn reset(&mut self) {
+        let (sample_rate, high_pass) = (self.sample_rate, self.high_pass);
+        let (muted, soloed) = (self.muted, self.soloed);
+        *self = Apu::new();
+        self.sample_rate = sample_rate;
+        self.high_pass = high_pass;
+        self.muted = muted;
+        self.soloed = soloed;
+    }
+
+    /// Output sample rate in Hz, 48000 by default
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.sample_clock = 0;
+    }
+
+    /// Charge factor per T-cycle of the high-pass filter after the mixer,
+    /// `DMG_CHARGE_FACTOR` by default. `None` turns it off.
+    pub fn set_high_pass(&mut self, charge_factor: Option<f32>) {
+        self.high_pass = charge_factor;
+        self.capacitors = [0.0; 2];
+    }
+
+    /// Leaves channel `channel` (0-3 for channels 1-4) out of the mix. It
+    /// keeps running, only its output is dropped.
+    pub fn set_muted(&mut self, channel: usize, muted: bool) {
+        self.muted = (self.muted & !(1 << channel)) | (muted as u8) << channel;
+    }
+
+    pub fn muted(&self, channel: usize) -> bool {
+        self.muted & 1 << channel != 0
+    }
+
+    /// Mixes only the soloed channels while any are, ignoring `set_muted`
+    pub fn set_soloed(&mut self, channel: usize, soloed: bool) {
+        self.soloed = (self.soloed & !(1 << channel)) | (soloed as u8) << channel;
+    }
+
+    pub fn soloed(&self, channel: usize) -> bool {
+        self.soloed & 1 << channel != 0
+    }
+
+    /// Takes the samples generated so far, interleaved left and right
+    pub fn drain_samples(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+
+    /// Applies audio register writes and runs the channels for `cycles`
+    /// M-cycles
+    pub fn step(&mut self, mmu: &mut Mmu, cycles: u32) {
+        for (address, value) in mmu.take_audio_writes() {
+            self.write_register(address, value);
+        }
+
+        let div_bit = mmu.div_counter() & (1 << 12) != 0;
+        if self.div_bit && !div_bit {
+            self.clock_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        let cycles = cycles * 4;
+        self.square1.tick(cycles);
+        self.square2.tick(cycles);
+        self.wave.tick(cycles, mmu.wave_ram());
+        self.noise.tick(cycles);
+        mmu.set_audio_status(self.status());
+        mmu.set_wave_byte(self.wave.enabled.then_some(self.wave.position / 2));
+
+        self.sample_clock += cycles * self.sample_rate;
+        while self.sample_clock >= CLOCK_RATE {
+            self.sample_clock -= CLOCK_RATE;
+            self.push_sample();
+        }
+    }
+
+    /// Channels currently playing, as NR52 bits 0-3
+    fn status(&self) -> u8 {
+        [
+            self.square1.enabled,
+            self.square2.enabled,
+            self.wave.enabled,
+            self.noise.enabled,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |status, (bit, &enabled)| status | (enabled as u8) << bit)
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF10 => self.square1.sweep.write(value),
+            0xFF11 => {
+                self.square1.duty = value >> 6;
+                self.square1.length.counter = 64 - (value & 0x3F) as u16;
+            }
+            0xFF12 => {
+                self.square1.envelope.write(value);
+                self.square1.enabled &= self.square1.envelope.dac_enabled();
+            }
+            0xFF13 => self.square1.frequency = self.square1.frequency & 0x700 | value as u16,
+            0xFF14 => {
+                self.square1.frequency =
+                    self.square1.frequency & 0xFF | ((value & 0b111) as u16) << 8;
+                self.square1.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.square1.trigger();
+                }
+            }
+            0xFF16 => {
+                self.square2.duty = value >> 6;
+                self.square2.length.counter = 64 - (value & 0x3F) as u16;
+            }
+            0xFF17 => {
+                self.square2.envelope.write(value);
+                self.square2.enabled &= self.square2.envelope.dac_enabled();
+            }
+            0xFF18 => self.square2.frequency = self.square2.frequency & 0x700 | value as u16,
+            0xFF19 => {
+                self.square2.frequency =
+                    self.square2.frequency & 0xFF | ((value & 0b111) as u16) << 8;
+                self.square2.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.square2.trigger();
+                }
+            }
+            0xFF1A => {
+                self.wave.dac_enabled = value & 0b1000_0000 != 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            }
+            0xFF1B => self.wave.length.counter = 256 - value as u16,
+            0xFF1C => self.wave.level = value >> 5 & 0b11,
+            0xFF1D => self.wave.frequency = self.wave.frequency & 0x700 | value as u16,
+            0xFF1E => {
+                self.wave.frequency = self.wave.frequency & 0xFF | ((value & 0b111) as u16) << 8;
+                self.wave.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.wave.trigger();
+                }
+            }
+            0xFF20 => self.noise.length.counter = 64 - (value & 0x3F) as u16,
+            0xFF21 => {
+                self.noise.envelope.write(value);
+                self.noise.enabled &= self.noise.envelope.dac_enabled();
+            }
+            0xFF22 => self.noise.write(value),
+            0xFF23 => {
+                self.noise.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.noise.trigger();
+                }
+            }
+            0xFF24 => {
+                self.left_volume = value >> 4 & 0b111;
+                self.right_volume = value & 0b111;
+            }
+            0xFF25 => self.panning = value,
+            0xFF26 => {
+                let powered = value & 0b1000_0000 != 0;
+                if !powered {
+                    /* Powering off clears every register, Mmu does the same
+                     * for the stored values */
+                    self.square1 = Square::default();
+                    self.square2 = Square::default();
+                    self.wave = Wave::default();
+                    self.noise = Noise::default();
+                    self.left_volume = 0;
+                    self.right_volume = 0;
+                    self.panning = 0;
+                } else if !self.powered {
+                    self.sequencer = 0;
+                }
+                self.powered = powered;
+            }
+            _ => {}
+        }
+    }
+
+    /// Lengths are clocked on even steps, sweep on 2 and 6, envelopes on 7
+    fn clock_sequencer(&mut self) {
+        if self.sequencer & 1 == 0 {
+            if self.square1.length.clock() {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.clock() {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.sequencer == 2 || self.sequencer == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.sequencer == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.sequencer = (self.sequencer + 1) % 8;
+    }
+
+    fn push_sample(&mut self) {
+        let dacs = [
+            self.square1.envelope.dac_enabled(),
+            self.square2.envelope.dac_enabled(),
+            self.wave.dac_enabled,
+            self.noise.envelope.dac_enabled(),
+        ];
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let audible = if self.soloed != 0 {
+            self.soloed
+        } else {
+            !self.muted
+        };
+        let (mut left, mut right) = (0.0, 0.0);
+        for (channel, (&dac, &output)) in dacs.iter().zip(outputs.iter()).enumerate() {
+            if audible & 1 << channel == 0 {
+                continue;
+            }
+            /* Each DAC maps 0-15 to -1.0-1.0, and outputs nothing when off */
+            let analog = if dac { output as f32 / 7.5 - 1.0 } else { 0.0 };
+            if self.panning & 0x10 << channel != 0 {
+                left += analog;
+            }
+            if self.panning & 1 << channel != 0 {
+                right += analog;
+            }
+        }
+        left *= (self.left_volume + 1) as f32 / 8.0 / 4.0;
+        right *= (self.right_volume + 1) as f32 / 8.0 / 4.0;
+        if let Some(factor) = self.high_pass {
+            let charge = factor.powf(CLOCK_RATE as f32 / self.sample_rate as f32);
+            let dacs_enabled = dacs.contains(&true);
+            left = high_pass(&mut self.capacitors[0], left, charge, dacs_enabled);
+            right = high_pass(&mut self.capacitors[1], right, charge, dacs_enabled);
+        }
+
+        if self.samples.len() >= BUFFER_LEN {
+            self.samples.drain(..2);
+        }
+        self.samples.push_back(left);
+        self.samples.push_back(right);
+    }
+}
+
+/// Passes `sample` through a capacitor that keeps `charge` of what it had
+/// each sample, removing the DACs' DC offset. It's silent with every DAC off.
+fn high_pass(capacitor: &mut f32, sample: f32, charge: f32, dacs_enabled: bool) -> f32 {
+    if !dacs_enabled {
+        return 0.0;
+    }
+    let output = sample - *capacitor;
+    *capacitor = sample - output * charge;
+    output
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut Apu, mmu: &mut Mmu, cycles: u32) {
+        for _ in 0..cycles {
+            mmu.increment_timer(1);
+            apu.step(mmu, 1);
+        }
+    }
+
+    fn powered_on() -> (Apu, Mmu) {
+        let mut apu = Apu::new();
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF26, 0x80);
+        mmu.write_byte(0xFF24, 0x77);
+        apu.step(&mut mmu, 1);
+        (apu, mmu)
+    }
+
+    #[test]
+    fn length_counter_stops_channel() {
+        let (mut apu, mut mmu) = powered_on();
+        /* Length 1, full volume, trigger with the length enabled */
+        mmu.write_byte(0xFF11, 0x3F);
+        mmu.write_byte(0xFF12, 0xF0);
+        mmu.write_byte(0xFF14, 0xC0);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0b0001);
+
+        /* Lengths are clocked at 256Hz, every 4096 M-cycles */
+        run(&mut apu, &mut mmu, 4096);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0);
+
+        /* Without the length enabled it keeps playing */
+        mmu.write_byte(0xFF11, 0x3F);
+        mmu.write_byte(0xFF14, 0x80);
+        run(&mut apu, &mut mmu, 8192);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0b0001);
+    }
+
+    #[test]
+    fn dac_off_disables_channel() {
+        let (mut apu, mut mmu) = powered_on();
+        mmu.write_byte(0xFF1A, 0x80);
+        mmu.write_byte(0xFF1E, 0x80);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0b0100);
+
+        mmu.write_byte(0xFF1A, 0x00);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0);
+
+        /* Triggering with the DAC off doesn't start it */
+        mmu.write_byte(0xFF21, 0x00);
+        mmu.write_byte(0xFF23, 0x80);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let (mut apu, mut mmu) = powered_on();
+        mmu.write_byte(0xFF12, 0xF0);
+        mmu.write_byte(0xFF14, 0x80);
+        run(&mut apu, &mut mmu, 1);
+
+        mmu.write_byte(0xFF26, 0x00);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF26), 0x70);
+        assert_eq!(mmu.read_byte(0xFF12), 0x00);
+        assert_eq!(mmu.read_byte(0xFF24), 0x00);
+
+        /* Registers ignore writes until it's powered back on */
+        mmu.write_byte(0xFF12, 0xF0);
+        mmu.write_byte(0xFF14, 0x80);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF12), 0x00);
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0);
+    }
+
+    #[test]
+    fn wave_ram_is_locked_while_channel_3_plays() {
+        let (mut apu, mut mmu) = powered_on();
+        for (offset, address) in (0xFF30..=0xFF3F).enumerate() {
+            mmu.write_byte(address, offset as u8 * 0x11);
+        }
+        assert_eq!(mmu.read_byte(0xFF35), 0x55);
+
+        /* The slowest frequency moves to the next sample every 1024 M-cycles */
+        mmu.write_byte(0xFF1A, 0x80);
+        mmu.write_byte(0xFF1D, 0x00);
+        mmu.write_byte(0xFF1E, 0x80);
+        run(&mut apu, &mut mmu, 1024 * 3);
+        /* Samples 0-3 have played, it's on the second byte */
+        assert_eq!(mmu.read_byte(0xFF35), 0x11);
+        assert_eq!(mmu.read_byte(0xFF3F), 0x11);
+        mmu.write_byte(0xFF3A, 0xAB);
+        assert_eq!(mmu.read_byte(0xFF30), 0xAB);
+
+        /* Turning the DAC off stops it and unlocks wave RAM */
+        mmu.write_byte(0xFF1A, 0x00);
+        run(&mut apu, &mut mmu, 1);
+        assert_eq!(mmu.read_byte(0xFF31), 0xAB);
+        assert_eq!(mmu.read_byte(0xFF3A), 0xAA);
+    }
+
+    #[test]
+    fn samples_follow_the_sample_rate() {
+        let (mut apu, mut mmu) = powered_on();
+        apu.drain_samples();
+        apu.set_sample_rate(32_768);
+
+        /* 1/128 of a second is 256 stereo samples */
+        run(&mut apu, &mut mmu, CLOCK_RATE / 4 / 128);
+        assert_eq!(apu.drain_samples().len(), 512);
+        assert!(apu.drain_samples().is_empty());
+    }
+
+    #[test]
+    fn samples_are_panned() {
+        let (mut apu, mut mmu) = powered_on();
+        /* Channel 2 left only, 50% duty at full volume */
+        mmu.write_byte(0xFF25, 0x20);
+        mmu.write_byte(0xFF16, 0x80);
+        mmu.write_byte(0xFF17, 0xF0);
+        mmu.write_byte(0xFF18, 0x00);
+        mmu.write_byte(0xFF19, 0x87);
+        run(&mut apu, &mut mmu, 4096);
+
+        let samples = apu.drain_samples();
+        let (left, right): (Vec<f32>, Vec<f32>) = samples
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .unzip();
+        assert!(right.iter().all(|&sample| sample == 0.0));
+        assert!(left.iter().any(|&sample| sample > 0.0));
+        assert!(left.iter().any(|&sample| sample < 0.0));
+    }
+
+    #[test]
+    fn high_pass_removes_dc_offset() {
+        /* Channel 1 left at volume 0, its DAC on puts out a constant -1.0 */
+        let (mut apu, mut mmu) = powered_on();
+        mmu.write_byte(0xFF25, 0x10);
+        mmu.write_byte(0xFF12, 0x08);
+        mmu.write_byte(0xFF14, 0x80);
+        run(&mut apu, &mut mmu, 64);
+        let start = apu.drain_samples()[0];
+        assert!(start < -0.1);
+
+        run(&mut apu, &mut mmu, CLOCK_RATE / 4 / 10);
+        let samples = apu.drain_samples();
+        let end = samples[samples.len() - 2];
+        assert!(end.abs() < 0.01, "{} is still offset", end);
+
+        /* Without the filter it stays */
+        let (mut apu, mut mmu) = powered_on();
+        apu.set_high_pass(None);
+        mmu.write_byte(0xFF25, 0x10);
+        mmu.write_byte(0xFF12, 0x08);
+        mmu.write_byte(0xFF14, 0x80);
+        run(&mut apu, &mut mmu, CLOCK_RATE / 4 / 10);
+        let samples = apu.drain_samples();
+        assert_eq!(samples[samples.len() - 2], start);
+    }
+
+    #[test]
+    fn muted_channels_are_left_out() {
+        let (mut apu, mut mmu) = powered_on();
+        apu.set_high_pass(None);
+        /* Channel 2 left at full volume, channel 1 right at volume 0 */
+        mmu.write_byte(0xFF25, 0x21);
+        mmu.write_byte(0xFF12, 0x08);
+        mmu.write_byte(0xFF14, 0x80);
+        mmu.write_byte(0xFF16, 0x80);
+        mmu.write_byte(0xFF17, 0xF0);
+        mmu.write_byte(0xFF19, 0x87);
+        apu.set_muted(1, true);
+        run(&mut apu, &mut mmu, 4096);
+        let samples = apu.drain_samples();
+        assert!(samples.iter().step_by(2).all(|&sample| sample == 0.0));
+        assert!(samples
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|&sample| sample != 0.0));
+
+        /* It kept playing while muted */
+        assert_eq!(mmu.read_byte(0xFF26) & 0x0F, 0b0011);
+
+        /* Soloing channel 2 leaves channel 1 out instead */
+        apu.set_soloed(1, true);
+        run(&mut apu, &mut mmu, 4096);
+        let samples = apu.drain_samples();
+        assert!(samples.iter().step_by(2).any(|&sample| sample != 0.0));
+        assert!(samples
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn samples_are_bounded() {
+        let (mut apu, mut mmu) = powered_on();
+        run(&mut apu, &mut mmu, CLOCK_RATE / 4);
+        assert_eq!(apu.drain_samples().len(), BUFFER_LEN);
+    }
+}
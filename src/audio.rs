@@ -0,0 +1,87 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Plays interleaved stereo samples on the default output device
+pub struct AudioOutput {
+    /* Playback stops when the stream is dropped */
+    _stream: cpal::Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl AudioOutput {
+    /// Opens the default output device, `None` if there isn't one usable
+    pub fn open() -> Option<Self> {
+        let device = cpal::default_host().default_output_device()?;
+        let config = match device.default_output_config() {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("Couldn't get the audio output config: {}", err);
+                return None;
+            }
+        };
+        if config.sample_format() != cpal::SampleFormat::F32 {
+            eprintln!("Unsupported audio sample format {}", config.sample_format());
+            return None;
+        }
+        let sample_rate = config.sample_rate().0;
+        let channels = config.channels() as usize;
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let playing = Arc::clone(&queue);
+        let stream = device.build_output_stream(
+            &config.into(),
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let mut queue = playing.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    /* Silence on underrun, mono devices get the average */
+                    let left = queue.pop_front().unwrap_or(0.0);
+                    let right = queue.pop_front().unwrap_or(0.0);
+                    match frame {
+                        [mono] => *mono = (left + right) / 2.0,
+                        [l, r, rest @ ..] => {
+                            *l = left;
+                            *r = right;
+                            rest.fill(0.0);
+                        }
+                        [] => {}
+                    }
+                }
+            },
+            |err| eprintln!("Audio output error: {}", err),
+            None,
+        );
+        let stream = match stream.map_err(|err| err.to_string()).and_then(|stream| {
+            stream.play().map_err(|err| err.to_string())?;
+            Ok(stream)
+        }) {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Couldn't start audio output: {}", err);
+                return None;
+            }
+        };
+        Some(AudioOutput {
+            _stream: stream,
+            queue,
+            sample_rate,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Queues samples to play, dropping the oldest to keep latency under
+    /// 100ms
+    pub fn push(&self, samples: &[f32]) {
+        let limit = self.sample_rate as usize / 10 * 2;
+        let mut queue = self.queue.lock().unwrap();
+        queue.extend(samples);
+        if queue.len() > limit {
+            let excess = (queue.len() - limit) & !1;
+            queue.drain(..excess);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io::Read;
+
+#[derive(Debug)]
+pub enum BorderError {
+    Decode(png::DecodingError),
+    TooSmall { width: u32, height: u32 },
+}
+
+impl fmt::Display for BorderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "Couldn't decode the border image: {}", err),
+            Self::TooSmall { width, height } => write!(
+                f,
+                "The border image is {}x{}, it must be at least 160x144",
+                width, height
+            ),
+        }
+    }
+}
+
+impl From<png::DecodingError> for BorderError {
+    fn from(err: png::DecodingError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+/// A static image framing the screen, like the Super Game Boy's 256x224
+/// borders. The 160x144 screen is drawn over its center.
+pub struct Border {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+impl Border {
+    pub fn from_png(image: impl Read) -> Result<Self, BorderError> {
+        let mut decoder = png::Decoder::new(image);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+        if info.width < 160 || info.height < 144 {
+            return Err(BorderError::TooSmall {
+                width: info.width,
+                height: info.height,
+            });
+        }
+        let buffer = &buffer[..info.buffer_size()];
+        let rgba = match info.color_type {
+            png::ColorType::Rgba => buffer.to_vec(),
+            png::ColorType::Rgb => buffer
+                .chunks_exact(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buffer
+                .chunks_exact(2)
+                .flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]])
+                .collect(),
+            /* Indexed images are expanded to RGB(A) by the transformations */
+            png::ColorType::Grayscale | png::ColorType::Indexed => buffer
+                .iter()
+                .flat_map(|&gray| [gray, gray, gray, 255])
+                .collect(),
+        };
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            rgba,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Copies the border to `output`, then the 160x144 `screen` into its center
+    pub fn compose(&self, screen: &[u8], output: &mut [u8]) {
+        output.copy_from_slice(&self.rgba);
+        let left = (self.width as usize - 160) / 2;
+        let top = (self.height as usize - 144) / 2;
+        for (y, row) in screen.chunks_exact(160 * 4).enumerate() {
+            let start = ((top + y) * self.width as usize + left) * 4;
+            output[start..start + 160 * 4].copy_from_slice(row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(width: u32, height: u32, color: png::ColorType, data: &[u8]) -> Vec<u8> {
+        let mut image = Vec::new();
+        let mut encoder = png::Encoder::new(&mut image, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(data)
+            .unwrap();
+        image
+    }
+
+    #[test]
+    fn screen_centered_in_border() {
+        let image = encode(256, 224, png::ColorType::Rgb, &[0x40; 256 * 224 * 3]);
+        let border = Border::from_png(image.as_slice()).unwrap();
+        let screen = vec![0xFF; 160 * 144 * 4];
+        let mut output = vec![0; 256 * 224 * 4];
+
+        border.compose(&screen, &mut output);
+
+        /* 48 pixels of border on each side, 40 above and below */
+        let pixel = |x: usize, y: usize| &output[(y * 256 + x) * 4..(y * 256 + x + 1) * 4];
+        assert_eq!(pixel(47, 40), [0x40, 0x40, 0x40, 255]);
+        assert_eq!(pixel(48, 40), [0xFF; 4]);
+        assert_eq!(pixel(207, 183), [0xFF; 4]);
+        assert_eq!(pixel(208, 183), [0x40, 0x40, 0x40, 255]);
+        assert_eq!(pixel(48, 39), [0x40, 0x40, 0x40, 255]);
+        assert_eq!(pixel(48, 184), [0x40, 0x40, 0x40, 255]);
+    }
+
+    #[test]
+    fn border_smaller_than_screen() {
+        let image = encode(160, 100, png::ColorType::Rgba, &[0; 160 * 100 * 4]);
+        assert!(matches!(
+            Border::from_png(image.as_slice()),
+            Err(BorderError::TooSmall {
+                width: 160,
+                height: 100
+            })
+        ));
+    }
+}
@@ -1,6 +1,10 @@
-use crate::mmu::Mmu;
-use crate::ppu::draw_scanline;
-use crate::registers::{Flags, R16OrSP, R8OrMem, Registers, R16, R8};
+use crate::apu::Apu;
+use crate::mmu::{CartridgeError, Interrupt, Mmu};
+use crate::ppu::{count_sprites, draw_scanline, mode3_length, window_visible, LineShades, Video};
+#[cfg(feature = "parallel")]
+use crate::ppu::{draw_deferred, DeferredLine};
+use crate::registers::{Flags, R16OrSP, R8OrMem, RegisterFile, Registers, R16, R8};
+use crate::state::{StateError, StateReader, StateWriter, MAGIC, VERSION};
 use std::cell::Cell;
 
 use crate::registers::{R16mem, R16stk};
@@ -11,11 +15,53 @@ enum AfterInstruction {
     None,
 }
 
+type FrameCallback = Box<dyn FnMut(&[u8])>;
+type BootCallback = Box<dyn FnMut(&RegisterFile)>;
+type TraceCallback = Box<dyn FnMut(&str)>;
+
+/// Widest span of PC values a frame can stay within and still count as stuck
+const HANG_WINDOW: u16 = 16;
+
+/// Lowest and highest PC seen this frame, and how many frames in a row
+/// looked stuck
+#[derive(Default)]
+struct HangCheck {
+    low: u16,
+    high: u16,
+    frames: u32,
+}
+
+/// How a frame run by `Cpu::run_frame` ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameStatus {
+    /// Drawn to the frame buffer
+    Drawn,
+    /// Skipped, or nothing drawn changed, the frame buffer is as it was
+    Unchanged,
+    /// PC stayed in a few bytes and memory didn't change for the last
+    /// `hang_frames` frames
+    Hung,
+}
+
 #[derive(PartialEq)]
 pub enum State {
     Running,
     Halted,
-    Ime,
+    /// Stopped by STOP until a button is pressed, along with the timer,
+    /// sound and drawing
+    Stopped,
+    /// Hung on an illegal opcode until reset, while the rest of the system
+    /// keeps running
+    Locked,
+}
+
+/// An instruction `step_instruction` ran
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StepResult {
+    pub pc_before: u16,
+    pub opcode: u8,
+    /// M-cycles it took
+    pub cycles: u8,
 }
 
 pub struct Cpu {
@@ -23,24 +69,368 @@ pub struct Cpu {
     pub pc: u16,
     pub sp: u16,
     pub mmu: Mmu,
+    pub apu: Apu,
     pub ime: bool,
+    /// Set by EI, IME turns on after the next instruction
+    pub ime_pending: bool,
     pub state: State,
+    /// Length of a scanline in dots, 456 on DMG
+    pub dots_per_line: u32,
+    /// Dots elapsed per M-cycle, 4 at normal speed and 2 in double-speed mode
+    pub cycle_scale: u32,
+    /// Number of frames emulated without drawing after each drawn frame
+    pub frame_skip: u32,
+    frame_count: u32,
+    /// Next scanline to run, frames can be run in parts
+    line: u8,
+    /// Whether `line` is under way, lines can be run an instruction at a time
+    line_started: bool,
+    /// Dots run so far on the current line
+    dots: u32,
+    /// Dot the current line's pixel transfer ends at
+    hblank_start: u32,
+    /// Whether the current frame is being drawn
+    render: bool,
+    /// Set by `skip_frame` for the frame it runs
+    skipping: bool,
+    /// Whether anything drawn changed since the last drawn frame started,
+    /// otherwise lines are only drawn once something changes
+    redraw: bool,
+    /// Whether the last finished frame drew anything
+    drawn: bool,
+    on_frame: Option<FrameCallback>,
+    on_boot: Option<BootCallback>,
+    on_trace: Option<TraceCallback>,
+    shades: Option<Vec<u8>>,
+    color_indexes: Option<Vec<u8>>,
+    /// Lines waiting to be drawn in parallel once VBlank starts, `None` to
+    /// draw each one at its HBlank
+    #[cfg(feature = "parallel")]
+    deferred: Option<Vec<DeferredLine>>,
+    /// Frames without progress before `is_hung` reports a hang, `None` to
+    /// disable the check
+    hang_frames: Option<u32>,
+    hang: HangCheck,
+    last_step: Option<StepResult>,
+    /// The opcode `step` fetched last
+    opcode: u8,
 }
 
 impl Cpu {
+    /// Powers on without a boot ROM, at the cartridge entry point
     pub fn new() -> Self {
-        Cpu {
+        Self::with_boot_rom(None)
+    }
+
+    /// Powers on running `bootstrap` from 0x0000 or, without one, in the
+    /// state it would leave behind
+    pub fn with_boot_rom(bootstrap: Option<[u8; 0x100]>) -> Self {
+        let mut cpu = Cpu {
             registers: Registers::new(),
             pc: 0,
             sp: 0,
             mmu: Mmu::new(),
+            apu: Apu::new(),
             ime: false,
+            ime_pending: false,
             state: State::Running,
+            dots_per_line: 456,
+            cycle_scale: 4,
+            frame_skip: 0,
+            hang_frames: None,
+            frame_count: 0,
+            line: 0,
+            line_started: false,
+            dots: 0,
+            hblank_start: 0,
+            render: true,
+            skipping: false,
+            redraw: true,
+            drawn: false,
+            on_frame: None,
+            on_boot: None,
+            on_trace: None,
+            shades: None,
+            color_indexes: None,
+            #[cfg(feature = "parallel")]
+            deferred: None,
+            hang: HangCheck::default(),
+            last_step: None,
+            opcode: 0,
+        };
+        cpu.mmu.set_boot_rom(bootstrap);
+        cpu.reset();
+        cpu
+    }
+
+    pub fn get_registers(&self) -> RegisterFile {
+        RegisterFile {
+            a: self.registers.a.get(),
+            f: self.registers.flags.to_u8(),
+            b: self.registers.b.get(),
+            c: self.registers.c.get(),
+            d: self.registers.d.get(),
+            e: self.registers.e.get(),
+            h: self.registers.h.get(),
+            l: self.registers.l.get(),
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    /// Loads every register at once, the low nibble of F is ignored
+    pub fn set_registers(&mut self, file: RegisterFile) {
+        self.registers.a.set(file.a);
+        self.registers.flags.set_from_u8(file.f);
+        self.registers.b.set(file.b);
+        self.registers.c.set(file.c);
+        self.registers.d.set(file.d);
+        self.registers.e.set(file.e);
+        self.registers.h.set(file.h);
+        self.registers.l.set(file.l);
+        self.sp = file.sp;
+        self.pc = file.pc;
+    }
+
+    /// Starts at the cartridge entry point with the registers and IO the DMG
+    /// boot ROM leaves behind, without running it
+    pub fn skip_boot(&mut self) {
+        self.set_registers(RegisterFile {
+            a: 0x01,
+            f: 0xB0,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            sp: 0xFFFE,
+            pc: 0x0100,
+        });
+        self.mmu.skip_boot();
+    }
+
+    /// Power cycles the system, keeping the cartridge and its RAM, and runs
+    /// the boot ROM again if there is one. Timing and frame skip settings are
+    /// kept.
+    pub fn reset(&mut self) {
+        self.registers = Registers::new();
+        self.pc = 0;
+        self.sp = 0;
+        self.ime = false;
+        self.ime_pending = false;
+        self.state = State::Running;
+        self.frame_count = 0;
+        self.line = 0;
+        self.line_started = false;
+        self.redraw = true;
+        self.hang = HangCheck::default();
+        self.last_step = None;
+        self.mmu.reset();
+        self.apu.reset();
+        if !self.mmu.has_boot_rom() {
+            self.skip_boot();
+        }
+    }
+
+    /// Powers the system back on with a different cartridge, reusing the
+    /// existing memory. A cartridge that can't be loaded leaves the current
+    /// game running.
+    pub fn reset_and_load(&mut self, rom: &[u8]) -> Result<(), CartridgeError> {
+        self.mmu.swap_game(rom)?;
+        self.reset();
+        Ok(())
+    }
+
+    /// Snapshots the CPU, memory and cartridge state. The sound registers
+    /// are kept but not the channels, which restart when the game next plays
+    /// a note after loading.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.bytes(&MAGIC);
+        state.u8(VERSION);
+        let registers = self.get_registers();
+        state.bytes(&[
+            registers.a,
+            registers.f,
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+        ]);
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.bool(self.ime);
+        state.bool(self.ime_pending);
+        /* A locked CPU is saved running, on the illegal opcode */
+        state.u8(match self.state {
+            State::Running | State::Locked => 0,
+            State::Halted => 1,
+            State::Stopped => 2,
+        });
+        state.u8(self.line);
+        self.mmu.save_state(&mut state);
+        state.finish()
+    }
+
+    /// Restores a snapshot from `save_state` taken with the same cartridge.
+    /// Nothing changes if it can't be loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        if let Err(err) = self.read_state(data) {
+            self.read_state(&backup)
+                .expect("a state that was just saved loads");
+            return Err(err);
         }
+        self.apu.reset();
+        self.mmu.queue_sound_registers();
+        Ok(())
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        if state.bytes(MAGIC.len()) != Ok(&MAGIC) {
+            return Err(StateError::NotAState);
+        }
+        match state.u8()? {
+            VERSION => {}
+            version => return Err(StateError::UnsupportedVersion(version)),
+        }
+        let registers = state.bytes(8)?;
+        self.set_registers(RegisterFile {
+            a: registers[0],
+            f: registers[1],
+            b: registers[2],
+            c: registers[3],
+            d: registers[4],
+            e: registers[5],
+            h: registers[6],
+            l: registers[7],
+            sp: state.u16()?,
+            pc: state.u16()?,
+        });
+        self.ime = state.bool()?;
+        self.ime_pending = state.bool()?;
+        self.state = match state.u8()? {
+            0 => State::Running,
+            1 => State::Halted,
+            _ => State::Stopped,
+        };
+        self.line = match state.u8()? {
+            line @ 0..=153 => line,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.line_started = false;
+        self.mmu.load_state(&mut state)?;
+        self.redraw = true;
+        self.hang = HangCheck::default();
+        self.last_step = None;
+        Ok(())
+    }
+
+    /// Calls `callback` with the RGBA framebuffer at the end of every frame.
+    /// Frames skipped by `frame_skip` pass the last drawn image.
+    pub fn set_on_frame(&mut self, callback: impl FnMut(&[u8]) + 'static) {
+        self.on_frame = Some(Box::new(callback));
+    }
+
+    /// Calls `callback` with the registers right after the boot ROM hands
+    /// off to the cartridge by writing 0xFF50, to compare against the
+    /// post-boot state `Mmu::skip_boot` sets up
+    pub fn set_on_boot(&mut self, callback: impl FnMut(&RegisterFile) + 'static) {
+        self.on_boot = Some(Box::new(callback));
+    }
+
+    /// Calls `callback` with `trace_line` before every instruction, for
+    /// comparing runs against a reference emulator's log
+    pub fn set_trace(&mut self, callback: impl FnMut(&str) + 'static) {
+        self.on_trace = Some(Box::new(callback));
+    }
+
+    /// The registers and the 4 bytes at PC, in the format of Gameboy Doctor
+    /// logs
+    pub fn trace_line(&self) -> String {
+        let r = self.get_registers();
+        let pcmem: Vec<String> = (0..4)
+            .map(|offset| format!("{:02X}", self.mmu.peek(self.pc.wrapping_add(offset))))
+            .collect();
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            r.a,
+            r.f,
+            r.b,
+            r.c,
+            r.d,
+            r.e,
+            r.h,
+            r.l,
+            r.sp,
+            r.pc,
+            pcmem.join(",")
+        )
+    }
+
+    /// Start or stop keeping 160x144 buffers of the shade (0 to 3, 0 the
+    /// lightest) and the color index before the palettes of every pixel
+    /// drawn, next to the RGBA frame
+    pub fn set_shade_buffer(&mut self, enable: bool) {
+        self.shades = enable.then(|| vec![0; 160 * 144]);
+        self.color_indexes = enable.then(|| vec![0; 160 * 144]);
+        self.redraw = true;
+    }
+
+    /// Draw the frame's lines in parallel when VBlank starts instead of one
+    /// at a time. Each line keeps the registers it had at its HBlank, but
+    /// they all see VRAM and OAM as they are at the end of the frame.
+    #[cfg(feature = "parallel")]
+    pub fn set_parallel_render(&mut self, enable: bool) {
+        self.deferred = enable.then(Vec::new);
+    }
+
+    /// Shades of the last frame drawn, when enabled with `set_shade_buffer`.
+    /// Frames skipped by `frame_skip` leave it unchanged.
+    pub fn shades(&self) -> Option<&[u8]> {
+        self.shades.as_deref()
+    }
+
+    /// Color indexes (0 to 3) of the last frame drawn before BGP, OBP0 or
+    /// OBP1 turned them into shades, when enabled with `set_shade_buffer`
+    pub fn color_indexes(&self) -> Option<&[u8]> {
+        self.color_indexes.as_deref()
+    }
+
+    /// Frames without progress before `is_hung` reports a hang and
+    /// `run_frame` returns `FrameStatus::Hung`. `None`, the default, turns
+    /// the check off.
+    pub fn set_hang_frames(&mut self, frames: Option<u32>) {
+        self.hang_frames = frames;
+        self.hang = HangCheck::default();
+        self.mmu.set_memory_tracking(frames.is_some());
+    }
+
+    /// Whether the last `hang_frames` frames all kept PC within a few bytes
+    /// and left memory outside the IO registers unchanged
+    pub fn is_hung(&self) -> bool {
+        self.hang_frames
+            .is_some_and(|limit| self.hang.frames >= limit)
+    }
+
+    /// The instruction run by the last `step_instruction` or the last step of
+    /// a frame, `None` if the CPU was halted or stopped instead
+    pub fn last_step(&self) -> Option<StepResult> {
+        self.last_step
+    }
+
+    /// Number of M-cycles the CPU runs for in one frame of 154 lines
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.dots_per_line * 154 / self.cycle_scale
     }
 
     pub fn step(&mut self) -> u8 {
         let opcode = self.mmu.read_byte(self.pc);
+        self.opcode = opcode;
 
         match opcode {
             0x00 => {
@@ -70,7 +460,7 @@ impl Cpu {
             }
             0x10 => {
                 // ## println!("{:#04x}: stop", self.pc);
-                self.pc += 2;
+                self.stop();
                 1
             }
             0x01 | 0x11 | 0x21 | 0x31 => {
@@ -136,7 +526,10 @@ impl Cpu {
             0x08 => {
                 // ## println!("{:#04x}: ld [imm16], sp", self.pc);
                 let imm16 = self.mmu.read_word(self.pc + 1);
-                self.mmu.write_word(imm16, self.sp);
+                /* Low byte in M4, high byte in M5 */
+                self.mmu.write_byte(imm16, self.sp as u8);
+                self.mmu
+                    .write_byte(imm16.wrapping_add(1), (self.sp >> 8) as u8);
                 self.pc += 3;
                 5
             }
@@ -145,7 +538,7 @@ impl Cpu {
                 let operand = R16::from_u8((opcode & 0b0011_0000) >> 4);
                 let operand = self.registers.get_r16(operand);
                 match operand {
-                    R16OrSP::SP => self.sp += 1,
+                    R16OrSP::SP => self.sp = self.sp.wrapping_add(1),
                     R16OrSP::R16(hi, lo) => inc_r16((hi, lo)),
                 }
                 self.pc += 1;
@@ -156,7 +549,7 @@ impl Cpu {
                 let operand = R16::from_u8((opcode & 0b0011_0000) >> 4);
                 let operand = self.registers.get_r16(operand);
                 match operand {
-                    R16OrSP::SP => self.sp -= 1,
+                    R16OrSP::SP => self.sp = self.sp.wrapping_sub(1),
                     R16OrSP::R16(hi, lo) => dec_r16((hi, lo)),
                 }
                 self.pc += 1;
@@ -554,8 +947,7 @@ impl Cpu {
             0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
                 // ## println!("{:#04x}: rst n", self.pc);
                 let n = (opcode & 0b0011_1000) >> 3;
-                self.mmu.write_word(self.sp - 2, self.pc + 1);
-                self.sp -= 2;
+                self.push_word(self.pc + 1);
                 self.pc = n as u16 * 8;
                 4
             }
@@ -599,80 +991,23 @@ impl Cpu {
                 self.pc += 3;
                 4
             }
-            0xCA => {
-                // ## println!("{:#04x}: jp z, imm16", self.pc);
-                if self.registers.flags.zero.get() {
-                    self.pc = self.mmu.read_word(self.pc + 1);
-                    return 4;
-                }
-                self.pc += 3;
-                3
-            }
-            0xC2 => {
-                // ## println!("{:#04x}: jp nz, imm16", self.pc);
-                if !self.registers.flags.zero.get() {
-                    self.pc = self.mmu.read_word(self.pc + 1);
-                    return 4;
-                }
-                self.pc += 3;
-                3
-            }
-            0xDA => {
-                // ## println!("{:#04x}: jp c, imm16", self.pc);
-                if self.registers.flags.carry.get() {
-                    self.pc = self.mmu.read_word(self.pc + 1);
-                    return 4;
-                }
-                self.pc += 3;
-                3
-            }
-            0xD2 => {
-                // ## println!("{:#04x}: jp nc, imm16", self.pc);
-                if !self.registers.flags.carry.get() {
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                // ## println!("{:#04x}: jp cond, imm16", self.pc);
+                let condition = (opcode & 0b0001_1000) >> 3;
+                let condition = self.registers.flags.get_condition(condition);
+                if condition {
                     self.pc = self.mmu.read_word(self.pc + 1);
                     return 4;
                 }
                 self.pc += 3;
                 3
             }
-            0xC4 => {
-                // ## println!("{:#04x}: call nz, imm16", self.pc);
-                if !self.registers.flags.zero.get() {
-                    self.mmu.write_word(self.sp - 2, self.pc + 3);
-                    self.sp -= 2;
-                    self.pc = self.mmu.read_word(self.pc + 1);
-                    return 6;
-                }
-                self.pc += 3;
-                3
-            }
-            0xCC => {
-                // ## println!("{:#04x}: call z, imm16", self.pc);
-                if self.registers.flags.zero.get() {
-                    self.mmu.write_word(self.sp - 2, self.pc + 3);
-                    self.sp -= 2;
-                    self.pc = self.mmu.read_word(self.pc + 1);
-                    return 6;
-                }
-                self.pc += 3;
-                3
-            }
-            0xDC => {
-                // ## println!("{:#04x}: call c, imm16", self.pc);
-                if self.registers.flags.carry.get() {
-                    self.mmu.write_word(self.sp - 2, self.pc + 3);
-                    self.sp -= 2;
-                    self.pc = self.mmu.read_word(self.pc + 1);
-                    return 6;
-                }
-                self.pc += 3;
-                3
-            }
-            0xD4 => {
-                // ## println!("{:#04x}: call nc, imm16", self.pc);
-                if !self.registers.flags.carry.get() {
-                    self.mmu.write_word(self.sp - 2, self.pc + 3);
-                    self.sp -= 2;
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                // ## println!("{:#04x}: call cond, imm16", self.pc);
+                let condition = (opcode & 0b0001_1000) >> 3;
+                let condition = self.registers.flags.get_condition(condition);
+                if condition {
+                    self.push_word(self.pc + 3);
                     self.pc = self.mmu.read_word(self.pc + 1);
                     return 6;
                 }
@@ -707,22 +1042,20 @@ impl Cpu {
             }
             0xCD => {
                 // ## println!("{:#04x}: call imm16", self.pc);
-                self.mmu.write_word(self.sp - 2, self.pc + 3);
-                self.sp -= 2;
+                self.push_word(self.pc + 3);
                 self.pc = self.mmu.read_word(self.pc + 1);
                 6
             }
             0xC9 => {
                 // ## println!("{:#04x}: ret", self.pc);
-                self.pc = self.mmu.read_word(self.sp);
-                self.sp += 2;
+                self.pc = self.pop_word();
                 4
             }
             0xD9 => {
                 // ## println!("{:#04x}: reti", self.pc);
-                self.pc = self.mmu.read_word(self.sp);
-                self.sp += 2;
-                self.state = State::Ime;
+                self.pc = self.pop_word();
+                /* Unlike EI, there's no delay */
+                self.ime = true;
                 4
             }
             0xC3 => {
@@ -742,41 +1075,12 @@ impl Cpu {
                 self.pc = (self.registers.h.get() as u16) << 8 | self.registers.l.get() as u16;
                 1
             }
-            0xC0 => {
-                // ## println!("{:#04x}: ret nz", self.pc);
-                if !self.registers.flags.zero.get() {
-                    self.pc = self.mmu.read_word(self.sp);
-                    self.sp += 2;
-                    return 5;
-                }
-                self.pc += 1;
-                2
-            }
-            0xC8 => {
-                // ## println!("{:#04x}: ret z", self.pc);
-                if self.registers.flags.zero.get() {
-                    self.pc = self.mmu.read_word(self.sp);
-                    self.sp += 2;
-                    return 5;
-                }
-                self.pc += 1;
-                2
-            }
-            0xD0 => {
-                // ## println!("{:#04x}: ret nc", self.pc);
-                if !self.registers.flags.carry.get() {
-                    self.pc = self.mmu.read_word(self.sp);
-                    self.sp += 2;
-                    return 5;
-                }
-                self.pc += 1;
-                2
-            }
-            0xD8 => {
-                // ## println!("{:#04x}: ret c", self.pc);
-                if self.registers.flags.carry.get() {
-                    self.pc = self.mmu.read_word(self.sp);
-                    self.sp += 2;
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                // ## println!("{:#04x}: ret cond", self.pc);
+                let condition = (opcode & 0b0001_1000) >> 3;
+                let condition = self.registers.flags.get_condition(condition);
+                if condition {
+                    self.pc = self.pop_word();
                     return 5;
                 }
                 self.pc += 1;
@@ -784,14 +1088,16 @@ impl Cpu {
             }
             0xF3 => {
                 // ## println!("{:#04x}: di", self.pc);
+                /* Also cancels an EI right before it */
                 self.ime = false;
+                self.ime_pending = false;
                 self.state = State::Running;
                 self.pc += 1;
                 1
             }
             0xFB => {
                 // ## println!("{:#04x}: ei", self.pc);
-                self.ime = true;
+                self.ime_pending = true;
                 self.pc += 1;
                 1
             }
@@ -800,19 +1106,15 @@ impl Cpu {
                 let register = R16stk::from_u8((opcode & 0b0011_0000) >> 4);
                 match register {
                     R16stk::AF => {
-                        let lo = self.mmu.read_byte(self.sp);
-                        let hi = self.mmu.read_byte(self.sp + 1);
+                        let [lo, hi] = self.pop_word().to_le_bytes();
                         self.registers.a.set(hi);
                         self.registers.flags.set_from_u8(lo);
                     }
                     _ => {
-                        let register = self.registers.get_r16stk(register);
-                        let lo = self.mmu.read_byte(self.sp);
-                        let hi = self.mmu.read_byte(self.sp + 1);
-                        ld_r16_imm16(register, (hi as u16) << 8 | lo as u16);
+                        let value = self.pop_word();
+                        ld_r16_imm16(self.registers.get_r16stk(register), value);
                     }
                 }
-                self.sp += 2;
                 self.pc += 1;
                 3
             }
@@ -823,18 +1125,15 @@ impl Cpu {
                     R16stk::AF => {
                         let hi = self.registers.a.get();
                         let lo = self.registers.flags.to_u8();
-                        self.mmu
-                            .write_word(self.sp - 2, (hi as u16) << 8 | lo as u16);
+                        self.push_word((hi as u16) << 8 | lo as u16);
                     }
                     _ => {
                         let register = self.registers.get_r16stk(register);
                         let hi = register.0.get();
                         let lo = register.1.get();
-                        self.mmu
-                            .write_word(self.sp - 2, (hi as u16) << 8 | lo as u16);
+                        self.push_word((hi as u16) << 8 | lo as u16);
                     }
                 }
-                self.sp -= 2;
                 self.pc += 1;
                 4
             }
@@ -1013,133 +1312,358 @@ impl Cpu {
                     }
                 }
             }
-            _ => panic!("Invalid opcode"),
+            /* 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC
+             * and 0xFD. PC stays on the opcode, so a saved state locks up
+             * again when loaded. */
+            _ => {
+                eprintln!(
+                    "Illegal opcode {:#04x} at {:#06x}, the CPU locked up",
+                    opcode, self.pc
+                );
+                self.state = State::Locked;
+                1
+            }
+        }
+    }
+
+    /// STOP, whose length and effect depend on whether a button is held and
+    /// an interrupt is pending. A CGB speed switch armed through KEY1 is
+    /// accepted but has no effect on DMG.
+    fn stop(&mut self) {
+        if self.mmu.take_speed_switch() {
+            self.pc = self.pc.wrapping_add(2);
+            return;
+        }
+        let button_held = self.mmu.peek(0xFF00) & 0x0F != 0x0F;
+        let interrupt_pending = self.has_pending_interrupt();
+        /* The byte after STOP is skipped unless an interrupt is pending */
+        self.pc = match interrupt_pending {
+            true => self.pc.wrapping_add(1),
+            false => self.pc.wrapping_add(2),
+        };
+        match (button_held, interrupt_pending) {
+            (true, true) => {}
+            (true, false) => self.state = State::Halted,
+            (false, _) => {
+                self.mmu.write_byte(0xFF04, 0);
+                self.state = State::Stopped;
+            }
         }
     }
 
+    /// Pushes the high byte then the low byte as two separate bus writes, in
+    /// the order the SM83 performs them
+    fn push_word(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.mmu.write_byte(self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.mmu.write_byte(self.sp, value as u8);
+    }
+
+    /// Pops the low byte then the high byte, wrapping around the top of
+    /// memory like PUSH wraps around the bottom
+    fn pop_word(&mut self) -> u16 {
+        let low = self.mmu.read_byte(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.mmu.read_byte(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        u16::from_le_bytes([low, high])
+    }
+
+    /// Whether an interrupt is both requested in IF and enabled in IE,
+    /// regardless of IME
+    pub fn has_pending_interrupt(&self) -> bool {
+        self.mmu.pending_interrupts() != 0
+    }
+
+    /// Wakes from HALT on a pending interrupt and, with IME set, dispatches the
+    /// highest priority one to its handler
+    fn service_interrupts(&mut self) {
+        if matches!(self.state, State::Stopped | State::Locked) {
+            return;
+        }
+        /* A pending interrupt ends HALT whether or not IME allows servicing it */
+        if self.state == State::Halted && self.has_pending_interrupt() {
+            self.state = State::Running;
+        }
+        if self.ime {
+            let pending = self.mmu.pending_interrupts();
+            if let Some(kind) = Interrupt::ALL
+                .into_iter()
+                .find(|kind| pending & kind.mask() != 0)
+            {
+                self.ime = false;
+                self.ime_pending = false;
+                self.mmu.clear_interrupt(kind);
+                self.push_word(self.pc);
+                self.pc = kind.vector();
+            }
+        }
+    }
+
+    /// Runs the rest of the current frame, up to the end of line 153. Returns
+    /// whether the frame was drawn to `frame`. Frames are skipped by
+    /// `frame_skip`, and also when nothing drawn changed, in which case
+    /// `frame` still holds the last drawn frame.
     pub fn game_loop(&mut self, frame: &mut [u8]) -> bool {
-        frame.fill(0);
-        let mut ticks = 0;
-        self.mmu.set_window_counter(0);
-        for line in 0..154 {
-            while ticks < 456 {
-                if self.state == State::Ime {
-                    self.state = State::Running;
-                    self.ime = true;
-                }
-                if self.state != State::Halted {
-                    let tac_enable = self.mmu.read_byte(0xFF07) & 0b100 != 0;
-                    let duration = self.step() as u32;
-                    ticks += duration;
-                    if self.mmu.increment_timer(duration, tac_enable) {
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) | 0b0000_0100);
-                    }
-                } else {
-                    ticks += 1;
-                    if self
-                        .mmu
-                        .increment_timer(1, self.mmu.read_byte(0xFF07) & 0b100 != 0)
-                    {
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) | 0b0000_0100);
-                    }
+        loop {
+            self.run_line(frame);
+            if self.line == 0 {
+                return self.drawn;
+            }
+        }
+    }
+
+    /// Runs the rest of the current frame like `game_loop`, reporting a hang
+    /// once `set_hang_frames` frames in a row made no progress
+    pub fn run_frame(&mut self, frame: &mut [u8]) -> FrameStatus {
+        let drawn = self.game_loop(frame);
+        match (self.is_hung(), drawn) {
+            (true, _) => FrameStatus::Hung,
+            (false, true) => FrameStatus::Drawn,
+            (false, false) => FrameStatus::Unchanged,
+        }
+    }
+
+    /// Runs the next frame without drawing it, for fast-forwarding. Anything
+    /// that changed is drawn in the next frame that is, and `frame_skip`
+    /// only counts the frames run by `game_loop`.
+    pub fn skip_frame(&mut self, frame: &mut [u8]) {
+        self.skipping = true;
+        self.game_loop(frame);
+        self.skipping = false;
+    }
+
+    /// Runs until VBlank starts and its interrupt is requested, before the
+    /// interrupt is serviced, finishing the current frame first if it is
+    /// already past that point. Returns whether the frame is being drawn to
+    /// `frame`, whose 144 visible lines are complete at that point.
+    pub fn run_until_vblank(&mut self, frame: &mut [u8]) -> bool {
+        while !(self.advance(frame) && self.line == 144) {}
+        self.start_line(frame);
+        self.drawing()
+    }
+
+    /// Whether the line about to be drawn could differ from the last frame
+    fn drawing(&self) -> bool {
+        self.render && self.state != State::Stopped && (self.redraw || self.mmu.vram_dirty())
+    }
+
+    /// Runs a single instruction, or one M-cycle while halted, with the
+    /// timer, APU and PPU keeping pace. Lines are drawn into `frame` as usual.
+    pub fn step_instruction(&mut self, frame: &mut [u8]) {
+        self.advance(frame);
+    }
+
+    /// Runs the rest of the frame like `game_loop`, but calls `stop` before
+    /// each instruction and returns `None` as soon as it says to, part way
+    /// through the frame. Otherwise returns whether the frame was drawn.
+    pub fn run_until(
+        &mut self,
+        frame: &mut [u8],
+        mut stop: impl FnMut(&Cpu) -> bool,
+    ) -> Option<bool> {
+        loop {
+            if stop(self) {
+                return None;
+            }
+            if self.advance(frame) && self.line == 0 {
+                return Some(self.drawn);
+            }
+        }
+    }
+
+    /// Runs one scanline
+    fn run_line(&mut self, frame: &mut [u8]) {
+        while !self.advance(frame) {}
+    }
+
+    /// Runs one instruction, starting a new line first if needed. Returns
+    /// whether it finished the line.
+    fn advance(&mut self, frame: &mut [u8]) -> bool {
+        if !self.line_started {
+            self.start_line(frame);
+        }
+        let line = self.line;
+        /* EI takes effect once the instruction after it has run */
+        if std::mem::take(&mut self.ime_pending) {
+            self.ime = true;
+        }
+        if self.state == State::Running {
+            if self.on_trace.is_some() {
+                let trace = self.trace_line();
+                if let Some(on_trace) = self.on_trace.as_mut() {
+                    on_trace(&trace);
                 }
-                if self.ime {
-                    if self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) != 0 {
-                        self.state = State::Running;
-                    }
-                    if self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) & 0b0000_0001 != 0 {
-                        /* V-Blank interrupt */
-                        self.ime = false;
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) & !0b0000_0001);
-                        self.mmu.write_word(self.sp - 2, self.pc);
-                        self.sp -= 2;
-                        self.pc = 0x40;
-                    } else if self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) & 0b0000_0010
-                        != 0
-                    {
-                        /* LCD STAT interrupt */
-                        self.ime = false;
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) & !0b0000_0010);
-                        self.mmu.write_word(self.sp - 2, self.pc);
-                        self.sp -= 2;
-                        self.pc = 0x48;
-                    } else if self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) & 0b0000_0100
-                        != 0
-                    {
-                        /* Timer interrupt */
-                        self.ime = false;
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) & !0b0000_0100);
-                        self.mmu.write_word(self.sp - 2, self.pc);
-                        self.sp -= 2;
-                        self.pc = 0x50;
-                    } else if self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) & 0b0000_1000
-                        != 0
-                    {
-                        /* Serial interrupt */
-                        self.ime = false;
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) & !0b0000_1000);
-                        self.mmu.write_word(self.sp - 2, self.pc);
-                        self.sp -= 2;
-                        self.pc = 0x58;
-                    } else if self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) & 0b0001_0000
-                        != 0
-                    {
-                        /* Joypad interrupt */
-                        self.ime = false;
-                        self.mmu
-                            .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) & !0b0001_0000);
-                        self.mmu.write_word(self.sp - 2, self.pc);
-                        self.sp -= 2;
-                        self.pc = 0x60;
-                    }
-                } else if self.state == State::Halted
-                    && self.mmu.read_byte(0xFFFF) & self.mmu.read_byte(0xFF0F) != 0
-                {
-                    self.state = State::Running;
+            }
+            let pc_before = self.pc;
+            let cycles = self.step();
+            self.last_step = Some(StepResult {
+                pc_before,
+                opcode: self.opcode,
+                cycles,
+            });
+            let duration = cycles as u32;
+            self.dots += duration * self.cycle_scale;
+            if self.mmu.take_boot_handoff() {
+                let registers = self.get_registers();
+                if let Some(on_boot) = self.on_boot.as_mut() {
+                    on_boot(&registers);
                 }
             }
-            ticks = 0;
-            if line < 144 {
-                let scx = self.mmu.read_byte(0xFF43);
-                let scy = self.mmu.read_byte(0xFF42);
-                draw_scanline(&self.mmu, frame, scx, scy, line);
-                let window_line = self.mmu.get_window_counter();
-                let (wy, wx) = self.mmu.get_window_pos();
-                if self.mmu.get_window_enable() && wy <= line && wy < 144 && wx < 167 {
+            if self.mmu.increment_timer(duration) {
+                self.mmu.request_interrupt(Interrupt::Timer);
+            }
+            self.mmu.step_dma(duration);
+            self.apu.step(&mut self.mmu, duration);
+        } else if self.state == State::Stopped {
+            self.last_step = None;
+            /* The clock is stopped, the LCD only keeps time here so frames
+             * still end. Any selected joypad line going low wakes it. */
+            self.dots += self.cycle_scale;
+            if self.mmu.peek(0xFF00) & 0x0F != 0x0F {
+                self.state = State::Running;
+                self.redraw = true;
+            }
+        } else {
+            self.last_step = None;
+            self.dots += self.cycle_scale;
+            if self.mmu.increment_timer(1) {
+                self.mmu.request_interrupt(Interrupt::Timer);
+            }
+            self.mmu.step_dma(1);
+            self.apu.step(&mut self.mmu, 1);
+        }
+        if line < 144 {
+            if self.mmu.get_ppu_mode() == 2 && self.dots >= 80 {
+                /* OAM scan done, start pixel transfer */
+                self.mmu.set_ppu_mode(3);
+                let scx = self.mmu.peek(0xFF43);
+                self.hblank_start = 80 + mode3_length(scx, count_sprites(&self.mmu, line));
+            }
+            if self.mmu.get_ppu_mode() == 3 && self.dots >= self.hblank_start {
+                /* Pixel transfer done, the line is drawn as HBlank begins */
+                self.mmu.set_ppu_mode(0);
+                if self.drawing() {
+                    self.draw_line(frame, line);
+                }
+                if window_visible(&self.mmu, line) {
+                    let window_line = self.mmu.get_window_counter();
                     self.mmu.set_window_counter(window_line + 1);
                 }
             }
+        }
+        self.service_interrupts();
+        if self.hang_frames.is_some() {
+            self.hang.low = self.hang.low.min(self.pc);
+            self.hang.high = self.hang.high.max(self.pc);
+        }
+        if self.dots < self.dots_per_line {
+            return false;
+        }
 
-            if line + 1 == self.mmu.read_byte(0xFF45)
-                && self.mmu.read_byte(0xFFFF) & 0b0000_0010 != 0
-            {
-                self.mmu
-                    .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) | 0b0000_0010);
-                self.mmu
-                    .write_byte(0xFF41, self.mmu.read_byte(0xFF41) | 0b0000_0100)
-            } else {
-                self.mmu
-                    .write_byte(0xFF41, self.mmu.read_byte(0xFF41) & !0b0000_0100)
-            }
+        self.line_started = false;
+        self.line = (line + 1) % 154;
+        if self.line == 0 {
+            self.end_frame(frame);
+        }
+        true
+    }
 
-            if line == 144 && self.mmu.read_byte(0xFFFF) & 0b0000_0001 != 0 {
-                self.mmu
-                    .write_byte(0xFF0F, self.mmu.read_byte(0xFF0F) | 0b0000_0001);
-            }
+    /// Draws `line` from the registers, VRAM and OAM as they are now, so
+    /// mid-frame changes such as LYC splits show up. With parallel rendering
+    /// only the registers are kept for drawing at VBlank.
+    fn draw_line(&mut self, frame: &mut [u8], line: u8) {
+        #[cfg(feature = "parallel")]
+        if let Some(lines) = self.deferred.as_mut() {
+            lines.push(DeferredLine::new(&self.mmu, line));
+            return;
+        }
+        let scx = self.mmu.peek(0xFF43);
+        let scy = self.mmu.peek(0xFF42);
+        let drawn = draw_scanline(&self.mmu, frame, scx, scy, line);
+        self.store_shades(line, &drawn);
+    }
 
-            if line < 153 {
-                self.mmu.write_byte(0xFF44, line);
-            } else {
-                self.mmu.write_byte(0xFF44, 0);
-            }
+    #[cfg(feature = "parallel")]
+    fn draw_deferred(&mut self, frame: &mut [u8]) {
+        let Some(mut lines) = self.deferred.take() else {
+            return;
+        };
+        for (line, drawn) in draw_deferred(&self.mmu, frame, &lines) {
+            self.store_shades(line, &drawn);
         }
-        true
+        lines.clear();
+        self.deferred = Some(lines);
+    }
+
+    fn store_shades(&mut self, line: u8, drawn: &LineShades) {
+        let start = line as usize * 160;
+        if let Some(buffer) = self.shades.as_mut() {
+            buffer[start..start + 160].copy_from_slice(&drawn.shades);
+        }
+        if let Some(buffer) = self.color_indexes.as_mut() {
+            buffer[start..start + 160].copy_from_slice(&drawn.colors);
+        }
+    }
+
+    fn start_line(&mut self, frame: &mut [u8]) {
+        let line = self.line;
+        if line == 0 {
+            self.start_frame(frame);
+        }
+        self.line_started = true;
+        self.dots = 0;
+        self.hblank_start = self.dots_per_line;
+        self.mmu.set_ly(line);
+        if line < 144 {
+            self.mmu.set_ppu_mode(2);
+        } else if line == 144 {
+            #[cfg(feature = "parallel")]
+            self.draw_deferred(frame);
+            self.mmu.set_ppu_mode(1);
+            self.mmu.request_interrupt(Interrupt::VBlank);
+        }
+    }
+
+    fn start_frame(&mut self, frame: &mut [u8]) {
+        if self.skipping {
+            self.render = false;
+        } else {
+            self.render = self.frame_count == 0;
+            self.frame_count = (self.frame_count + 1) % (self.frame_skip + 1);
+        }
+        if self.render && self.redraw {
+            frame.fill(0);
+        }
+        self.mmu.set_window_counter(0);
+        self.hang.low = self.pc;
+        self.hang.high = self.pc;
+    }
+
+    fn end_frame(&mut self, frame: &mut [u8]) {
+        self.drawn = self.drawing();
+        /* Changes in skipped frames still need drawing in the next drawn one */
+        let changed = self.mmu.take_vram_dirty();
+        self.redraw = changed || (self.redraw && !self.render);
+        if self.hang_frames.is_some() {
+            let stuck = self.hang.high - self.hang.low < HANG_WINDOW;
+            /* Always take the flag so a stale write can't hide a later hang */
+            let changed = self.mmu.take_memory_changed();
+            self.hang.frames = match stuck && !changed {
+                true => self.hang.frames + 1,
+                false => 0,
+            };
+        }
+        if let Some(on_frame) = self.on_frame.as_mut() {
+            on_frame(frame);
+        }
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -1152,23 +1676,32 @@ fn add_a_r8(a: &Cell<u8>, value: u8, flags: &Flags) {
     a.set(result);
 }
 
-fn adc_a_r8(reg_a: &Cell<u8>, value: u8, flags: &Flags) {
-    let a = reg_a.get() as u16;
-    let value = value as u16;
+/// A + value + carry, shared by ADC with a register or an immediate
+fn adc(a: u8, value: u8, carry: bool, flags: &Flags) -> u8 {
+    let (a, value, carry) = (a as u16, value as u16, carry as u16);
+    let result = a + value + carry;
+    flags.zero.set(result as u8 == 0);
+    flags.subtract.set(false);
+    flags
+        .half_carry
+        .set((a & 0xF) + (value & 0xF) + carry > 0xF);
+    flags.carry.set(result > 0xFF);
+    result as u8
+}
 
-    let result = if !flags.carry.get() {
-        flags.half_carry.set((a & 0xF) + (value & 0xF) > 0xF);
-        flags.carry.set(a + value > 0xFF);
-        (a + value) as u8
-    } else {
-        flags.half_carry.set((a & 0xF) + (value & 0xF) + 1 > 0xF);
-        flags.carry.set(a + value + 1 > 0xFF);
-        (a + value + 1) as u8
-    };
+/// A - value - carry, shared by SBC with a register or an immediate
+fn sbc(a: u8, value: u8, carry: bool, flags: &Flags) -> u8 {
+    let (a, value, carry) = (a as u16, value as u16, carry as u16);
+    let result = a.wrapping_sub(value + carry) as u8;
     flags.zero.set(result == 0);
-    flags.subtract.set(false);
+    flags.subtract.set(true);
+    flags.half_carry.set(a & 0xF < (value & 0xF) + carry);
+    flags.carry.set(a < value + carry);
+    result
+}
 
-    reg_a.set(result);
+fn adc_a_r8(a: &Cell<u8>, value: u8, flags: &Flags) {
+    a.set(adc(a.get(), value, flags.carry.get(), flags));
 }
 
 fn sub_a_r8(a: &Cell<u8>, value: u8, flags: &Flags) {
@@ -1180,18 +1713,8 @@ fn sub_a_r8(a: &Cell<u8>, value: u8, flags: &Flags) {
     a.set(result);
 }
 
-fn sbc_a_r8(reg_a: &Cell<u8>, value: u8, flags: &Flags) {
-    let a = reg_a.get() as u16;
-    let c = flags.carry.get() as u16;
-    let value = value as u16;
-
-    let result = a.wrapping_sub(value + c) as u8;
-    flags.half_carry.set(a & 0xF < (value & 0xF) + c);
-    flags.carry.set(a < value + c);
-    flags.zero.set(result == 0);
-    flags.subtract.set(true);
-
-    reg_a.set(result);
+fn sbc_a_r8(a: &Cell<u8>, value: u8, flags: &Flags) {
+    a.set(sbc(a.get(), value, flags.carry.get(), flags));
 }
 
 fn and_a_r8(a: &Cell<u8>, value: u8, flags: &Flags) {
@@ -1323,23 +1846,8 @@ fn add_a_imm8(a: &Cell<u8>, imm8: u8, flags: &Flags) {
     a.set(result);
 }
 
-fn adc_a_imm8(r8: &Cell<u8>, imm8: u8, flags: &Flags) {
-    let a = r8.get() as u16;
-    let imm8 = imm8 as u16;
-
-    let result = if !flags.carry.get() {
-        flags.half_carry.set((a & 0xF) + (imm8 & 0xF) > 0xF);
-        flags.carry.set(a + imm8 > 0xFF);
-        (a + imm8) as u8
-    } else {
-        flags.half_carry.set((a & 0xF) + (imm8 & 0xF) + 1 > 0xF);
-        flags.carry.set(a + imm8 + 1 > 0xFF);
-        (a + imm8 + 1) as u8
-    };
-    flags.zero.set(result == 0);
-    flags.subtract.set(false);
-
-    r8.set(result);
+fn adc_a_imm8(a: &Cell<u8>, imm8: u8, flags: &Flags) {
+    a.set(adc(a.get(), imm8, flags.carry.get(), flags));
 }
 
 fn sub_a_imm8(a: &Cell<u8>, imm8: u8, flags: &Flags) {
@@ -1351,18 +1859,8 @@ fn sub_a_imm8(a: &Cell<u8>, imm8: u8, flags: &Flags) {
     a.set(result);
 }
 
-fn sbc_a_imm8(r8: &Cell<u8>, imm8: u8, flags: &Flags) {
-    let a = r8.get() as u16;
-    let c = flags.carry.get() as u16;
-    let imm8 = imm8 as u16;
-
-    let result = a.wrapping_sub(imm8 + c) as u8;
-    flags.half_carry.set(a & 0xF < (imm8 & 0xF) + c);
-    flags.carry.set(a < imm8 + c);
-    flags.zero.set(result == 0);
-    flags.subtract.set(true);
-
-    r8.set(result);
+fn sbc_a_imm8(a: &Cell<u8>, imm8: u8, flags: &Flags) {
+    a.set(sbc(a.get(), imm8, flags.carry.get(), flags));
 }
 
 fn and_a_imm8(a: &Cell<u8>, imm8: u8, flags: &Flags) {
@@ -1469,17 +1967,16 @@ fn swap_r8(value: u8, flags: &Flags) -> u8 {
     result
 }
 
+/// SP plus a signed offset, for ADD SP, e8 and LD HL, SP+e8. H and C come
+/// from adding the offset's unsigned byte to the low byte of SP, even when
+/// it is negative.
 fn add_sp_imm8(sp: u16, imm8: i8, flags: &Flags) -> u16 {
-    let sp = sp as i32;
-    let imm8 = imm8 as i32;
-
-    let result = sp.wrapping_add(imm8);
+    let offset = imm8 as u8 as u16;
     flags.zero.set(false);
-    flags.carry.set((sp & 0xFF) + (imm8 & 0xFF) > 0xFF);
     flags.subtract.set(false);
-    flags.half_carry.set((sp & 0xF) + (imm8 & 0xF) > 0xF);
-
-    result as u16
+    flags.half_carry.set((sp & 0xF) + (offset & 0xF) > 0xF);
+    flags.carry.set((sp & 0xFF) + offset > 0xFF);
+    sp.wrapping_add_signed(imm8 as i16)
 }
 
 fn ld_a_imm16(a: &Cell<u8>, imm16: u8) {
@@ -1538,12 +2035,1149 @@ fn sra_r8(value: u8, flags: &Flags) -> u8 {
 }
 
 fn add_hl_sp_imm8((h, l): (&Cell<u8>, &Cell<u8>), sp: u16, imm8: i8, flags: &Flags) {
-    let imm8 = imm8 as i16;
-    let hl = sp.wrapping_add(imm8 as u16);
-    flags.zero.set(false);
-    flags.subtract.set(false);
-    flags.carry.set((sp & 0xFF) + (imm8 as u16 & 0xFF) > 0xFF);
-    flags.half_carry.set((sp & 0xF) + (imm8 as u16 & 0xF) > 0xF);
+    let hl = add_sp_imm8(sp, imm8, flags);
     h.set((hl >> 8) as u8);
     l.set(hl as u8);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::DEFAULT_PALETTE;
+    use crate::test_rom;
+    use std::cell::RefCell;
+
+    /* M-cycles for every unprefixed opcode, conditional instructions with
+     * the branch not taken. 0 marks the prefix and the unused opcodes */
+    #[rustfmt::skip]
+    const OPCODE_CYCLES: [u8; 256] = [
+        1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+        1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+        2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+        3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+        3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    ];
+
+    /// M-cycles for a conditional opcode when the branch is taken
+    fn taken_cycles(opcode: u8) -> Option<u8> {
+        match opcode {
+            0x20 | 0x28 | 0x30 | 0x38 => Some(3),
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => Some(5),
+            0xC2 | 0xCA | 0xD2 | 0xDA => Some(4),
+            0xC4 | 0xCC | 0xD4 | 0xDC => Some(6),
+            _ => None,
+        }
+    }
+
+    /// M-cycles for a 0xCB prefixed opcode, prefix fetch included
+    fn cb_cycles(opcode: u8) -> u8 {
+        match (opcode & 0b111 == 0b110, opcode) {
+            (false, _) => 2,
+            (true, 0x40..=0x7F) => 3,
+            (true, _) => 4,
+        }
+    }
+
+    /// Runs one instruction with HL and SP in work RAM, C pointing into HRAM
+    /// and every flag set to `flags`, returning the cycles `step` reports
+    fn step_cycles(program: &[u8], flags: bool) -> u8 {
+        let mut cpu = test_rom::boot(program);
+        cpu.set_registers(RegisterFile {
+            a: 0,
+            f: if flags { 0xF0 } else { 0x00 },
+            b: 0,
+            c: 0x80,
+            d: 0,
+            e: 0,
+            h: 0xC0,
+            l: 0x00,
+            sp: 0xDFF0,
+            pc: 0x100,
+        });
+        cpu.step()
+    }
+
+    #[test]
+    fn opcode_cycle_table() {
+        for opcode in 0..=0xFF_u8 {
+            if OPCODE_CYCLES[opcode as usize] == 0 {
+                continue;
+            }
+            /* All flags clear takes NZ and NC, all flags set takes Z and C */
+            for flags in [false, true] {
+                let expected = match taken_cycles(opcode) {
+                    Some(taken) if flags == (opcode & 0b0000_1000 != 0) => taken,
+                    _ => OPCODE_CYCLES[opcode as usize],
+                };
+                let cycles = step_cycles(&[opcode, 0x00, 0xC0], flags);
+                assert_eq!(
+                    cycles, expected,
+                    "opcode {:#04x} with flags {}",
+                    opcode, flags
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cb_opcode_cycle_table() {
+        for opcode in 0..=0xFF_u8 {
+            let cycles = step_cycles(&[0xCB, opcode], false);
+            assert_eq!(cycles, cb_cycles(opcode), "opcode 0xcb {:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn default_frame_timing() {
+        /* An empty ROM is a NOP sled, one M-cycle per instruction */
+        let mut cpu = test_rom::boot(&[]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        assert_eq!(cpu.cycles_per_frame(), 17556);
+
+        cpu.game_loop(&mut frame);
+
+        assert_eq!(cpu.pc as u32, 0x100 + cpu.cycles_per_frame());
+    }
+
+    #[test]
+    fn double_speed_frame_timing() {
+        let mut cpu = test_rom::boot(&[]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.cycle_scale = 2;
+        /* The NOP sled runs on into VRAM, don't let the PPU lock it */
+        cpu.mmu.write_byte(0xFF40, 0);
+        assert_eq!(cpu.cycles_per_frame(), 35112);
+
+        cpu.game_loop(&mut frame);
+
+        assert_eq!(cpu.pc as u32, 0x100 + cpu.cycles_per_frame());
+    }
+
+    #[test]
+    fn run_until_vblank_then_finish_frame() {
+        let mut cpu = test_rom::boot(&[]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.mmu.write_byte(0xFF0F, 0);
+        cpu.mmu.write_byte(0xFFFF, Interrupt::VBlank.mask());
+        cpu.ime = true;
+
+        assert!(cpu.run_until_vblank(&mut frame));
+
+        /* Lines 0 to 143 have run, and the handler hasn't */
+        assert_eq!(cpu.pc as u32, 0x100 + 144 * 456 / 4);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 144);
+        assert_eq!(cpu.mmu.get_ppu_mode(), 1);
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::VBlank.mask());
+
+        /* The rest of the frame, not another whole one */
+        cpu.ime = false;
+        assert!(cpu.game_loop(&mut frame));
+        assert_eq!(cpu.pc as u32, 0x100 + cpu.cycles_per_frame());
+    }
+
+    #[test]
+    fn stat_mode_sequence() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x21, 0x00, 0xC0, /* ld hl, 0xC000 */
+            0xF0, 0x41,       /* ldh a, [STAT] */
+            0x22,             /* ld [hl+], a */
+            0x18, 0xFB,       /* jr -5 */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.run_line(&mut frame);
+        cpu.run_line(&mut frame);
+
+        /* A sample every 32 dots over two lines */
+        let mut modes: Vec<u8> = (0xC000..0xC01A)
+            .map(|address| cpu.mmu.read_byte(address) & 0b11)
+            .collect();
+        modes.dedup();
+        assert_eq!(modes, [2, 3, 0, 2, 3, 0]);
+    }
+
+    #[test]
+    fn vblank_starts_with_line_144() {
+        let mut cpu = test_rom::boot(&[]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.mmu.write_byte(0xFF0F, 0);
+        for _ in 0..144 {
+            cpu.run_line(&mut frame);
+        }
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 143);
+        assert_eq!(cpu.mmu.read_byte(0xFF0F) & Interrupt::VBlank.mask(), 0);
+
+        /* Requested even though IE doesn't enable it */
+        cpu.run_line(&mut frame);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 144);
+        assert_eq!(cpu.mmu.get_ppu_mode(), 1);
+        assert_ne!(cpu.mmu.read_byte(0xFF0F) & Interrupt::VBlank.mask(), 0);
+
+        cpu.game_loop(&mut frame);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 153);
+        cpu.run_line(&mut frame);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 0);
+    }
+
+    #[test]
+    fn shade_buffer_matches_frame() {
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        assert!(cpu.shades().is_none());
+        cpu.set_shade_buffer(true);
+        /* Solid color 1 tiles, shown as shade 2 through BGP */
+        for address in (0x8000..0x8010).step_by(2) {
+            cpu.mmu.write_byte(address, 0xFF);
+        }
+        cpu.mmu.write_byte(0xFF47, 0b0000_1000);
+        /* An object of color 3 in the top left corner, shown as shade 1 */
+        cpu.mmu.write_byte(0xFF40, 0x00);
+        for address in 0x8010..0x8020 {
+            cpu.mmu.write_byte(address, 0xFF);
+        }
+        cpu.mmu.write_byte(0xFE00, 16);
+        cpu.mmu.write_byte(0xFE01, 8);
+        cpu.mmu.write_byte(0xFE02, 1);
+        cpu.mmu.write_byte(0xFF48, 0b0100_0000);
+        cpu.mmu.write_byte(0xFF40, 0b1001_0011);
+
+        cpu.game_loop(&mut frame);
+
+        let shades = cpu.shades().unwrap();
+        let colors = cpu.color_indexes().unwrap();
+        for (index, (&shade, &color)) in shades.iter().zip(colors).enumerate() {
+            let expected = match (index % 160, index / 160) {
+                (0..=7, 0..=7) => (1, 3),
+                _ => (2, 1),
+            };
+            assert_eq!((shade, color), expected, "pixel {}", index);
+        }
+        for (shade, pixel) in shades.iter().zip(frame.chunks_exact(4)) {
+            assert_eq!(pixel, DEFAULT_PALETTE[*shade as usize]);
+        }
+    }
+
+    #[test]
+    fn interrupt_dispatch_priority() {
+        let mut cpu = test_rom::boot(&[]);
+        cpu.ime = true;
+        cpu.mmu
+            .write_byte(0xFFFF, Interrupt::Timer.mask() | Interrupt::Joypad.mask());
+        cpu.mmu.request_interrupt(Interrupt::Joypad);
+        cpu.mmu.request_interrupt(Interrupt::Timer);
+        cpu.mmu.request_interrupt(Interrupt::VBlank);
+
+        cpu.service_interrupts();
+
+        /* VBlank is not enabled in IE, so Timer wins over Joypad */
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.sp, 0xFFFC);
+        assert_eq!(cpu.mmu.read_word(0xFFFC), 0x100);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::Joypad.mask());
+    }
+
+    #[test]
+    fn no_dispatch_without_ime() {
+        let mut cpu = test_rom::boot(&[]);
+        cpu.mmu.write_byte(0xFFFF, Interrupt::Serial.mask());
+        cpu.mmu.request_interrupt(Interrupt::Serial);
+
+        cpu.service_interrupts();
+
+        assert_eq!(cpu.pc, 0x100);
+        assert!(cpu.has_pending_interrupt());
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::Serial.mask());
+    }
+
+    /// Runs `program` for a frame with the timer interrupt enabled and
+    /// already requested
+    fn run_with_pending_timer(program: &[u8]) -> Cpu {
+        let mut cpu = test_rom::boot_with(&[(0x50, &[0x18, 0xFE]), (0x100, program)]);
+        cpu.mmu.write_byte(0xFFFF, Interrupt::Timer.mask());
+        cpu.mmu.request_interrupt(Interrupt::Timer);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        cpu
+    }
+
+    #[test]
+    fn ei_enables_after_next_instruction() {
+        let cpu = run_with_pending_timer(&[0xFB, 0x00, 0x18, 0xFE]);
+
+        /* Dispatched after the NOP, not straight after EI */
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.mmu.read_word(cpu.sp), 0x102);
+    }
+
+    #[test]
+    fn di_cancels_pending_ei() {
+        let cpu = run_with_pending_timer(&[0xFB, 0xF3, 0x18, 0xFE]);
+
+        assert_eq!(cpu.pc, 0x102);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn di_ei_di_services_nothing() {
+        let cpu = run_with_pending_timer(&[0xF3, 0xFB, 0xF3, 0x18, 0xFE]);
+
+        assert_eq!(cpu.pc, 0x103);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn reti_enables_immediately() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot_with(&[
+            (0x50, &[0x18, 0xFE]),
+            (0x100, &[
+                0xCD, 0x00, 0x02, /* call 0x0200 */
+                0x00,             /* nop */
+                0x18, 0xFE,       /* jr -2 */
+            ]),
+            (0x200, &[0xD9]),     /* reti */
+        ]);
+        cpu.mmu.write_byte(0xFFFF, Interrupt::Timer.mask());
+        cpu.mmu.request_interrupt(Interrupt::Timer);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+
+        /* Dispatched straight after RETI, before the NOP it returned to */
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.mmu.read_word(cpu.sp), 0x103);
+    }
+
+    #[test]
+    fn halt_exits_without_ime() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x3E, 0x05, /* ld a, 0x05 */
+            0xE0, 0x07, /* ldh [TAC], a */
+            0x3E, 0x04, /* ld a, 0x04 */
+            0xE0, 0xFF, /* ldh [IE], a */
+            0x76,       /* halt */
+            0x3E, 0x42, /* ld a, 0x42 */
+            0xE0, 0x80, /* ldh [0xFF80], a */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+
+        cpu.game_loop(&mut frame);
+
+        assert!(cpu.state == State::Running);
+        assert_eq!(cpu.mmu.read_byte(0xFF80), 0x42);
+        assert_eq!(cpu.pc, 0x10D);
+        /* The timer interrupt woke the CPU but was never serviced */
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn stack_wraps_around_memory() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x31, 0x00, 0x00, /* ld sp, 0x0000 */
+            0x01, 0x34, 0x12, /* ld bc, 0x1234 */
+            0xC5,             /* push bc */
+            0x33,             /* inc sp */
+            0xD1,             /* pop de */
+            0x3B,             /* dec sp */
+            0x3B,             /* dec sp */
+            0xC9,             /* ret */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        for _ in 0..3 {
+            cpu.step_instruction(&mut frame);
+        }
+        /* Pushed to IE and the top of HRAM */
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.mmu.read_byte(0xFFFF), 0x12);
+        assert_eq!(cpu.mmu.read_byte(0xFFFE), 0x34);
+
+        cpu.step_instruction(&mut frame);
+        cpu.step_instruction(&mut frame);
+        /* Popped from IE then the first ROM byte */
+        assert_eq!(cpu.sp, 0x0001);
+        assert_eq!(cpu.registers.d.get(), 0x00);
+        assert_eq!(cpu.registers.e.get(), 0x12);
+
+        for _ in 0..3 {
+            cpu.step_instruction(&mut frame);
+        }
+        assert_eq!(cpu.sp, 0x0001);
+        assert_eq!(cpu.pc, 0x0012);
+    }
+
+    /* Selects the d-pad, stops, then counts in A from the byte after STOP */
+    #[rustfmt::skip]
+    const STOP: [u8; 8] = [
+        0x3E, 0x20, /* ld a, 0x20 */
+        0xE0, 0x00, /* ldh [P1], a */
+        0x10,       /* stop */
+        0x3C,       /* inc a */
+        0x3C,       /* inc a */
+        0x76,       /* halt */
+    ];
+
+    #[test]
+    fn stop_until_button_press() {
+        let mut cpu = test_rom::boot(&STOP);
+        cpu.mmu.write_byte(0xFF07, 0b101);
+        let mut frame = vec![0; 160 * 144 * 4];
+        for _ in 0..3 {
+            cpu.step_instruction(&mut frame);
+        }
+        assert!(cpu.state == State::Stopped);
+        assert_eq!(cpu.pc, 0x106);
+
+        /* DIV is reset and the timer doesn't run */
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0);
+        let (div, tima) = (cpu.mmu.div_counter(), cpu.mmu.read_byte(0xFF05));
+        cpu.game_loop(&mut frame);
+        assert!(cpu.state == State::Stopped);
+        assert_eq!(cpu.mmu.div_counter(), div);
+        assert_eq!(cpu.mmu.read_byte(0xFF05), tima);
+
+        /* Buttons on the unselected lines don't wake it */
+        cpu.mmu.joypad_a(true);
+        cpu.step_instruction(&mut frame);
+        assert!(cpu.state == State::Stopped);
+        cpu.mmu.joypad_down(true);
+        cpu.step_instruction(&mut frame);
+        cpu.step_instruction(&mut frame);
+        assert_eq!(cpu.registers.a.get(), 0x21);
+    }
+
+    #[test]
+    fn stop_edge_cases() {
+        let run = |button: bool, interrupt: bool| {
+            let mut frame = vec![0; 160 * 144 * 4];
+            let mut cpu = test_rom::boot(&STOP);
+            cpu.mmu.joypad_down(button);
+            cpu.mmu.write_byte(0xFF0F, 0);
+            if interrupt {
+                cpu.mmu.write_byte(0xFFFF, Interrupt::Timer.mask());
+                cpu.mmu.request_interrupt(Interrupt::Timer);
+            }
+            for _ in 0..3 {
+                cpu.step_instruction(&mut frame);
+            }
+            cpu
+        };
+
+        /* A 1-byte opcode that doesn't stop */
+        let cpu = run(true, true);
+        assert!(cpu.state == State::Running);
+        assert_eq!(cpu.pc, 0x105);
+        /* A 2-byte opcode that halts without resetting DIV */
+        let cpu = run(true, false);
+        assert!(cpu.state == State::Halted);
+        assert_eq!(cpu.pc, 0x106);
+        assert_ne!(cpu.mmu.read_byte(0xFF04), 0);
+        /* A 1-byte opcode that stops */
+        let cpu = run(false, true);
+        assert!(cpu.state == State::Stopped);
+        assert_eq!(cpu.pc, 0x105);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0);
+
+        /* An armed speed switch is taken but does nothing on DMG */
+        let mut cpu = test_rom::boot(&STOP);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.mmu.write_byte(0xFF4D, 0x01);
+        for _ in 0..3 {
+            cpu.step_instruction(&mut frame);
+        }
+        assert!(cpu.state == State::Running);
+        assert_eq!(cpu.pc, 0x106);
+        assert_eq!(cpu.mmu.read_byte(0xFF4D) & 1, 0);
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x3E, 0x04, /* ld a, 0x04 */
+            0xE0, 0xFF, /* ldh [IE], a */
+            0x3E, 0x05, /* ld a, 0x05 */
+            0xE0, 0x07, /* ldh [TAC], a */
+            0xFB,       /* ei */
+            0xD3,       /* illegal */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+
+        cpu.game_loop(&mut frame);
+        let tima = cpu.mmu.read_byte(0xFF05);
+        cpu.game_loop(&mut frame);
+
+        assert!(cpu.state == State::Locked);
+        assert_eq!(cpu.pc, 0x109);
+        /* The timer keeps counting, but its interrupt is never serviced */
+        assert_ne!(cpu.mmu.read_byte(0xFF05), tima);
+        assert_eq!(cpu.mmu.pending_interrupts(), Interrupt::Timer.mask());
+        assert_eq!(cpu.sp, 0xFFFE);
+
+        cpu.reset();
+        assert!(cpu.state == State::Running);
+    }
+
+    #[test]
+    fn frame_skip_keeps_emulating() {
+        let mut cpu = test_rom::boot(&[]);
+        let mut frame = vec![0xAA; 160 * 144 * 4];
+        cpu.frame_skip = 1;
+        /* The NOP sled runs on into VRAM, don't let the PPU lock it */
+        cpu.mmu.write_byte(0xFF40, 0);
+
+        assert!(cpu.game_loop(&mut frame));
+        frame.fill(0xAA);
+        assert!(!cpu.game_loop(&mut frame));
+        assert!(frame.iter().all(|&byte| byte == 0xAA));
+        assert_eq!(cpu.pc as u32, 0x100 + 2 * cpu.cycles_per_frame());
+        assert!(cpu.game_loop(&mut frame));
+    }
+
+    #[test]
+    fn skipped_frames_are_drawn_later() {
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        let mut frame = vec![0xAA; 160 * 144 * 4];
+        assert!(cpu.game_loop(&mut frame));
+        assert_eq!(frame[..4], DEFAULT_PALETTE[0]);
+
+        /* Shade 3 for the blank background, not drawn until the next frame */
+        cpu.mmu.write_byte(0xFF47, 0xFF);
+        frame.fill(0xAA);
+        cpu.skip_frame(&mut frame);
+        assert!(frame.iter().all(|&byte| byte == 0xAA));
+        assert!(cpu.game_loop(&mut frame));
+        assert_eq!(frame[..4], DEFAULT_PALETTE[3]);
+    }
+
+    #[test]
+    fn unchanged_frames_are_not_redrawn() {
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        let mut frames = (0..3)
+            .map(|_| cpu.game_loop(&mut frame))
+            .collect::<Vec<_>>();
+        assert_eq!(frames, [true, true, false]);
+
+        frame.fill(0xAA);
+        assert!(!cpu.game_loop(&mut frame));
+        assert!(frame.iter().all(|&byte| byte == 0xAA));
+
+        /* A palette change redraws, writing the same value again doesn't */
+        cpu.mmu.write_byte(0xFF47, 0xFF);
+        assert!(cpu.game_loop(&mut frame));
+        assert!(frame.chunks_exact(4).all(|pixel| pixel[0] == 20));
+        cpu.mmu.write_byte(0xFF47, 0xFF);
+        frames = (0..2).map(|_| cpu.game_loop(&mut frame)).collect();
+        assert_eq!(frames, [true, false]);
+    }
+
+    #[test]
+    fn reset_and_load_starts_new_game() {
+        let mut cpu = test_rom::boot(&[0x3E, 0x42, 0xE0, 0x80, 0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        assert_eq!(cpu.mmu.read_byte(0xFF80), 0x42);
+
+        cpu.reset_and_load(&test_rom::build(&[0x18, 0xFE])).unwrap();
+
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.mmu.read_byte(0xFF80), 0);
+        assert_eq!(cpu.mmu.read_byte(0x0101), 0xFE);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn reset_and_load_keeps_the_game_on_a_bad_rom() {
+        let mut cpu = test_rom::boot(&[0x3E, 0x42, 0xE0, 0x80, 0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        let pc = cpu.pc;
+
+        assert!(matches!(
+            cpu.reset_and_load(&[0; 0x100]),
+            Err(CartridgeError::TooSmall(0x100))
+        ));
+
+        assert_eq!(cpu.pc, pc);
+        assert_eq!(cpu.mmu.read_byte(0xFF80), 0x42);
+        assert_eq!(cpu.mmu.read_byte(0x0100), 0x3E);
+        cpu.game_loop(&mut frame);
+        assert_eq!(cpu.pc, pc);
+    }
+
+    #[test]
+    fn skip_boot_starts_at_entry_point() {
+        let mut cpu = Cpu::with_boot_rom(Some([0x31; 0x100]));
+        let rom = test_rom::build_with(&[(0x0000, &[0xC9]), (0x0100, &[0x18, 0xFE])]);
+        cpu.mmu.load_game(rom.as_slice()).unwrap();
+
+        cpu.skip_boot();
+
+        let registers = cpu.get_registers();
+        assert_eq!((registers.a, registers.f), (0x01, 0xB0));
+        assert_eq!((registers.b, registers.c), (0x00, 0x13));
+        assert_eq!((registers.d, registers.e), (0x00, 0xD8));
+        assert_eq!((registers.h, registers.l), (0x01, 0x4D));
+        assert_eq!((registers.sp, registers.pc), (0xFFFE, 0x0100));
+        assert_eq!(cpu.mmu.read_byte(0xFF50), 0xFF);
+        assert_eq!(cpu.mmu.read_byte(0xFF40), 0x91);
+        /* Cartridge visible under the boot ROM area */
+        assert_eq!(cpu.mmu.read_byte(0x0000), 0xC9);
+        assert_eq!(cpu.mmu.read_byte(0x0100), 0x18);
+    }
+
+    #[test]
+    fn skip_boot_sets_up_the_apu() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x3E, 0xF0, /* ld a, 0xF0 */
+            0xE0, 0x12, /* ldh [NR12], a */
+            0x3E, 0x87, /* ld a, 0x87 */
+            0xE0, 0x14, /* ldh [NR14], a */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+
+        /* NR50 and NR51 are left as the boot ROM sets them */
+        assert_eq!(cpu.mmu.read_byte(0xFF25), 0xF3);
+        assert!(cpu.apu.drain_samples().iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn reset_restarts_boot_rom() {
+        let mut cpu = test_rom::boot(&[0x3E, 0x42, 0xE0, 0x80, 0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        cpu.mmu.set_window_counter(5);
+        cpu.mmu.set_boot_rom(Some([0x31; 0x100]));
+
+        cpu.reset();
+
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.sp, 0);
+        assert_eq!(cpu.mmu.read_byte(0xFF50), 0);
+        assert_eq!(cpu.mmu.read_byte(0x0000), 0x31);
+        assert_eq!(cpu.mmu.read_byte(0xFF80), 0);
+        assert_eq!(cpu.mmu.read_byte(0x0100), 0x3E);
+        assert_eq!(cpu.mmu.get_window_counter(), 0);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0);
+    }
+
+    #[test]
+    fn reset_without_boot_rom_skips_it() {
+        let mut cpu = test_rom::boot(&[0x3E, 0x42, 0xE0, 0x80, 0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+
+        cpu.reset();
+
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.mmu.read_byte(0xFF50), 0xFF);
+        assert_eq!(cpu.mmu.read_byte(0xFF80), 0);
+        assert_eq!(cpu.mmu.read_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn on_frame_once_per_frame() {
+        use std::rc::Rc;
+
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        let frames = Rc::new(Cell::new(0));
+        let counter = frames.clone();
+        cpu.set_on_frame(move |frame| {
+            assert_eq!(frame.len(), 160 * 144 * 4);
+            counter.set(counter.get() + 1);
+        });
+        cpu.frame_skip = 1;
+
+        for _ in 0..3 {
+            cpu.game_loop(&mut frame);
+        }
+
+        assert_eq!(frames.get(), 3);
+    }
+
+    /// Runs `opcode` once with A and F preset, returning the new A and F
+    fn run_flag_op(opcode: u8, a: u8, f: u8) -> (u8, u8) {
+        let mut cpu = test_rom::boot(&[opcode]);
+        cpu.set_registers(RegisterFile {
+            a,
+            f,
+            ..cpu.get_registers()
+        });
+        cpu.step();
+        let registers = cpu.get_registers();
+        (registers.a, registers.f)
+    }
+
+    #[test]
+    fn cpl_flags() {
+        /* N and H set, Z and C kept */
+        assert_eq!(run_flag_op(0x2F, 0x5A, 0x00), (0xA5, 0x60));
+        assert_eq!(run_flag_op(0x2F, 0xFF, 0x90), (0x00, 0xF0));
+    }
+
+    #[test]
+    fn scf_flags() {
+        /* C set, N and H cleared, Z kept */
+        assert_eq!(run_flag_op(0x37, 0x12, 0x60), (0x12, 0x10));
+        assert_eq!(run_flag_op(0x37, 0x12, 0xE0), (0x12, 0x90));
+    }
+
+    #[test]
+    fn ccf_flags() {
+        /* C flipped, N and H cleared, Z kept */
+        assert_eq!(run_flag_op(0x3F, 0x12, 0x60), (0x12, 0x10));
+        assert_eq!(run_flag_op(0x3F, 0x12, 0xF0), (0x12, 0x80));
+        assert_eq!(run_flag_op(0x3F, 0x12, 0x80), (0x12, 0x90));
+    }
+
+    #[test]
+    fn jr_loop_is_hung() {
+        let mut cpu = test_rom::boot(&[0xF3, 0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.set_hang_frames(Some(3));
+
+        for _ in 0..2 {
+            cpu.game_loop(&mut frame);
+        }
+        assert!(!cpu.is_hung());
+        assert_eq!(cpu.run_frame(&mut frame), FrameStatus::Hung);
+        assert!(cpu.is_hung());
+    }
+
+    #[test]
+    fn counting_loop_is_not_hung() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x21, 0x00, 0xC0, /* ld hl, 0xC000 */
+            0x34,             /* inc [hl] */
+            0x18, 0xFD,       /* jr -3 */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.set_hang_frames(Some(2));
+
+        for _ in 0..4 {
+            cpu.game_loop(&mut frame);
+        }
+
+        assert!(!cpu.is_hung());
+    }
+
+    #[test]
+    fn hang_check_is_opt_in() {
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+
+        for _ in 0..4 {
+            assert_ne!(cpu.run_frame(&mut frame), FrameStatus::Hung);
+        }
+
+        assert!(!cpu.is_hung());
+        /* Writes aren't compared with memory while it's off */
+        cpu.mmu.write_byte(0xC000, 0x12);
+        assert!(!cpu.mmu.take_memory_changed());
+    }
+
+    #[test]
+    fn on_boot_at_handoff() {
+        use std::rc::Rc;
+
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x3E, 0x01, /* ld a, 0x01 */
+            0xE0, 0x50, /* ldh [0xFF50], a */
+            0xE0, 0x50, /* ldh [0xFF50], a */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        /* Boot ROM still mapped, as if it just jumped to 0x0100 */
+        cpu.mmu.restore_io(&[0; 0x80]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        let handoffs = Rc::new(RefCell::new(Vec::new()));
+        let seen = handoffs.clone();
+        cpu.set_on_boot(move |registers| seen.borrow_mut().push(*registers));
+
+        cpu.game_loop(&mut frame);
+
+        let handoffs = handoffs.borrow();
+        assert_eq!(handoffs.len(), 1);
+        assert_eq!(handoffs[0].pc, 0x104);
+        assert_eq!(handoffs[0].a, 0x01);
+    }
+
+    #[test]
+    fn last_step_describes_the_instruction() {
+        let mut cpu = test_rom::boot(&[0x00, 0x18, 0xFE]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        assert_eq!(cpu.last_step(), None);
+
+        cpu.step_instruction(&mut frame);
+        cpu.step_instruction(&mut frame);
+
+        let step = StepResult {
+            pc_before: 0x101,
+            opcode: 0x18,
+            cycles: 3,
+        };
+        assert_eq!(cpu.last_step(), Some(step));
+        cpu.state = State::Halted;
+        cpu.step_instruction(&mut frame);
+        assert_eq!(cpu.last_step(), None);
+    }
+
+    #[test]
+    fn trace_in_gameboy_doctor_format() {
+        use std::rc::Rc;
+
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x06, 0x42, /* ld b, 0x42 */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        cpu.set_registers(RegisterFile {
+            a: 0x01,
+            f: 0xB0,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            sp: 0xFFFE,
+            pc: 0x100,
+        });
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let seen = lines.clone();
+        cpu.set_trace(move |line| seen.borrow_mut().push(line.to_string()));
+
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.step_instruction(&mut frame);
+        cpu.step_instruction(&mut frame);
+
+        assert_eq!(
+            *lines.borrow(),
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:06,42,18,FE",
+                "A:01 F:B0 B:42 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:18,FE,00,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn register_file_round_trip() {
+        let mut cpu = Cpu::new();
+        let file = RegisterFile {
+            a: 0x01,
+            f: 0xFF,
+            b: 0x23,
+            c: 0x45,
+            d: 0x67,
+            e: 0x89,
+            h: 0xAB,
+            l: 0xCD,
+            sp: 0xFFFE,
+            pc: 0x0150,
+        };
+
+        cpu.set_registers(file);
+
+        assert!(cpu.registers.flags.zero.get());
+        assert!(cpu.registers.flags.carry.get());
+        assert_eq!(cpu.get_registers(), RegisterFile { f: 0xF0, ..file });
+    }
+
+    #[test]
+    fn multi_byte_write_order() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x31, 0x10, 0xFE, /* ld sp, 0xFE10 */
+            0x01, 0x34, 0x12, /* ld bc, 0x1234 */
+            0xC5,             /* push bc */
+            0x08, 0x00, 0x80, /* ld [0x8000], sp */
+        ]);
+        cpu.mmu.set_vram_log(true);
+        for _ in 0..4 {
+            cpu.step();
+        }
+
+        let writes: Vec<(u16, u8)> = cpu
+            .mmu
+            .take_vram_log()
+            .iter()
+            .map(|write| (write.address, write.value))
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                (0xFE0F, 0x12),
+                (0xFE0E, 0x34),
+                (0x8000, 0x0E),
+                (0x8001, 0xFE)
+            ]
+        );
+    }
+
+    fn counting_cpu() -> Cpu {
+        #[rustfmt::skip]
+        let cpu = test_rom::boot(&[
+            0x3C,       /* inc a */
+            0xE0, 0x80, /* ldh [0xFF80], a */
+            0x18, 0xFB, /* jr -5 */
+        ]);
+        cpu
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut cpu = counting_cpu();
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        let state = cpu.save_state();
+        let counter = cpu.mmu.read_byte(0xFF80);
+
+        cpu.game_loop(&mut frame);
+        assert_ne!(cpu.mmu.read_byte(0xFF80), counter);
+        cpu.load_state(&state).unwrap();
+
+        assert_eq!(cpu.mmu.read_byte(0xFF80), counter);
+        assert_eq!(cpu.save_state(), state);
+    }
+
+    #[test]
+    fn load_state_keeps_the_sound_registers() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0x3E, 0xF0, /* ld a, 0xF0 */
+            0xE0, 0x17, /* ldh [NR22], a */
+            0x3E, 0x87, /* ld a, 0x87 */
+            0xE0, 0x19, /* ldh [NR24], a */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        let state = cpu.save_state();
+
+        cpu.load_state(&state).unwrap();
+        cpu.apu.drain_samples();
+        /* Panning and the envelope come from the state, only the note is
+         * played again */
+        cpu.mmu.write_byte(0xFF19, 0x87);
+        cpu.game_loop(&mut frame);
+
+        assert!(cpu.apu.drain_samples().iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn bad_states_are_rejected() {
+        let mut cpu = counting_cpu();
+        let state = cpu.save_state();
+        cpu.registers.b.set(0x42);
+        let before = cpu.save_state();
+
+        assert_eq!(cpu.load_state(b"PNG"), Err(StateError::NotAState));
+        let mut newer = state.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(
+            cpu.load_state(&newer),
+            Err(StateError::UnsupportedVersion(VERSION + 1))
+        );
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        /* Magic, version, registers, SP, PC, IME, EI pending, state */
+        let mut bad_line = state.clone();
+        bad_line[20] = 154;
+        assert_eq!(cpu.load_state(&bad_line), Err(StateError::Corrupt));
+        assert_eq!(cpu.save_state(), before);
+
+        let mut other = Cpu::new();
+        let mut rom = test_rom::build(&[]);
+        rom[0x14D] = 0x12;
+        other.mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(other.load_state(&state), Err(StateError::WrongGame));
+    }
+
+    /* DAA worked out on a 9-bit result like SameBoy does: the high digit is
+     * checked after the low one is adjusted, and a carry out sets C */
+    fn reference_daa(a: u8, subtract: bool, half_carry: bool, carry: bool) -> (u8, u8) {
+        let mut result = a as u16;
+        if subtract {
+            if half_carry {
+                result = result.wrapping_sub(0x06) & 0xFF;
+            }
+            if carry {
+                result = result.wrapping_sub(0x60);
+            }
+        } else {
+            if half_carry || result & 0x0F > 0x09 {
+                result += 0x06;
+            }
+            if carry || result > 0x9F {
+                result += 0x60;
+            }
+        }
+        let carry = carry || result & 0x100 != 0;
+        let flags =
+            (((result as u8 == 0) as u8) << 7) | ((subtract as u8) << 6) | ((carry as u8) << 4);
+        (result as u8, flags)
+    }
+
+    #[test]
+    fn daa_matches_reference() {
+        let registers = Registers::new();
+        for a in 0..=0xFF {
+            for flags in 0..8 {
+                let (subtract, half_carry, carry) =
+                    (flags & 4 != 0, flags & 2 != 0, flags & 1 != 0);
+                registers.a.set(a);
+                registers.flags.zero.set(false);
+                registers.flags.subtract.set(subtract);
+                registers.flags.half_carry.set(half_carry);
+                registers.flags.carry.set(carry);
+
+                daa(&registers.a, &registers.flags);
+
+                assert_eq!(
+                    (registers.a.get(), registers.flags.to_u8()),
+                    reference_daa(a, subtract, half_carry, carry),
+                    "A={:02X} N={} H={} C={}",
+                    a,
+                    subtract,
+                    half_carry,
+                    carry
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn daa_after_bcd_arithmetic() {
+        let registers = Registers::new();
+        let bcd = |value: u8| ((value / 10) << 4) | (value % 10);
+        for x in 0..100u8 {
+            for y in 0..100u8 {
+                registers.flags.set_from_u8(0);
+                registers.a.set(bcd(x));
+                add_a_r8(&registers.a, bcd(y), &registers.flags);
+                daa(&registers.a, &registers.flags);
+                assert_eq!(registers.a.get(), bcd((x + y) % 100), "{} + {}", x, y);
+                assert_eq!(registers.flags.carry.get(), x + y >= 100, "{} + {}", x, y);
+
+                registers.flags.set_from_u8(0);
+                registers.a.set(bcd(x));
+                sub_a_r8(&registers.a, bcd(y), &registers.flags);
+                daa(&registers.a, &registers.flags);
+                let difference = (x + 100 - y) % 100;
+                assert_eq!(registers.a.get(), bcd(difference), "{} - {}", x, y);
+                assert_eq!(registers.flags.carry.get(), x < y, "{} - {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn adc_edge_cases() {
+        let registers = Registers::new();
+        let flags = &registers.flags;
+        /* (a, value, carry in) => (result, F) */
+        for (a, value, carry, result, f) in [
+            (0xFF, 0x00, true, 0x00, 0b1011_0000),
+            (0xFF, 0x00, false, 0xFF, 0b0000_0000),
+            (0x0F, 0x00, true, 0x10, 0b0010_0000),
+            (0x0E, 0x01, true, 0x10, 0b0010_0000),
+            (0x0E, 0x01, false, 0x0F, 0b0000_0000),
+            (0xF0, 0x0F, true, 0x00, 0b1011_0000),
+            (0x80, 0x7F, false, 0xFF, 0b0000_0000),
+        ] {
+            flags.set_from_u8(0);
+            assert_eq!(
+                adc(a, value, carry, flags),
+                result,
+                "{:02X} + {:02X} + {}",
+                a,
+                value,
+                carry
+            );
+            assert_eq!(flags.to_u8(), f, "{:02X} + {:02X} + {}", a, value, carry);
+        }
+    }
+
+    #[test]
+    fn sbc_edge_cases() {
+        let registers = Registers::new();
+        let flags = &registers.flags;
+        for (a, value, carry, result, f) in [
+            (0x00, 0x00, true, 0xFF, 0b0111_0000),
+            (0x00, 0xFF, false, 0x01, 0b0111_0000),
+            (0x10, 0x0F, true, 0x00, 0b1110_0000),
+            (0x10, 0x00, true, 0x0F, 0b0110_0000),
+            (0x01, 0x00, true, 0x00, 0b1100_0000),
+            (0xFF, 0xFF, true, 0xFF, 0b0111_0000),
+        ] {
+            flags.set_from_u8(0);
+            assert_eq!(
+                sbc(a, value, carry, flags),
+                result,
+                "{:02X} - {:02X} - {}",
+                a,
+                value,
+                carry
+            );
+            assert_eq!(flags.to_u8(), f, "{:02X} - {:02X} - {}", a, value, carry);
+        }
+    }
+
+    #[test]
+    fn sp_offset_flags() {
+        let registers = Registers::new();
+        let flags = &registers.flags;
+        /* (SP, e8) => (result, F) */
+        for (sp, imm8, result, f) in [
+            (0x0000, -1, 0xFFFF, 0b0000_0000),
+            (0x0001, -1, 0x0000, 0b0011_0000),
+            (0xFFF8, -1, 0xFFF7, 0b0011_0000),
+            (0x000F, -128, 0xFF8F, 0b0000_0000),
+            (0x00FF, -128, 0x007F, 0b0001_0000),
+            (0xFFFF, 1, 0x0000, 0b0011_0000),
+            (0x0008, 8, 0x0010, 0b0010_0000),
+        ] {
+            /* Z is always cleared */
+            flags.set_from_u8(0b1100_0000);
+            assert_eq!(
+                add_sp_imm8(sp, imm8, flags),
+                result,
+                "{:04X} {:+}",
+                sp,
+                imm8
+            );
+            assert_eq!(flags.to_u8(), f, "{:04X} {:+}", sp, imm8);
+
+            flags.set_from_u8(0b1100_0000);
+            add_hl_sp_imm8((&registers.h, &registers.l), sp, imm8, flags);
+            assert_eq!(
+                u16::from_be_bytes([registers.h.get(), registers.l.get()]),
+                result
+            );
+            assert_eq!(flags.to_u8(), f, "{:04X} {:+}", sp, imm8);
+        }
+    }
+}
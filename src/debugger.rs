@@ -0,0 +1,521 @@
+use crate::cpu::{Cpu, State, StepResult};
+use crate::disassembler::disassemble;
+use crate::mmu::{Watch, WatchHit};
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Write};
+
+/// Instructions kept for showing what ran before PC
+const HISTORY_LEN: usize = 8;
+
+const HELP: &str = "\
+c                  continue
+s [count]          step instructions
+r                  show registers
+l [count]          disassemble around PC
+x <addr> [len]     dump memory
+b [addr]           set a breakpoint, or list breakpoints and watchpoints
+w <addr> [r|w|rw]  watch reads, writes (the default) or both
+d <addr>           delete the breakpoint or watchpoint at addr
+q                  quit
+Addresses are hex, with or without 0x or $";
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    /// `request_break` was called
+    Requested,
+}
+
+/// What to do after a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Keep reading commands
+    Stay,
+    Continue,
+    Quit,
+}
+
+/// PC breakpoints and a command interpreter on top of a `Cpu`. Watchpoints
+/// are kept by the `Mmu`.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    break_requested: bool,
+    /// Run the next instruction even if it has a breakpoint, so continuing
+    /// from one doesn't stop straight away
+    resuming: bool,
+    history: VecDeque<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            break_requested: false,
+            resuming: false,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Returns whether there was a breakpoint at `address`
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Stops the run before the next instruction
+    pub fn request_break(&mut self) {
+        self.break_requested = true;
+    }
+
+    /// Runs the rest of the frame like `Cpu::game_loop` unless a breakpoint,
+    /// a watchpoint or `request_break` stops it first. Returns whether the
+    /// frame was drawn, or why it stopped.
+    pub fn run_frame(&mut self, cpu: &mut Cpu, frame: &mut [u8]) -> Result<bool, Stop> {
+        let mut stop = None;
+        /* The step before the first was recorded when the last run ended */
+        let mut first = true;
+        let drawn = cpu.run_until(frame, |cpu| {
+            if !std::mem::take(&mut first) {
+                self.record(cpu.last_step());
+            }
+            stop = self.check(cpu);
+            stop.is_some()
+        });
+        match (drawn, stop) {
+            (Some(drawn), _) => {
+                self.record(cpu.last_step());
+                Ok(drawn)
+            }
+            (None, Some(stop)) => Err(stop),
+            (None, None) => unreachable!("run_until only stops when asked"),
+        }
+    }
+
+    /// Adds an instruction that ran to the history, halted cycles are left
+    /// out
+    fn record(&mut self, step: Option<StepResult>) {
+        let Some(step) = step else {
+            return;
+        };
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(step.pc_before);
+    }
+
+    fn check(&mut self, cpu: &Cpu) -> Option<Stop> {
+        if let Some(hit) = cpu.mmu.take_watch_hit() {
+            return Some(Stop::Watchpoint(hit));
+        }
+        if std::mem::take(&mut self.break_requested) {
+            return Some(Stop::Requested);
+        }
+        /* PC sits on the next instruction while halted, it hasn't been
+         * reached until the CPU wakes */
+        if cpu.state != State::Running {
+            return None;
+        }
+        let resuming = std::mem::take(&mut self.resuming);
+        match !resuming && self.breakpoints.contains(&cpu.pc) {
+            true => Some(Stop::Breakpoint(cpu.pc)),
+            false => None,
+        }
+    }
+
+    /// Describes a stop and the instruction it stopped at
+    pub fn report(&self, cpu: &Cpu, stop: Stop, out: &mut impl Write) -> io::Result<()> {
+        match stop {
+            Stop::Breakpoint(address) => writeln!(out, "Breakpoint at {:04X}", address)?,
+            Stop::Watchpoint(hit) => writeln!(
+                out,
+                "Watchpoint: {} {:04X} = {:02X}",
+                if hit.write { "write" } else { "read" },
+                hit.address,
+                hit.value
+            )?,
+            Stop::Requested => writeln!(out, "Stopped")?,
+        }
+        self.list(cpu, 1, out)
+    }
+
+    /// Runs one command line, writing what it shows to `out`. Steps draw
+    /// into `frame` like the normal run does.
+    pub fn command(
+        &mut self,
+        cpu: &mut Cpu,
+        frame: &mut [u8],
+        line: &str,
+        out: &mut impl Write,
+    ) -> io::Result<Action> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Action::Stay);
+        };
+        let first = words.next();
+        let second = words.next();
+        match (command, first.map(parse_address)) {
+            ("c" | "continue", _) => {
+                self.resuming = true;
+                return Ok(Action::Continue);
+            }
+            ("q" | "quit", _) => return Ok(Action::Quit),
+            ("h" | "help", _) => writeln!(out, "{}", HELP)?,
+            ("s" | "step", _) => {
+                let count = first.and_then(|count| count.parse().ok()).unwrap_or(1);
+                for _ in 0..count {
+                    cpu.step_instruction(frame);
+                    self.record(cpu.last_step());
+                }
+                self.list(cpu, 1, out)?;
+            }
+            ("r" | "regs", _) => show_registers(cpu, out)?,
+            ("l" | "list", _) => {
+                let count = first.and_then(|count| count.parse().ok()).unwrap_or(8);
+                self.list(cpu, count, out)?;
+            }
+            ("x", Some(Some(address))) => {
+                let len = second.and_then(|len| len.parse().ok()).unwrap_or(64);
+                dump(cpu, address, len, out)?;
+            }
+            ("b", None) => {
+                for address in &self.breakpoints {
+                    writeln!(out, "Breakpoint {:04X}", address)?;
+                }
+                for (address, kind) in cpu.mmu.watchpoints() {
+                    writeln!(out, "Watchpoint {:04X} {:?}", address, kind)?;
+                }
+            }
+            ("b", Some(Some(address))) => self.add_breakpoint(address),
+            ("w", Some(Some(address))) => {
+                let kind = match second.unwrap_or("w") {
+                    "r" => Watch::Read,
+                    "w" => Watch::Write,
+                    "rw" => Watch::Access,
+                    other => {
+                        writeln!(out, "Unknown watch kind {}, expected r, w or rw", other)?;
+                        return Ok(Action::Stay);
+                    }
+                };
+                cpu.mmu.set_watchpoint(address, kind);
+            }
+            ("d", Some(Some(address))) => {
+                let breakpoint = self.remove_breakpoint(address);
+                if !cpu.mmu.remove_watchpoint(address) && !breakpoint {
+                    writeln!(out, "Nothing set at {:04X}", address)?;
+                }
+            }
+            ("x" | "b" | "w" | "d", _) => writeln!(out, "Expected an address, see help")?,
+            _ => writeln!(out, "Unknown command {}, see help", command)?,
+        }
+        Ok(Action::Stay)
+    }
+
+    /// Disassembles the instructions that ran just before PC, then `count`
+    /// from PC on
+    fn list(&self, cpu: &Cpu, count: usize, out: &mut impl Write) -> io::Result<()> {
+        for &address in &self.history {
+            if address != cpu.pc {
+                writeln!(
+                    out,
+                    "   {:04X}  {}",
+                    address,
+                    disassemble(&cpu.mmu, address).0
+                )?;
+            }
+        }
+        let mut address = cpu.pc;
+        for index in 0..count {
+            let (text, length) = disassemble(&cpu.mmu, address);
+            let marker = if index == 0 { "=>" } else { "  " };
+            writeln!(out, "{} {:04X}  {}", marker, address, text)?;
+            address = address.wrapping_add(length);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hex with an optional 0x or $ prefix
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn show_registers(cpu: &Cpu, out: &mut impl Write) -> io::Result<()> {
+    let registers = cpu.get_registers();
+    writeln!(
+        out,
+        "A:{:02X} F:{:02X} BC:{:02X}{:02X} DE:{:02X}{:02X} HL:{:02X}{:02X} SP:{:04X} PC:{:04X}",
+        registers.a,
+        registers.f,
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        registers.sp,
+        registers.pc
+    )?;
+    let flags: String = ["Z", "N", "H", "C"]
+        .iter()
+        .enumerate()
+        .map(|(bit, name)| match registers.f & (0x80 >> bit) != 0 {
+            true => *name,
+            false => "-",
+        })
+        .collect();
+    writeln!(
+        out,
+        "Flags:{} IME:{} IE:{:02X} IF:{:02X} LY:{}{}",
+        flags,
+        cpu.ime as u8,
+        cpu.mmu.peek(0xFFFF),
+        cpu.mmu.peek(0xFF0F),
+        cpu.mmu.peek(0xFF44),
+        match cpu.state {
+            State::Running => "",
+            State::Halted => " halted",
+            State::Stopped => " stopped",
+            State::Locked => " locked",
+        }
+    )
+}
+
+/// 16 bytes a row
+fn dump(cpu: &Cpu, start: u16, len: usize, out: &mut impl Write) -> io::Result<()> {
+    let addresses: Vec<u16> = (0..len)
+        .map(|offset| start.wrapping_add(offset as u16))
+        .collect();
+    for row in addresses.chunks(16) {
+        let bytes: Vec<String> = row
+            .iter()
+            .map(|&address| format!("{:02X}", cpu.mmu.peek(address)))
+            .collect();
+        writeln!(out, "{:04X}: {}", row[0], bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::RegisterFile;
+    use crate::test_rom;
+
+    /* Counts up in [0xC000] forever */
+    #[rustfmt::skip]
+    const COUNTER: [u8; 7] = [
+        0xFA, 0x00, 0xC0, /* ld a, [0xC000] */
+        0x3C,             /* inc a */
+        0xEA, 0x00, 0xC0, /* ld [0xC000], a */
+    ];
+
+    fn counter() -> Cpu {
+        let mut program = COUNTER.to_vec();
+        program.extend([0x18, 0xF7]); /* jr -9 */
+        test_rom::boot(&program)
+    }
+
+    fn run(debugger: &mut Debugger, cpu: &mut Cpu, line: &str) -> (Action, String) {
+        let mut frame = vec![0; 160 * 144 * 4];
+        let mut out = Vec::new();
+        let action = debugger.command(cpu, &mut frame, line, &mut out).unwrap();
+        (action, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn breakpoint_stops_and_continues() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        let mut frame = vec![0; 160 * 144 * 4];
+        run(&mut debugger, &mut cpu, "b 0x103");
+
+        assert_eq!(
+            debugger.run_frame(&mut cpu, &mut frame),
+            Err(Stop::Breakpoint(0x103))
+        );
+        assert_eq!(cpu.pc, 0x103);
+        /* The load ran, the increment hasn't */
+        assert_eq!(cpu.mmu.read_byte(0xC000), 0);
+
+        assert_eq!(run(&mut debugger, &mut cpu, "c").0, Action::Continue);
+        assert_eq!(
+            debugger.run_frame(&mut cpu, &mut frame),
+            Err(Stop::Breakpoint(0x103))
+        );
+        assert_eq!(cpu.mmu.read_byte(0xC000), 1);
+
+        run(&mut debugger, &mut cpu, "d 103");
+        run(&mut debugger, &mut cpu, "c");
+        assert!(debugger.run_frame(&mut cpu, &mut frame).is_ok());
+    }
+
+    #[test]
+    fn breakpoint_after_halt_waits_for_the_wake() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0xF3,       /* di */
+            0x3E, 0x04, /* ld a, 0x04 */
+            0xE0, 0xFF, /* ldh [IE], a */
+            0x3E, 0x05, /* ld a, 0x05 */
+            0xE0, 0x07, /* ldh [TAC], a */
+            0x76,       /* halt */
+            0x00,       /* nop */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        let mut debugger = Debugger::new();
+        let mut frame = vec![0; 160 * 144 * 4];
+        run(&mut debugger, &mut cpu, "b 0x10A");
+
+        assert_eq!(
+            debugger.run_frame(&mut cpu, &mut frame),
+            Err(Stop::Breakpoint(0x10A))
+        );
+        assert!(cpu.state == State::Running);
+        assert_ne!(cpu.mmu.read_byte(0xFF0F) & 0b100, 0);
+    }
+
+    #[test]
+    fn watchpoint_stops_after_the_access() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        let mut frame = vec![0; 160 * 144 * 4];
+        run(&mut debugger, &mut cpu, "w c000");
+
+        let hit = WatchHit {
+            address: 0xC000,
+            value: 1,
+            write: true,
+        };
+        assert_eq!(
+            debugger.run_frame(&mut cpu, &mut frame),
+            Err(Stop::Watchpoint(hit))
+        );
+        assert_eq!(cpu.pc, 0x107);
+
+        /* Reads are only caught when asked for */
+        run(&mut debugger, &mut cpu, "w c000 r");
+        run(&mut debugger, &mut cpu, "c");
+        let stop = debugger.run_frame(&mut cpu, &mut frame);
+        assert!(matches!(
+            stop,
+            Err(Stop::Watchpoint(WatchHit { write: false, .. }))
+        ));
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn ppu_reads_dont_hit_watchpoints() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        let mut frame = vec![0; 160 * 144 * 4];
+        /* The PPU reads SCX and SCY on every line, the program never does */
+        run(&mut debugger, &mut cpu, "w ff43 r");
+        run(&mut debugger, &mut cpu, "w ff42 r");
+        assert!(debugger.run_frame(&mut cpu, &mut frame).is_ok());
+    }
+
+    #[test]
+    fn requested_break() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        let mut frame = vec![0; 160 * 144 * 4];
+        debugger.request_break();
+        assert_eq!(
+            debugger.run_frame(&mut cpu, &mut frame),
+            Err(Stop::Requested)
+        );
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn step_and_list() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        let (_, text) = run(&mut debugger, &mut cpu, "s 2");
+        assert_eq!(
+            text,
+            "   0100  ld a, [0xC000]\n   0103  inc a\n=> 0104  ld [0xC000], a\n"
+        );
+        let (_, text) = run(&mut debugger, &mut cpu, "l 2");
+        assert!(text.ends_with("=> 0104  ld [0xC000], a\n   0107  jr 0x0100\n"));
+    }
+
+    #[test]
+    fn history_skips_halted_cycles() {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot(&[
+            0xF3, /* di */
+            0x76, /* halt */
+            0x00, /* nop */
+        ]);
+        let mut debugger = Debugger::new();
+        cpu.mmu.write_byte(0xFFFF, 0);
+        run(&mut debugger, &mut cpu, "s 10");
+        cpu.mmu.request_interrupt(crate::mmu::Interrupt::Timer);
+        cpu.mmu.write_byte(0xFFFF, 0b100);
+
+        let (_, text) = run(&mut debugger, &mut cpu, "s");
+
+        assert_eq!(text, "   0100  di\n   0101  halt\n=> 0102  nop\n");
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        cpu.set_registers(RegisterFile {
+            a: 0x01,
+            f: 0xB0,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            sp: 0xFFFE,
+            pc: 0x100,
+        });
+        let (_, text) = run(&mut debugger, &mut cpu, "r");
+        assert!(text.starts_with("A:01 F:B0 BC:0013 DE:00D8 HL:014D SP:FFFE PC:0100\n"));
+        assert!(text.contains("Flags:Z-HC"));
+
+        let (_, text) = run(&mut debugger, &mut cpu, "x 100 20");
+        assert_eq!(
+            text,
+            "0100: FA 00 C0 3C EA 00 C0 18 F7 00 00 00 00 00 00 00\n\
+             0110: 00 00 00 00\n"
+        );
+    }
+
+    #[test]
+    fn bad_commands() {
+        let mut cpu = counter();
+        let mut debugger = Debugger::new();
+        assert_eq!(run(&mut debugger, &mut cpu, "").0, Action::Stay);
+        assert_eq!(run(&mut debugger, &mut cpu, "q").0, Action::Quit);
+        assert!(run(&mut debugger, &mut cpu, "x zz")
+            .1
+            .starts_with("Expected an address"));
+        assert!(run(&mut debugger, &mut cpu, "d 1234")
+            .1
+            .starts_with("Nothing set"));
+        assert!(run(&mut debugger, &mut cpu, "frobnicate")
+            .1
+            .starts_with("Unknown command"));
+    }
+}
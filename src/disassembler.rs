@@ -0,0 +1,137 @@
+use crate::mmu::Mmu;
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = [
+    "add a,", "adc a,", "sub a,", "sbc a,", "and a,", "xor a,", "or a,", "cp a,",
+];
+const ROTATES: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+/// Decodes the instruction at `address`, returning it in the same syntax as
+/// the test programs' comments along with its length in bytes. Reads with
+/// `Mmu::peek`, so watchpoints don't see it.
+pub fn disassemble(mmu: &Mmu, address: u16) -> (String, u16) {
+    let opcode = mmu.peek(address);
+    let n8 = mmu.peek(address.wrapping_add(1));
+    let n16 = u16::from_le_bytes([n8, mmu.peek(address.wrapping_add(2))]);
+    /* Relative jumps are shown by their target */
+    let e8 = address.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+
+    let x = (opcode >> 6) as usize;
+    let y = (opcode >> 3 & 0b111) as usize;
+    let z = (opcode & 0b111) as usize;
+    let p = y >> 1;
+
+    let (text, length) = match (x, z) {
+        _ if opcode == 0xCB => {
+            let register = R8[(n8 & 0b111) as usize];
+            let bit = n8 >> 3 & 0b111;
+            let text = match n8 >> 6 {
+                0 => format!("{} {}", ROTATES[bit as usize], register),
+                1 => format!("bit {}, {}", bit, register),
+                2 => format!("res {}, {}", bit, register),
+                _ => format!("set {}, {}", bit, register),
+            };
+            (text, 2)
+        }
+        (0, 0) => match y {
+            0 => (String::from("nop"), 1),
+            1 => (format!("ld [{:#06X}], sp", n16), 3),
+            2 => (String::from("stop"), 2),
+            3 => (format!("jr {:#06X}", e8), 2),
+            _ => (format!("jr {}, {:#06X}", CONDITIONS[y - 4], e8), 2),
+        },
+        (0, 1) if y & 1 == 0 => (format!("ld {}, {:#06X}", R16[p], n16), 3),
+        (0, 1) => (format!("add hl, {}", R16[p]), 1),
+        (0, 2) if y & 1 == 0 => (format!("ld {}, a", R16_MEM[p]), 1),
+        (0, 2) => (format!("ld a, {}", R16_MEM[p]), 1),
+        (0, 3) if y & 1 == 0 => (format!("inc {}", R16[p]), 1),
+        (0, 3) => (format!("dec {}", R16[p]), 1),
+        (0, 4) => (format!("inc {}", R8[y]), 1),
+        (0, 5) => (format!("dec {}", R8[y]), 1),
+        (0, 6) => (format!("ld {}, {:#04X}", R8[y], n8), 2),
+        (0, _) => {
+            let name = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"][y];
+            (String::from(name), 1)
+        }
+        (1, _) if opcode == 0x76 => (String::from("halt"), 1),
+        (1, _) => (format!("ld {}, {}", R8[y], R8[z]), 1),
+        (2, _) => (format!("{} {}", ALU[y], R8[z]), 1),
+        (_, 0) => match y {
+            0..=3 => (format!("ret {}", CONDITIONS[y]), 1),
+            4 => (format!("ldh [{:#06X}], a", 0xFF00 | n8 as u16), 2),
+            5 => (format!("add sp, {}", n8 as i8), 2),
+            6 => (format!("ldh a, [{:#06X}]", 0xFF00 | n8 as u16), 2),
+            _ => (format!("ld hl, sp{:+}", n8 as i8), 2),
+        },
+        (_, 1) => match y {
+            1 => (String::from("ret"), 1),
+            3 => (String::from("reti"), 1),
+            5 => (String::from("jp hl"), 1),
+            7 => (String::from("ld sp, hl"), 1),
+            _ => (format!("pop {}", R16_STK[p]), 1),
+        },
+        (_, 2) => match y {
+            0..=3 => (format!("jp {}, {:#06X}", CONDITIONS[y], n16), 3),
+            4 => (String::from("ldh [c], a"), 1),
+            5 => (format!("ld [{:#06X}], a", n16), 3),
+            6 => (String::from("ldh a, [c]"), 1),
+            _ => (format!("ld a, [{:#06X}]", n16), 3),
+        },
+        (_, 3) => match y {
+            0 => (format!("jp {:#06X}", n16), 3),
+            6 => (String::from("di"), 1),
+            7 => (String::from("ei"), 1),
+            _ => (format!("db {:#04X}", opcode), 1),
+        },
+        (_, 4) if y < 4 => (format!("call {}, {:#06X}", CONDITIONS[y], n16), 3),
+        (_, 5) if y & 1 == 0 => (format!("push {}", R16_STK[p]), 1),
+        (_, 5) if y == 1 => (format!("call {:#06X}", n16), 3),
+        (_, 6) => (format!("{} {:#04X}", ALU[y], n8), 2),
+        (_, 7) => (format!("rst {:#04X}", y * 8), 1),
+        /* 0xE4, 0xEC, 0xF4, 0xFC, 0xDD, 0xED and 0xFD */
+        _ => (format!("db {:#04X}", opcode), 1),
+    };
+    (text, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble_bytes(bytes: &[u8]) -> (String, u16) {
+        let mut mmu = Mmu::new();
+        for (offset, &byte) in bytes.iter().enumerate() {
+            mmu.write_byte(0xC000 + offset as u16, byte);
+        }
+        disassemble(&mmu, 0xC000)
+    }
+
+    #[test]
+    fn decodes_each_group() {
+        let cases: [(&[u8], &str, u16); 16] = [
+            (&[0x00], "nop", 1),
+            (&[0x3E, 0x42], "ld a, 0x42", 2),
+            (&[0xE0, 0x80], "ldh [0xFF80], a", 2),
+            (&[0x18, 0xFE], "jr 0xC000", 2),
+            (&[0x20, 0x05], "jr nz, 0xC007", 2),
+            (&[0x21, 0x34, 0x12], "ld hl, 0x1234", 3),
+            (&[0x22], "ld [hl+], a", 1),
+            (&[0x76], "halt", 1),
+            (&[0x7E], "ld a, [hl]", 1),
+            (&[0xAF], "xor a, a", 1),
+            (&[0xCB, 0x7C], "bit 7, h", 2),
+            (&[0xCB, 0x37], "swap a", 2),
+            (&[0xCD, 0x00, 0x40], "call 0x4000", 3),
+            (&[0xF5], "push af", 1),
+            (&[0xF8, 0xFE], "ld hl, sp-2", 2),
+            (&[0xD3], "db 0xD3", 1),
+        ];
+        for (bytes, text, length) in cases {
+            assert_eq!(disassemble_bytes(bytes), (String::from(text), length));
+        }
+    }
+}
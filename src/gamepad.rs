@@ -0,0 +1,65 @@
+use crate::keymap::Button;
+use gilrs::{EventType, Gilrs};
+use trashgb::Mmu;
+
+/// Controllers driving the joypad next to the keyboard. gilrs picks up
+/// controllers connected later by itself.
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    /// `None` if the platform's gamepad API isn't available
+    pub fn open() -> Option<Self> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(Gamepads { gilrs }),
+            Err(err) => {
+                eprintln!("Couldn't open gamepads: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Applies the events since the last call to the joypad
+    pub fn poll(&mut self, mmu: &mut Mmu) {
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(button) = joypad_button(button) {
+                        button.set(mmu, true);
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(button) = joypad_button(button) {
+                        button.set(mmu, false);
+                    }
+                }
+                EventType::Connected => {
+                    println!("Gamepad connected: {}", self.gilrs.gamepad(event.id).name());
+                }
+                /* Nothing will release what was held when it was unplugged */
+                EventType::Disconnected => {
+                    for button in Button::ALL {
+                        button.set(mmu, false);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Face buttons by position, like the Game Boy's: East is A, South is B
+fn joypad_button(button: gilrs::Button) -> Option<Button> {
+    match button {
+        gilrs::Button::East => Some(Button::A),
+        gilrs::Button::South => Some(Button::B),
+        gilrs::Button::Start => Some(Button::Start),
+        gilrs::Button::Select => Some(Button::Select),
+        gilrs::Button::DPadUp => Some(Button::Up),
+        gilrs::Button::DPadDown => Some(Button::Down),
+        gilrs::Button::DPadLeft => Some(Button::Left),
+        gilrs::Button::DPadRight => Some(Button::Right),
+        _ => None,
+    }
+}
@@ -0,0 +1,199 @@
+//! Keyboard bindings for the joypad. A config file lists a button and the
+//! keys that press it on each line, named like winit's `VirtualKeyCode`:
+//!
+//! ```text
+//! # Buttons not listed keep their default keys
+//! a = J
+//! b = K, Numpad0
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use trashgb::Mmu;
+use winit::event::VirtualKeyCode;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    A,
+    B,
+    Start,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Start,
+        Button::Select,
+        Button::Up,
+        Button::Down,
+        Button::Left,
+        Button::Right,
+    ];
+
+    const NAMES: [(&'static str, Button); 8] = [
+        ("a", Button::A),
+        ("b", Button::B),
+        ("start", Button::Start),
+        ("select", Button::Select),
+        ("up", Button::Up),
+        ("down", Button::Down),
+        ("left", Button::Left),
+        ("right", Button::Right),
+    ];
+
+    /// Presses or releases the button on the joypad
+    pub fn set(self, mmu: &mut Mmu, pressed: bool) {
+        match self {
+            Button::A => mmu.joypad_a(pressed),
+            Button::B => mmu.joypad_b(pressed),
+            Button::Start => mmu.joypad_start(pressed),
+            Button::Select => mmu.joypad_select(pressed),
+            Button::Up => mmu.joypad_up(pressed),
+            Button::Down => mmu.joypad_down(pressed),
+            Button::Left => mmu.joypad_left(pressed),
+            Button::Right => mmu.joypad_right(pressed),
+        }
+    }
+}
+
+macro_rules! key_names {
+    ($($key:ident),* $(,)?) => {
+        const KEY_NAMES: &[(&str, VirtualKeyCode)] = &[$((stringify!($key), VirtualKeyCode::$key)),*];
+    };
+}
+
+key_names! {
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, A, B, C, D, E, F, G, H, I, J, K, L,
+    M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11,
+    F12, Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down, Back, Return, Space,
+    Tab, Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    NumpadEnter, Apostrophe, Backslash, Comma, Equals, Grave, LAlt, LBracket, LControl, LShift,
+    Minus, Period, RAlt, RBracket, RControl, RShift, Semicolon, Slash,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyMapError {
+    /// A line without `=`
+    Syntax(usize),
+    UnknownButton(usize, String),
+    UnknownKey(usize, String),
+}
+
+impl fmt::Display for KeyMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyMapError::Syntax(line) => {
+                write!(f, "Line {}: expected `button = key, ...`", line)
+            }
+            KeyMapError::UnknownButton(line, name) => {
+                write!(f, "Line {}: unknown button `{}`", line, name)
+            }
+            KeyMapError::UnknownKey(line, name) => {
+                write!(f, "Line {}: unknown key `{}`", line, name)
+            }
+        }
+    }
+}
+
+pub struct KeyMap {
+    keys: HashMap<VirtualKeyCode, Button>,
+}
+
+impl Default for KeyMap {
+    /// Arrows, Z and X for A and B, Enter for Start and Backspace for Select
+    fn default() -> Self {
+        KeyMap {
+            keys: HashMap::from([
+                (VirtualKeyCode::Up, Button::Up),
+                (VirtualKeyCode::Down, Button::Down),
+                (VirtualKeyCode::Left, Button::Left),
+                (VirtualKeyCode::Right, Button::Right),
+                (VirtualKeyCode::Z, Button::A),
+                (VirtualKeyCode::X, Button::B),
+                (VirtualKeyCode::Return, Button::Start),
+                (VirtualKeyCode::Back, Button::Select),
+            ]),
+        }
+    }
+}
+
+impl KeyMap {
+    /// Reads a config file over the default layout, see the module docs
+    pub fn parse(config: &str) -> Result<Self, KeyMapError> {
+        let mut map = KeyMap::default();
+        for (index, line) in config.lines().enumerate() {
+            let number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (button, keys) = line.split_once('=').ok_or(KeyMapError::Syntax(number))?;
+            let button = button.trim();
+            let button = Button::NAMES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(button))
+                .map(|&(_, button)| button)
+                .ok_or_else(|| KeyMapError::UnknownButton(number, button.to_string()))?;
+
+            map.keys.retain(|_, bound| *bound != button);
+            for key in keys.split(',').map(str::trim) {
+                let code = KEY_NAMES
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                    .map(|&(_, code)| code)
+                    .ok_or_else(|| KeyMapError::UnknownKey(number, key.to_string()))?;
+                map.keys.insert(code, button);
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn button(&self, key: VirtualKeyCode) -> Option<Button> {
+        self.keys.get(&key).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layout() {
+        let map = KeyMap::default();
+        assert_eq!(map.button(VirtualKeyCode::Z), Some(Button::A));
+        assert_eq!(map.button(VirtualKeyCode::Back), Some(Button::Select));
+        assert_eq!(map.button(VirtualKeyCode::J), None);
+    }
+
+    #[test]
+    fn config_replaces_listed_buttons() {
+        let map =
+            KeyMap::parse("# WASD\nup = W\nleft = a\n\ndown = S # comment\nright = D, Numpad6\n")
+                .unwrap();
+        assert_eq!(map.button(VirtualKeyCode::W), Some(Button::Up));
+        assert_eq!(map.button(VirtualKeyCode::A), Some(Button::Left));
+        assert_eq!(map.button(VirtualKeyCode::Numpad6), Some(Button::Right));
+        assert_eq!(map.button(VirtualKeyCode::Up), None);
+        /* Buttons not in the file keep their keys */
+        assert_eq!(map.button(VirtualKeyCode::Z), Some(Button::A));
+    }
+
+    #[test]
+    fn config_errors() {
+        assert_eq!(
+            KeyMap::parse("a = Z\nturbo = T").err(),
+            Some(KeyMapError::UnknownButton(2, String::from("turbo")))
+        );
+        assert_eq!(
+            KeyMap::parse("a = Shift").err(),
+            Some(KeyMapError::UnknownKey(1, String::from("Shift")))
+        );
+        assert_eq!(KeyMap::parse("a Z").err(), Some(KeyMapError::Syntax(1)));
+    }
+}
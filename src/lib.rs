@@ -0,0 +1,86 @@
+#![deny(clippy::all)]
+
+pub mod apu;
+pub mod border;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod link;
+pub mod mapper;
+pub mod mmu;
+pub mod ppu;
+pub mod registers;
+pub mod state;
+#[cfg(test)]
+mod test_rom;
+
+pub use cpu::{Cpu, FrameStatus};
+pub use mmu::{CartridgeError, Mmu};
+
+/// A Game Boy with a cartridge inserted, for running games without a
+/// front-end. `Cpu` has the rest of the controls.
+pub struct Emulator {
+    cpu: Cpu,
+}
+
+impl Emulator {
+    /// Powers on with `rom` inserted, at the cartridge entry point. Give
+    /// `cpu_mut().mmu` a boot ROM and reset to run one.
+    pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+        let mut cpu = Cpu::new();
+        cpu.mmu.load_game(rom)?;
+        Ok(Emulator { cpu })
+    }
+
+    /// Runs one frame, drawing into `frame`, a 160x144 RGBA buffer. Returns
+    /// whether anything was drawn, see `Cpu::game_loop`.
+    pub fn step_frame(&mut self, frame: &mut [u8]) -> bool {
+        self.cpu.game_loop(frame)
+    }
+
+    /// Runs one frame like `step_frame`, returning `FrameStatus::Hung` once
+    /// the game stops making progress, see `Cpu::set_hang_frames`
+    pub fn run_frame(&mut self, frame: &mut [u8]) -> FrameStatus {
+        self.cpu.run_frame(frame)
+    }
+
+    /// Calls `callback` with the 160x144 RGBA frame each time one finishes,
+    /// from inside `step_frame`. The slice is only valid during the call,
+    /// copy it out to keep the image.
+    pub fn set_frame_callback(&mut self, callback: impl FnMut(&[u8]) + 'static) {
+        self.cpu.set_on_frame(callback);
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn set_a(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_a(pressed);
+    }
+    pub fn set_b(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_b(pressed);
+    }
+    pub fn set_start(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_start(pressed);
+    }
+    pub fn set_select(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_select(pressed);
+    }
+    pub fn set_up(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_up(pressed);
+    }
+    pub fn set_down(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_down(pressed);
+    }
+    pub fn set_left(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_left(pressed);
+    }
+    pub fn set_right(&mut self, pressed: bool) {
+        self.cpu.mmu.joypad_right(pressed);
+    }
+}
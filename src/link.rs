@@ -0,0 +1,72 @@
+use crate::cpu::Cpu;
+use crate::mmu::Mmu;
+
+/// Swaps the serial data registers of two linked MMUs if either side has
+/// started a transfer with its internal clock.
+pub fn exchange(a: &mut Mmu, b: &mut Mmu) {
+    if !a.serial_transfer_pending() && !b.serial_transfer_pending() {
+        return;
+    }
+    let from_a = a.read_byte(0xFF01);
+    let from_b = b.serial_exchange(from_a);
+    a.serial_exchange(from_b);
+}
+
+/// Runs a frame on each emulator with their link ports connected. Transfers
+/// started during the frame complete at the end of it.
+pub fn run_frame(a: &mut Cpu, frame_a: &mut [u8], b: &mut Cpu, frame_b: &mut [u8]) -> (bool, bool) {
+    let rendered = (a.game_loop(frame_a), b.game_loop(frame_b));
+    exchange(&mut a.mmu, &mut b.mmu);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Interrupt;
+    use crate::test_rom;
+
+    #[test]
+    fn transfer_between_instances() {
+        #[rustfmt::skip]
+        let mut a = test_rom::boot(&[
+            0x3E, 0x42, /* ld a, 0x42 */
+            0xE0, 0x01, /* ldh [SB], a */
+            0x3E, 0x81, /* ld a, 0x81 */
+            0xE0, 0x02, /* ldh [SC], a */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        #[rustfmt::skip]
+        let mut b = test_rom::boot(&[
+            0x3E, 0x99, /* ld a, 0x99 */
+            0xE0, 0x01, /* ldh [SB], a */
+            0x3E, 0x80, /* ld a, 0x80 */
+            0xE0, 0x02, /* ldh [SC], a */
+            0x18, 0xFE, /* jr -2 */
+        ]);
+        let mut frame_a = vec![0; 160 * 144 * 4];
+        let mut frame_b = vec![0; 160 * 144 * 4];
+
+        run_frame(&mut a, &mut frame_a, &mut b, &mut frame_b);
+
+        assert_eq!(a.mmu.read_byte(0xFF01), 0x99);
+        assert_eq!(b.mmu.read_byte(0xFF01), 0x42);
+        assert_eq!(a.mmu.read_byte(0xFF02) & 0x80, 0);
+        assert_eq!(b.mmu.read_byte(0xFF02) & 0x80, 0);
+        assert_ne!(a.mmu.read_byte(0xFF0F) & Interrupt::Serial.mask(), 0);
+        assert_ne!(b.mmu.read_byte(0xFF0F) & Interrupt::Serial.mask(), 0);
+        assert!(!a.mmu.serial_transfer_pending());
+    }
+
+    #[test]
+    fn no_transfer_without_start() {
+        let mut a = Mmu::new();
+        let mut b = Mmu::new();
+        a.write_byte(0xFF01, 0x12);
+        b.write_byte(0xFF01, 0x34);
+        b.write_byte(0xFF02, 0x80);
+        exchange(&mut a, &mut b);
+        assert_eq!(a.read_byte(0xFF01), 0x12);
+        assert_eq!(b.read_byte(0xFF01), 0x34);
+    }
+}
@@ -1,12 +1,18 @@
 #![deny(clippy::all)]
 
-mod cpu;
-mod mapper;
-mod mmu;
-mod ppu;
-mod registers;
+#[cfg(not(target_arch = "wasm32"))]
+mod audio;
+#[cfg(not(target_arch = "wasm32"))]
+mod gamepad;
+mod keymap;
+mod pacer;
 
-use cpu::Cpu;
+use keymap::KeyMap;
+use pacer::FramePacer;
+use trashgb::border::Border;
+use trashgb::debugger::{Action, Debugger};
+use trashgb::ppu::ColorPalette;
+use trashgb::Cpu;
 
 use pixels::{Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
@@ -15,47 +21,436 @@ use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 
 #[cfg(target_arch = "wasm32")]
-use instant::{Duration, Instant};
+use instant::Instant;
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 #[cfg(target_arch = "wasm32")]
 use winit::platform::web::WindowExtWebSys;
 
 #[cfg(not(target_arch = "wasm32"))]
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+/* Frames run per frame shown while Space is held, Tab picks one */
+const FAST_FORWARD: [u32; 3] = [2, 4, 8];
+/* Speed while Left Shift is held */
+const SLOW_MOTION: f64 = 0.5;
 
 #[cfg(target_arch = "wasm32")]
 fn main() {
     std::panic::set_hook(Box::new(console_error_panic_hook::hook));
 }
 
+#[derive(Default)]
+struct Options {
+    /// Print serial output to stdout
+    serial: bool,
+    /// Image to frame the screen with
+    border: Option<Border>,
+    /// Report writes to IO registers the emulator doesn't implement
+    unhandled_io: bool,
+    /// Print the registers when the boot ROM hands off to the cartridge
+    log_boot: bool,
+    /// Refuse ROMs with a wrong header checksum instead of warning
+    strict_header: bool,
+    /// Boot ROM to run before the cartridge, skipped without one
+    boot: Option<[u8; 0x100]>,
+    /// Start at the cartridge entry point instead of running the boot ROM
+    skip_boot: bool,
+    /// Leave the DC offset in the audio instead of filtering it like a DMG
+    no_high_pass: bool,
+    /// Draw each frame's lines in parallel at VBlank
+    #[cfg(feature = "parallel")]
+    parallel: bool,
+    /// Which keys press which joypad buttons
+    keys: KeyMap,
+    /// Colors the four shades are shown in
+    colors: ColorPalette,
+    /// Starting window size as a multiple of the screen, 4 by default
+    scale: Option<u32>,
+    /// Start stopped in the debugger, F8 stops again
+    debug: bool,
+    /// Where battery-backed cartridge RAM is kept, next to the ROM
+    #[cfg(not(target_arch = "wasm32"))]
+    save: Option<PathBuf>,
+    /// Run this many frames without a window, then print a hash of the last
+    #[cfg(not(target_arch = "wasm32"))]
+    headless: Option<u32>,
+    /// Log every instruction to this file like Gameboy Doctor
+    #[cfg(not(target_arch = "wasm32"))]
+    trace: Option<PathBuf>,
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <rom>", args[0]);
+    let mut options = Options::default();
+    let mut paths = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--serial" => options.serial = true,
+            "--unhandled-io" => options.unhandled_io = true,
+            "--log-boot" => options.log_boot = true,
+            "--debug" => options.debug = true,
+            "--strict-header" => options.strict_header = true,
+            "--skip-boot" => options.skip_boot = true,
+            "--no-high-pass" => options.no_high_pass = true,
+            #[cfg(feature = "parallel")]
+            "--parallel" => options.parallel = true,
+            "--border" => match rest.next() {
+                Some(path) => options.border = Some(load_border(path)),
+                None => usage(&args[0]),
+            },
+            "--scale" => match rest.next().and_then(|scale| scale.parse().ok()) {
+                Some(scale) if scale > 0 => options.scale = Some(scale),
+                _ => usage(&args[0]),
+            },
+            "--palette" => match rest.next().and_then(|name| ColorPalette::from_name(name)) {
+                Some(colors) => options.colors = colors,
+                None => usage(&args[0]),
+            },
+            "--boot" => match rest.next() {
+                Some(path) => options.boot = Some(load_boot_rom(path)),
+                None => usage(&args[0]),
+            },
+            "--keys" => match rest.next() {
+                Some(path) => options.keys = load_keys(path),
+                None => usage(&args[0]),
+            },
+            "--trace" => match rest.next() {
+                Some(path) => options.trace = Some(PathBuf::from(path)),
+                None => usage(&args[0]),
+            },
+            "--headless" => match rest.next().and_then(|frames| frames.parse().ok()) {
+                Some(frames) => options.headless = Some(frames),
+                None => usage(&args[0]),
+            },
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 1 || paths[0].starts_with("--") {
+        usage(&args[0]);
+    }
+    let rom = match std::fs::read(paths[0]) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("Couldn't read {}: {}", paths[0], err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(frames) = options.headless {
+        run_headless(&rom, &options, frames);
+        return;
+    }
+    options.save = Some(Path::new(paths[0]).with_extension("sav"));
+    pollster::block_on(run(&rom, options));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "Usage: {} [--serial] [--unhandled-io] [--log-boot] [--debug] [--strict-header] [--boot <file>] [--skip-boot] [--no-high-pass] [--border <png>] [--scale <n>] [--palette <name>] [--keys <file>] [--trace <file>] [--headless <frames>] <rom>",
+        program
+    );
+    let palettes: Vec<&str> = ColorPalette::PRESETS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect();
+    eprintln!("Palettes: {}", palettes.join(", "));
+    std::process::exit(1);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_border(path: &str) -> Border {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Couldn't read {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    match Border::from_png(std::io::BufReader::new(file)) {
+        Ok(border) => border,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_boot_rom(path: &str) -> [u8; 0x100] {
+    let boot = match std::fs::read(path) {
+        Ok(boot) => boot,
+        Err(err) => {
+            eprintln!("Couldn't read {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    match boot.as_slice().try_into() {
+        Ok(boot) => boot,
+        Err(_) => {
+            eprintln!("{}: a boot ROM is 256 bytes, not {}", path, boot.len());
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_keys(path: &str) -> KeyMap {
+    let config = match std::fs::read_to_string(path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Couldn't read {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    match KeyMap::parse(&config) {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs `frames` frames without a window or saves, then prints a hash of the
+/// last frame to compare rendering between builds
+#[cfg(not(target_arch = "wasm32"))]
+fn run_headless(rom: &[u8], options: &Options, frames: u32) {
+    let mut cpu = Cpu::with_boot_rom(options.boot);
+    cpu.mmu.set_strict_header(options.strict_header);
+    if let Err(err) = cpu.mmu.load_game(rom) {
+        eprintln!("{}", err);
         std::process::exit(1);
     }
-    let rom = std::fs::read(&args[1]).unwrap();
-    pollster::block_on(run(&rom));
+    if options.skip_boot {
+        cpu.skip_boot();
+    }
+    cpu.mmu.set_serial_stdout(options.serial);
+    cpu.mmu.set_unhandled_io_log(options.unhandled_io);
+    cpu.mmu.set_colors(options.colors);
+    #[cfg(feature = "parallel")]
+    cpu.set_parallel_render(options.parallel);
+    if let Some(path) = &options.trace {
+        start_trace(&mut cpu, path);
+    }
+    let mut frame = vec![0; 160 * 144 * 4];
+    for _ in 0..frames {
+        cpu.game_loop(&mut frame);
+    }
+    println!("{:016x}", frame_hash(&frame));
+}
+
+/// Writes `Cpu::trace_line` to `path` before every instruction
+#[cfg(not(target_arch = "wasm32"))]
+fn start_trace(cpu: &mut Cpu, path: &Path) {
+    use std::io::Write;
+    let file = match std::fs::File::create(path) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Couldn't create {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let mut trace = std::io::BufWriter::new(file);
+    cpu.set_trace(move |line| {
+        let _ = writeln!(trace, "{}", line);
+    });
+}
+
+/// 64-bit FNV-1a, unlike `DefaultHasher` it's the same on every Rust version
+#[cfg(not(target_arch = "wasm32"))]
+fn frame_hash(frame: &[u8]) -> u64 {
+    frame.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+/// Loads the cartridge's save if it has a battery and was saved before.
+/// Returns false when there's a save that can't be loaded, which mustn't be
+/// overwritten on exit.
+#[cfg(not(target_arch = "wasm32"))]
+fn load_save(cpu: &mut Cpu, path: &Path) -> bool {
+    if !cpu.mmu.has_battery() || !path.exists() {
+        return true;
+    }
+    if let Err(err) = cpu.mmu.load_sram(path) {
+        eprintln!(
+            "Couldn't load {}: {}. Running without saving.",
+            path.display(),
+            err
+        );
+        return false;
+    }
+    true
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_save(cpu: &Cpu, path: &Path) {
+    if !cpu.mmu.has_battery() {
+        return;
+    }
+    if let Err(err) = cpu.mmu.save_sram(path) {
+        eprintln!("Couldn't save {}: {}", path.display(), err);
+    }
+}
+
+/// Save state slot, next to the ROM like the battery save
+#[cfg(not(target_arch = "wasm32"))]
+fn write_state(cpu: &Cpu, save: &Path) {
+    let path = save.with_extension("state");
+    match std::fs::write(&path, cpu.save_state()) {
+        Ok(()) => println!("Saved state to {}", path.display()),
+        Err(err) => eprintln!("Couldn't save {}: {}", path.display(), err),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_state(cpu: &mut Cpu, save: &Path) {
+    let path = save.with_extension("state");
+    let result = match std::fs::read(&path) {
+        Ok(data) => cpu.load_state(&data).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    match result {
+        Ok(()) => println!("Loaded state from {}", path.display()),
+        Err(err) => eprintln!("Couldn't load {}: {}", path.display(), err),
+    }
+}
+
+/// Writes the RGBA `frame` on screen to a timestamped PNG in the working
+/// directory
+#[cfg(not(target_arch = "wasm32"))]
+fn write_screenshot(frame: &[u8], width: u32, height: u32) {
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = format!("trashgb-{}.png", time.as_millis());
+    let result = std::fs::File::create(&path)
+        .map_err(png::EncodingError::from)
+        .and_then(|file| {
+            let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(frame)?;
+            writer.finish()
+        });
+    match result {
+        Ok(()) => println!("Saved screenshot to {}", path),
+        Err(err) => eprintln!("Couldn't save {}: {}", path, err),
+    }
+}
+
+/// Runs the rest of the frame under the debugger, reading commands from
+/// stdin whenever it stops. Returns whether the frame was drawn, or `None`
+/// to quit.
+fn debug_frame(debugger: &mut Debugger, cpu: &mut Cpu, frame: &mut [u8]) -> Option<bool> {
+    use std::io::Write;
+    let mut stdout = std::io::stdout();
+    loop {
+        let stop = match debugger.run_frame(cpu, frame) {
+            Ok(drawn) => return Some(drawn),
+            Err(stop) => stop,
+        };
+        debugger.report(cpu, stop, &mut stdout).ok()?;
+        loop {
+            print!("(debug) ");
+            stdout.flush().ok()?;
+            let mut line = String::new();
+            /* Quit at the end of input too */
+            if std::io::stdin().read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            match debugger.command(cpu, frame, &line, &mut stdout).ok()? {
+                Action::Stay => {}
+                Action::Continue => break,
+                Action::Quit => return None,
+            }
+        }
+    }
 }
 
 #[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 pub fn start(rom: &[u8]) {
     let rom: &'static [u8] = Box::leak(rom.to_vec().into_boxed_slice());
-    wasm_bindgen_futures::spawn_local(run(rom));
+    wasm_bindgen_futures::spawn_local(run(rom, Options::default()));
 }
 
-async fn run(rom: &[u8]) {
+/// The lightest shade as a linear color for the surface, which is sRGB
+fn letterbox_color(colors: &ColorPalette) -> pixels::wgpu::Color {
+    let [r, g, b, _] = colors.0[0].map(|channel| (channel as f64 / 255.0).powf(2.2));
+    pixels::wgpu::Color { r, g, b, a: 1.0 }
+}
+
+fn window_title(cpu: &Cpu) -> String {
+    match cpu.mmu.game_title() {
+        title if title.is_empty() => String::from("trashgb"),
+        title => format!("trashgb - {}", title),
+    }
+}
+
+async fn run(rom: &[u8], options: Options) {
     let rom = std::io::Cursor::new(rom);
+    let mut cpu = Cpu::with_boot_rom(options.boot);
+    cpu.mmu.set_strict_header(options.strict_header);
+    if let Err(err) = cpu.mmu.load_game(rom) {
+        eprintln!("{}", err);
+        return;
+    }
+    if options.skip_boot {
+        cpu.skip_boot();
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut save = options.save;
+    /* The save state slot stays usable when the battery save isn't */
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut save_writable = save
+        .as_deref()
+        .is_some_and(|path| load_save(&mut cpu, path));
+    cpu.mmu.set_serial_stdout(options.serial);
+    cpu.mmu.set_unhandled_io_log(options.unhandled_io);
+    cpu.mmu.set_colors(options.colors);
+    #[cfg(feature = "parallel")]
+    cpu.set_parallel_render(options.parallel);
+    if options.log_boot {
+        cpu.set_on_boot(|registers| println!("Boot ROM handed off: {:x?}", registers));
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(path) = &options.trace {
+        start_trace(&mut cpu, path);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let audio = audio::AudioOutput::open();
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(audio) = &audio {
+        cpu.apu.set_sample_rate(audio.sample_rate());
+    }
+    if options.no_high_pass {
+        cpu.apu.set_high_pass(None);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut gamepads = gamepad::Gamepads::open();
+
+    let (width, height) = match &options.border {
+        Some(border) => (border.width(), border.height()),
+        None => (160, 144),
+    };
+    let mut screen = vec![0; 160 * 144 * 4];
+
     let event_loop = EventLoop::new();
     let window = {
-        let size = LogicalSize::new(640.0, 576.0);
+        let scale = options.scale.unwrap_or(4) as f64;
+        let size = LogicalSize::new(width as f64 * scale, height as f64 * scale);
         WindowBuilder::new()
-            .with_title("trashgb")
+            .with_title(window_title(&cpu))
             .with_inner_size(size)
-            .with_min_inner_size(size)
+            .with_min_inner_size(LogicalSize::new(width, height))
             .build(&event_loop)
             .unwrap()
     };
@@ -68,21 +463,40 @@ async fn run(rom: &[u8]) {
             .and_then(|body| body.append_child(&web_sys::Element::from(canvas)).ok())
             .expect("couldn't append canvas to document body");
     }
-    let mut cpu = Cpu::new();
-    cpu.mmu.load_game(rom);
-
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new_async(160, 144, surface_texture).await.unwrap()
+        let mut pixels = Pixels::new_async(width, height, surface_texture)
+            .await
+            .unwrap();
+        /* pixels only scales by whole multiples and centers the image, fill
+         * the rest of the window with the lightest shade */
+        pixels.clear_color(letterbox_color(&options.colors));
+        pixels
     };
 
+    let mut debugger = options.debug.then(|| {
+        let mut debugger = Debugger::new();
+        debugger.request_break();
+        println!("Debugging, type h for help");
+        debugger
+    });
+
+    let mut pacer = FramePacer::new(Instant::now());
+    let mut fast_forward = false;
+    let mut fast_forward_speed = 0;
+    let mut slow_motion = false;
+    let mut paused = false;
     event_loop.run(move |event, _, control_flow| match event {
         Event::MainEventsCleared => {}
         Event::WindowEvent {
             event: WindowEvent::CloseRequested,
             ..
         } => {
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(path) = save.as_ref().filter(|_| save_writable) {
+                write_save(&cpu, path);
+            }
             *control_flow = ControlFlow::Exit;
         }
         Event::WindowEvent {
@@ -91,14 +505,87 @@ async fn run(rom: &[u8]) {
         } => {
             let _ = pixels.resize_surface(size.width, size.height);
         }
+        #[cfg(not(target_arch = "wasm32"))]
+        Event::WindowEvent {
+            event: WindowEvent::DroppedFile(path),
+            ..
+        } => {
+            let rom = match std::fs::read(&path) {
+                Ok(rom) => rom,
+                Err(err) => {
+                    eprintln!("Couldn't read {}: {}", path.display(), err);
+                    return;
+                }
+            };
+            /* Save the game being replaced first */
+            if let Some(old_save) = save.as_ref().filter(|_| save_writable) {
+                write_save(&cpu, old_save);
+            }
+            match cpu.reset_and_load(&rom) {
+                Ok(()) => {
+                    if options.skip_boot {
+                        cpu.skip_boot();
+                    }
+                    let new_save = path.with_extension("sav");
+                    save_writable = load_save(&mut cpu, &new_save);
+                    save = Some(new_save);
+                    window.set_title(&window_title(&cpu));
+                }
+                /* The current game keeps running */
+                Err(err) => eprintln!("Couldn't load {}: {}", path.display(), err),
+            }
+        }
         Event::NewEvents(StartCause::Init) => {
-            *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(16));
+            pacer = FramePacer::new(Instant::now());
+            *control_flow = ControlFlow::WaitUntil(pacer.next_frame(Instant::now()));
             pixels.render().unwrap();
         }
         Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
-            *control_flow = ControlFlow::WaitUntil(Instant::now() + Duration::from_millis(16));
-            if cpu.game_loop(pixels.frame_mut()) {
+            /* Deadlines follow the frame count so wake-up latency doesn't
+             * accumulate */
+            *control_flow = ControlFlow::WaitUntil(pacer.next_frame(Instant::now()));
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(gamepads) = &mut gamepads {
+                gamepads.poll(&mut cpu.mmu);
+            }
+            if paused {
                 pixels.render().unwrap();
+                return;
+            }
+            if fast_forward && debugger.is_none() {
+                for _ in 1..FAST_FORWARD[fast_forward_speed] {
+                    cpu.skip_frame(&mut screen);
+                    /* Only the shown frame is heard, so sound doesn't lag */
+                    cpu.apu.drain_samples();
+                }
+            }
+            let frame = match &options.border {
+                Some(_) => &mut screen[..],
+                None => pixels.frame_mut(),
+            };
+            let drawn = match &mut debugger {
+                Some(debugger) => match debug_frame(debugger, &mut cpu, frame) {
+                    Some(drawn) => drawn,
+                    None => {
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if let Some(path) = save.as_ref().filter(|_| save_writable) {
+                            write_save(&cpu, path);
+                        }
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                },
+                None => cpu.game_loop(frame),
+            };
+            if drawn {
+                if let Some(border) = &options.border {
+                    border.compose(&screen, pixels.frame_mut());
+                }
+                pixels.render().unwrap();
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(audio) = &audio {
+                audio.push(&cpu.apu.drain_samples());
             }
         }
         // Keyboard Input
@@ -107,30 +594,98 @@ async fn run(rom: &[u8]) {
             ..
         } => {
             if let Some(key) = input.virtual_keycode {
-                match input.state {
-                    winit::event::ElementState::Pressed => match key {
-                        VirtualKeyCode::Up => cpu.mmu.joypad_up(true),
-                        VirtualKeyCode::Down => cpu.mmu.joypad_down(true),
-                        VirtualKeyCode::Left => cpu.mmu.joypad_left(true),
-                        VirtualKeyCode::Right => cpu.mmu.joypad_right(true),
-                        VirtualKeyCode::Z => cpu.mmu.joypad_a(true),
-                        VirtualKeyCode::X => cpu.mmu.joypad_b(true),
-                        VirtualKeyCode::Return => cpu.mmu.joypad_start(true),
-                        VirtualKeyCode::Back => cpu.mmu.joypad_select(true),
-                        _ => {}
-                    },
-                    winit::event::ElementState::Released => match key {
-                        VirtualKeyCode::Up => cpu.mmu.joypad_up(false),
-                        VirtualKeyCode::Down => cpu.mmu.joypad_down(false),
-                        VirtualKeyCode::Left => cpu.mmu.joypad_left(false),
-                        VirtualKeyCode::Right => cpu.mmu.joypad_right(false),
-                        VirtualKeyCode::Z => cpu.mmu.joypad_a(false),
-                        VirtualKeyCode::X => cpu.mmu.joypad_b(false),
+                let pressed = input.state == winit::event::ElementState::Pressed;
+                if let Some(button) = options.keys.button(key) {
+                    button.set(&mut cpu.mmu, pressed);
+                } else if key == VirtualKeyCode::Space {
+                    fast_forward = pressed;
+                } else if key == VirtualKeyCode::LShift {
+                    if slow_motion != pressed {
+                        slow_motion = pressed;
+                        pacer.set_speed(if pressed { SLOW_MOTION } else { 1.0 });
+                    }
+                } else if !pressed {
+                    match key {
                         VirtualKeyCode::D => println!("{:08b}", cpu.mmu.read_byte(0xFF41)),
-                        VirtualKeyCode::Return => cpu.mmu.joypad_start(false),
-                        VirtualKeyCode::Back => cpu.mmu.joypad_select(false),
+                        VirtualKeyCode::R => {
+                            cpu.reset();
+                            if options.skip_boot {
+                                cpu.skip_boot();
+                            }
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        VirtualKeyCode::F5 => {
+                            if let Some(path) = &save {
+                                write_state(&cpu, path);
+                            }
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        VirtualKeyCode::F9 => {
+                            if let Some(path) = &save {
+                                read_state(&mut cpu, path);
+                            }
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        VirtualKeyCode::F12 => write_screenshot(pixels.frame(), width, height),
+                        VirtualKeyCode::F8 => {
+                            if let Some(debugger) = &mut debugger {
+                                debugger.request_break();
+                            }
+                        }
+                        VirtualKeyCode::P => {
+                            paused = !paused;
+                            let mut title = window_title(&cpu);
+                            if paused {
+                                title.push_str(" (Paused)");
+                            }
+                            window.set_title(&title);
+                        }
+                        VirtualKeyCode::Tab => {
+                            fast_forward_speed = (fast_forward_speed + 1) % FAST_FORWARD.len();
+                            println!("Fast-forward: {}x", FAST_FORWARD[fast_forward_speed]);
+                        }
+                        VirtualKeyCode::F => {
+                            cpu.frame_skip = (cpu.frame_skip + 1) % 4;
+                            println!("Frame skip: {}", cpu.frame_skip);
+                        }
+                        VirtualKeyCode::Key1
+                        | VirtualKeyCode::Key2
+                        | VirtualKeyCode::Key3
+                        | VirtualKeyCode::Key4 => {
+                            let channel = key as usize - VirtualKeyCode::Key1 as usize;
+                            let muted = !cpu.apu.muted(channel);
+                            cpu.apu.set_muted(channel, muted);
+                            println!(
+                                "Channel {} {}",
+                                channel + 1,
+                                if muted { "muted" } else { "unmuted" }
+                            );
+                        }
+                        VirtualKeyCode::F1
+                        | VirtualKeyCode::F2
+                        | VirtualKeyCode::F3
+                        | VirtualKeyCode::F4 => {
+                            let channel = key as usize - VirtualKeyCode::F1 as usize;
+                            let soloed = !cpu.apu.soloed(channel);
+                            cpu.apu.set_soloed(channel, soloed);
+                            println!(
+                                "Channel {} {}",
+                                channel + 1,
+                                if soloed { "soloed" } else { "unsoloed" }
+                            );
+                        }
+                        VirtualKeyCode::V => {
+                            for write in cpu.mmu.take_vram_log() {
+                                println!(
+                                    "LY {:3}: [{:#06x}] = {:#04x}",
+                                    write.line, write.address, write.value
+                                );
+                            }
+                            let enable = !cpu.mmu.vram_log_enabled();
+                            cpu.mmu.set_vram_log(enable);
+                        }
                         _ => {}
-                    },
+                    }
                 }
             }
         }
@@ -1,4 +1,34 @@
-use crate::mmu::Mmu;
+use crate::state::{StateError, StateReader, StateWriter};
+#[cfg(target_arch = "wasm32")]
+use instant::{Duration, Instant};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+
+/// Currently mapped ROM and external RAM banks
+pub struct Banks {
+    pub bank0: usize,
+    pub bank1: usize,
+    pub eram: Option<usize>,
+}
+
+impl Banks {
+    pub fn new() -> Self {
+        Banks {
+            bank0: 0,
+            bank1: 1,
+            eram: None,
+        }
+    }
+}
+
+impl Default for Banks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cartridge without a mapper, writes to the ROM area are ignored
+pub struct RomOnly;
 
 pub struct Mbc1 {
     rom_size: u8,
@@ -8,19 +38,97 @@ pub struct Mbc1 {
     rom_mode: u8,
 }
 
+/// MBC3, with a real-time clock on some cartridges
+pub struct Mbc3 {
+    ram_size: u8,
+    ram_enable: bool,
+    /// RAM bank 0x00-0x03, or RTC register 0x08-0x0C
+    ram_bank: u8,
+    /// Last value written to the latch register, 0x00 then 0x01 latches
+    latch: u8,
+    rtc: Option<Rtc>,
+}
+
+/// MBC5, for ROMs up to 8MiB
+pub struct Mbc5 {
+    ram_size: u8,
+    ram_enable: bool,
+    /// 9-bit ROM bank, 0 maps bank 0 at 0x4000 too
+    rom_bank: u16,
+    ram_bank: u8,
+}
+
+/// Length of the clock footer battery saves end with, see `Mapper::rtc_footer`
+pub const RTC_FOOTER_LEN: usize = 48;
+
+/// MBC3 clock, running on wall time. Reads see the registers as of the last
+/// latch. Battery saves keep it in a footer after the cartridge RAM.
+#[derive(Clone)]
+struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    /// 9-bit day counter
+    days: u16,
+    halt: bool,
+    /// Set when the day counter overflows, until cleared by a write
+    carry: bool,
+    latched: [u8; 5],
+    /// When the registers were last brought up to date, fractions of a
+    /// second since then are still to be counted
+    updated: Instant,
+}
+
 pub trait Mapper {
-    fn write_register(&mut self, address: u16, value: u8, mmu: &mut Mmu);
+    fn write_register(&mut self, address: u16, value: u8, banks: &mut Banks);
+
+    /// Reads 0xA000-0xBFFF when the mapper answers instead of RAM, such as
+    /// MBC3's clock registers
+    fn read_eram(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// Writes 0xA000-0xBFFF, returning whether the mapper took it instead
+    /// of RAM
+    fn write_eram(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    /// Appends the mapper's registers to a save state. What the cartridge
+    /// header decides, like the ROM size, is left out.
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+
+    /// The clock to append to a battery save, in the format BGB and VBA use:
+    /// the registers then the latched registers as 32-bit values, and `now`
+    /// as a 64-bit Unix time. `None` without a clock.
+    fn rtc_footer(&self, _now: u64) -> Option<[u8; RTC_FOOTER_LEN]> {
+        None
+    }
+
+    /// Restores the clock from `rtc_footer`, counting the time between when
+    /// it was saved and `now`. Returns false without a clock.
+    fn load_rtc_footer(&mut self, _footer: &[u8; RTC_FOOTER_LEN], _now: u64) -> bool {
+        false
+    }
+}
+
+impl Mapper for RomOnly {
+    fn write_register(&mut self, _address: u16, _value: u8, _banks: &mut Banks) {}
 }
 
 impl Mapper for Mbc1 {
-    fn write_register(&mut self, address: u16, value: u8, mmu: &mut Mmu) {
+    fn write_register(&mut self, address: u16, value: u8, banks: &mut Banks) {
         match address {
             0x0000..=0x1FFF => {
                 /* RAMG */
                 self.ram_enable = value & 0x0F == 0x0A && self.ram_size > 0;
                 match self.ram_enable {
-                    true => mmu.eram = Some(self.rom_bank as usize >> 5),
-                    false => mmu.eram = None,
+                    true => banks.eram = Some(self.rom_bank as usize >> 5),
+                    false => banks.eram = None,
                 }
             }
             0x2000..=0x3FFF => {
@@ -34,7 +142,7 @@ impl Mapper for Mbc1 {
                     n @ 2..96 if n < self.rom_size => n,
                     n => n % self.rom_size,
                 };
-                mmu.bank1 = bank as usize;
+                banks.bank1 = bank as usize;
             }
             0x4000..=0x5FFF => {
                 /* BANK2 */
@@ -42,10 +150,10 @@ impl Mapper for Mbc1 {
                 self.rom_bank |= (value & 0b0000_0011) << 5;
 
                 if self.rom_size < 64 || self.rom_mode == 0 {
-                    mmu.bank0 = 0;
+                    banks.bank0 = 0;
                 } else {
                     let bank = self.rom_bank as usize & 0b0110_0000;
-                    mmu.bank0 = bank;
+                    banks.bank0 = bank;
                 }
 
                 let mut rambank = self.rom_bank as usize >> 5;
@@ -61,28 +169,28 @@ impl Mapper for Mbc1 {
                 };
                 match self.rom_mode {
                     0 if self.ram_enable => {
-                        mmu.bank0 = 0;
-                        mmu.eram = Some(0);
+                        banks.bank0 = 0;
+                        banks.eram = Some(0);
                     }
                     0 if !self.ram_enable => {
-                        mmu.bank0 = 0;
-                        mmu.eram = None;
+                        banks.bank0 = 0;
+                        banks.eram = None;
                     }
                     1 if self.ram_enable => {
-                        mmu.bank0 = bank0;
-                        mmu.eram = Some(rambank);
+                        banks.bank0 = bank0;
+                        banks.eram = Some(rambank);
                     }
                     1 if !self.ram_enable => {
-                        mmu.bank0 = bank0;
-                        mmu.eram = None;
+                        banks.bank0 = bank0;
+                        banks.eram = None;
                     }
                     _ => unreachable!(),
                 }
                 if self.rom_size < 64 {
-                    mmu.bank0 = 0;
+                    banks.bank0 = 0;
                 }
 
-                mmu.bank1 = bank1 as usize;
+                banks.bank1 = bank1 as usize;
             }
             0x6000..=0x7FFF => {
                 /* MODE */
@@ -96,40 +204,298 @@ impl Mapper for Mbc1 {
 
                 match self.rom_mode {
                     0 if self.ram_enable => {
-                        mmu.bank0 = 0;
-                        mmu.eram = Some(0);
+                        banks.bank0 = 0;
+                        banks.eram = Some(0);
                     }
                     0 if !self.ram_enable => {
-                        mmu.bank0 = 0;
-                        mmu.eram = None;
+                        banks.bank0 = 0;
+                        banks.eram = None;
                     }
                     1 if self.ram_enable => {
-                        mmu.bank0 = bank0;
-                        mmu.eram = Some(rambank);
+                        banks.bank0 = bank0;
+                        banks.eram = Some(rambank);
                     }
                     1 if !self.ram_enable => {
-                        mmu.bank0 = bank0;
-                        mmu.eram = None;
+                        banks.bank0 = bank0;
+                        banks.eram = None;
                     }
                     _ => unreachable!(),
                 }
                 if self.rom_size < 64 {
-                    mmu.bank0 = 0;
+                    banks.bank0 = 0;
                 }
             }
             _ => unreachable!(),
         }
         if self.ram_size == 0 {
-            mmu.eram = None;
+            banks.eram = None;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.ram_enable);
+        state.u8(self.rom_bank);
+        state.u8(self.rom_mode);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = state.bool()?;
+        self.rom_bank = state.u8()?;
+        self.rom_mode = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn write_register(&mut self, address: u16, value: u8, banks: &mut Banks) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                /* 7-bit ROM bank, 0 maps to 1 */
+                banks.bank1 = match value & 0b0111_1111 {
+                    0 => 1,
+                    n => n as usize,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank = value,
+            0x6000..=0x7FFF => {
+                if self.latch == 0x00 && value == 0x01 {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.latch();
+                    }
+                }
+                self.latch = value;
+            }
+            _ => unreachable!(),
+        }
+        banks.eram = match self.ram_bank {
+            n @ 0x00..=0x03 if self.ram_enable && self.ram_size > 0 => {
+                Some(n as usize % self.ram_size as usize)
+            }
+            _ => None,
+        };
+    }
+
+    fn read_eram(&self, _address: u16) -> Option<u8> {
+        match (self.ram_bank, &self.rtc) {
+            (register @ 0x08..=0x0C, Some(rtc)) if self.ram_enable => {
+                Some(rtc.latched[register as usize - 0x08])
+            }
+            _ => None,
+        }
+    }
+
+    fn write_eram(&mut self, _address: u16, value: u8) -> bool {
+        match (self.ram_bank, self.rtc.as_mut()) {
+            (register @ 0x08..=0x0C, Some(rtc)) if self.ram_enable => {
+                rtc.write(register, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.ram_enable);
+        state.u8(self.ram_bank);
+        state.u8(self.latch);
+        if let Some(rtc) = &self.rtc {
+            let mut rtc = rtc.clone();
+            rtc.update();
+            state.bytes(&rtc.registers());
+            state.bytes(&rtc.latched);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = state.bool()?;
+        self.ram_bank = state.u8()?;
+        self.latch = state.u8()?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            for (register, &value) in (0x08..).zip(state.bytes(5)?) {
+                rtc.write(register, value);
+            }
+            state.read_into(&mut rtc.latched)?;
+            rtc.updated = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn rtc_footer(&self, now: u64) -> Option<[u8; RTC_FOOTER_LEN]> {
+        let mut rtc = self.rtc.clone()?;
+        rtc.update();
+        let mut footer = [0; RTC_FOOTER_LEN];
+        let registers = rtc.registers().into_iter().chain(rtc.latched);
+        for (field, value) in footer.chunks_exact_mut(4).zip(registers) {
+            field.copy_from_slice(&(value as u32).to_le_bytes());
+        }
+        footer[40..].copy_from_slice(&now.to_le_bytes());
+        Some(footer)
+    }
+
+    fn load_rtc_footer(&mut self, footer: &[u8; RTC_FOOTER_LEN], now: u64) -> bool {
+        let Some(rtc) = self.rtc.as_mut() else {
+            return false;
+        };
+        /* Each register is the low byte of a 32-bit field */
+        for (register, field) in (0x08..=0x0C).zip(footer.chunks_exact(4)) {
+            rtc.write(register, field[0]);
+        }
+        for (latched, field) in rtc.latched.iter_mut().zip(footer[20..].chunks_exact(4)) {
+            *latched = field[0];
+        }
+        rtc.updated = Instant::now();
+        let saved = u64::from_le_bytes(footer[40..].try_into().unwrap());
+        if !rtc.halt {
+            rtc.add_seconds(now.saturating_sub(saved));
+        }
+        true
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn write_register(&mut self, address: u16, value: u8, banks: &mut Banks) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enable = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = self.rom_bank & 0x100 | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = self.rom_bank & 0xFF | ((value & 1) as u16) << 8,
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0x6000..=0x7FFF => {}
+            _ => unreachable!(),
+        }
+        banks.bank1 = self.rom_bank as usize;
+        banks.eram = match self.ram_size {
+            0 => None,
+            _ if !self.ram_enable => None,
+            n => Some(self.ram_bank as usize % n as usize),
+        };
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.ram_enable);
+        state.u16(self.rom_bank);
+        state.u8(self.ram_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = state.bool()?;
+        self.rom_bank = state.u16()?;
+        self.ram_bank = state.u8()?;
+        Ok(())
+    }
+}
+
+impl Mbc5 {
+    pub fn new(ram_size: u8, banks: &mut Banks) -> Self {
+        banks.bank0 = 0;
+        banks.bank1 = 1;
+        banks.eram = None;
+        Mbc5 {
+            ram_size,
+            ram_enable: false,
+            rom_bank: 1,
+            ram_bank: 0,
+        }
+    }
+}
+
+impl Mbc3 {
+    pub fn new(ram_size: u8, has_rtc: bool, banks: &mut Banks) -> Self {
+        banks.bank0 = 0;
+        banks.bank1 = 1;
+        banks.eram = None;
+        Mbc3 {
+            ram_size,
+            ram_enable: false,
+            ram_bank: 0,
+            latch: 0xFF,
+            rtc: has_rtc.then(Rtc::new),
+        }
+    }
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            updated: Instant::now(),
+        }
+    }
+
+    /// Counts the whole seconds elapsed since the last update
+    fn update(&mut self) {
+        let elapsed = self.updated.elapsed().as_secs();
+        self.updated += Duration::from_secs(elapsed);
+        if !self.halt {
+            self.add_seconds(elapsed);
+        }
+    }
+
+    fn add_seconds(&mut self, elapsed: u64) {
+        let seconds = self.seconds as u64 + elapsed;
+        self.seconds = (seconds % 60) as u8;
+        let minutes = self.minutes as u64 + seconds / 60;
+        self.minutes = (minutes % 60) as u8;
+        let hours = self.hours as u64 + minutes / 60;
+        self.hours = (hours % 24) as u8;
+        let days = self.days as u64 + hours / 24;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            (self.days >> 8) as u8 | (self.halt as u8) << 6 | (self.carry as u8) << 7,
+        ]
+    }
+
+    fn latch(&mut self) {
+        self.update();
+        self.latched = self.registers();
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        match register {
+            0x08 => {
+                self.seconds = value & 0b0011_1111;
+                /* Writing the seconds restarts the current second */
+                self.updated = Instant::now();
+            }
+            0x09 => self.minutes = value & 0b0011_1111,
+            0x0A => self.hours = value & 0b0001_1111,
+            0x0B => self.days = self.days & 0x100 | value as u16,
+            0x0C => {
+                self.days = self.days & 0xFF | ((value & 1) as u16) << 8;
+                if self.halt && value & 0b0100_0000 == 0 {
+                    /* Time spent halted doesn't count */
+                    self.updated = Instant::now();
+                }
+                self.halt = value & 0b0100_0000 != 0;
+                self.carry = value & 0b1000_0000 != 0;
+            }
+            _ => unreachable!(),
         }
     }
 }
 
 impl Mbc1 {
-    pub fn new(rom_size: u8, ram_size: u8, mmu: &mut Mmu) -> Self {
-        mmu.bank0 = 0;
-        mmu.bank1 = 1;
-        mmu.eram = None;
+    pub fn new(rom_size: u8, ram_size: u8, banks: &mut Banks) -> Self {
+        banks.bank0 = 0;
+        banks.bank1 = 1;
+        banks.eram = None;
         Mbc1 {
             rom_size,
             ram_size,
@@ -139,3 +505,286 @@ impl Mbc1 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Rtc;
+    use crate::mmu::Mmu;
+
+    /* MBC1 cartridge with `banks` 16KiB banks, each holding its number at 0x200 */
+    fn mbc1(banks: usize) -> Mmu {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000 + 0x200] = bank as u8;
+        }
+        rom[0x147] = 0x01;
+        rom[0x148] = banks.trailing_zeros() as u8 - 1;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        mmu
+    }
+
+    /* Effective 0x4000-0x7FFF bank from the MBC1 pinout: BANK2 drives ROM
+     * A19-A20, the zero check only looks at the five BANK1 bits, and address
+     * lines the ROM doesn't have are ignored */
+    fn expected_bank(value: u8, banks: usize) -> u8 {
+        let bank1 = match value & 0b1_1111 {
+            0 => 1,
+            n => n,
+        };
+        ((value & 0b110_0000) | bank1) & (banks - 1) as u8
+    }
+
+    #[test]
+    fn bank_numbers() {
+        /* 256KiB, 512KiB, 1MiB and 2MiB */
+        for banks in [16, 32, 64, 128] {
+            let mut mmu = mbc1(banks);
+            for value in 0..0x80u8 {
+                mmu.write_byte(0x4000, value >> 5);
+                mmu.write_byte(0x2000, value & 0b1_1111);
+                assert_eq!(
+                    mmu.read_byte(0x4200),
+                    expected_bank(value, banks),
+                    "{} banks, bank {:#04x}",
+                    banks,
+                    value
+                );
+                assert_eq!(mmu.read_byte(0x0200), 0, "{} banks, mode 0", banks);
+            }
+            /* Same values with BANK1 written first */
+            for value in 0..0x80u8 {
+                mmu.write_byte(0x2000, value & 0b1_1111);
+                mmu.write_byte(0x4000, value >> 5);
+                assert_eq!(
+                    mmu.read_byte(0x4200),
+                    expected_bank(value, banks),
+                    "{} banks, bank {:#04x}",
+                    banks,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bank1_ignores_upper_bits() {
+        let mut mmu = mbc1(128);
+        mmu.write_byte(0x2000, 0b1110_0101);
+        assert_eq!(mmu.read_byte(0x4200), 0x05);
+        mmu.write_byte(0x2000, 0b1110_0000);
+        assert_eq!(mmu.read_byte(0x4200), 0x01);
+    }
+
+    #[test]
+    fn mode1_maps_bank2_at_0000() {
+        for banks in [64, 128] {
+            let mut mmu = mbc1(banks);
+            mmu.write_byte(0x6000, 0x01);
+            for bank2 in 0..4u8 {
+                mmu.write_byte(0x4000, bank2);
+                let expected = (bank2 << 5) & (banks - 1) as u8;
+                assert_eq!(mmu.read_byte(0x0200), expected, "{} banks", banks);
+            }
+        }
+        /* Small carts don't have the address lines */
+        let mut mmu = mbc1(32);
+        mmu.write_byte(0x6000, 0x01);
+        mmu.write_byte(0x4000, 0x01);
+        assert_eq!(mmu.read_byte(0x0200), 0);
+    }
+
+    /* MBC3+TIMER+RAM+BATTERY with 4 RAM banks, banks numbered like `mbc1` */
+    fn mbc3(banks: usize) -> Mmu {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000 + 0x200] = bank as u8;
+        }
+        rom[0x147] = 0x10;
+        rom[0x148] = banks.trailing_zeros() as u8 - 1;
+        rom[0x149] = 0x03;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        mmu
+    }
+
+    #[test]
+    fn mbc3_rom_banks() {
+        let mut mmu = mbc3(128);
+        for bank in 1..0x80u8 {
+            mmu.write_byte(0x2000, bank);
+            assert_eq!(mmu.read_byte(0x4200), bank);
+        }
+        mmu.write_byte(0x2000, 0x00);
+        assert_eq!(mmu.read_byte(0x4200), 0x01);
+        /* Only 7 bits */
+        mmu.write_byte(0x2000, 0x85);
+        assert_eq!(mmu.read_byte(0x4200), 0x05);
+        assert_eq!(mmu.read_byte(0x0200), 0x00);
+    }
+
+    #[test]
+    fn mbc3_ram_banks() {
+        let mut mmu = mbc3(4);
+        mmu.write_byte(0xA000, 0x42);
+        assert_eq!(mmu.read_byte(0xA000), 0xFF, "RAM disabled");
+
+        mmu.write_byte(0x0000, 0x0A);
+        for bank in 0..4 {
+            mmu.write_byte(0x4000, bank);
+            mmu.write_byte(0xA000, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            mmu.write_byte(0x4000, bank);
+            assert_eq!(mmu.read_byte(0xA000), 0x10 + bank);
+        }
+    }
+
+    #[test]
+    fn mbc3_rtc_latch() {
+        let mut mmu = mbc3(4);
+        mmu.write_byte(0x0000, 0x0A);
+        /* Halt the clock so the test doesn't race the wall clock */
+        mmu.write_byte(0x4000, 0x0C);
+        mmu.write_byte(0xA000, 0b0100_0001);
+        for (register, value) in [(0x08, 59), (0x09, 30), (0x0A, 23), (0x0B, 0xFF)] {
+            mmu.write_byte(0x4000, register);
+            mmu.write_byte(0xA000, value);
+        }
+
+        /* Reads see the old latched values until latched again */
+        mmu.write_byte(0x4000, 0x08);
+        assert_eq!(mmu.read_byte(0xA000), 0);
+        mmu.write_byte(0x6000, 0x00);
+        mmu.write_byte(0x6000, 0x01);
+        let registers: Vec<u8> = (0x08..=0x0C)
+            .map(|register| {
+                mmu.write_byte(0x4000, register);
+                mmu.read_byte(0xA000)
+            })
+            .collect();
+        assert_eq!(registers, [59, 30, 23, 0xFF, 0b0100_0001]);
+
+        /* Writing 0x01 again without 0x00 first doesn't latch */
+        mmu.write_byte(0x4000, 0x0A);
+        mmu.write_byte(0xA000, 5);
+        mmu.write_byte(0x6000, 0x01);
+        assert_eq!(mmu.read_byte(0xA000), 23);
+
+        /* The clock isn't mapped while RAM is disabled */
+        mmu.write_byte(0x0000, 0x00);
+        assert_eq!(mmu.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn rtc_carries() {
+        let mut rtc = Rtc::new();
+        rtc.add_seconds(59);
+        assert_eq!(rtc.registers(), [59, 0, 0, 0, 0]);
+        rtc.add_seconds(1);
+        assert_eq!(rtc.registers(), [0, 1, 0, 0, 0]);
+        rtc.add_seconds(23 * 3600 + 58 * 60 + 59);
+        assert_eq!(rtc.registers(), [59, 59, 23, 0, 0]);
+        rtc.add_seconds(1);
+        assert_eq!(rtc.registers(), [0, 0, 0, 1, 0]);
+
+        /* Day 511 overflows to 0 and sets the carry */
+        rtc.add_seconds(510 * 86400);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0xFF, 1]);
+        rtc.add_seconds(86400);
+        assert_eq!(rtc.registers(), [0, 0, 0, 0, 0b1000_0000]);
+    }
+
+    /* MBC5+RAM+BATTERY with 16 RAM banks, banks numbered like `mbc1` */
+    fn mbc5(banks: usize) -> Mmu {
+        let mut rom = vec![0; banks * 0x4000];
+        for bank in 0..banks {
+            rom[bank * 0x4000 + 0x200] = bank as u8;
+            rom[bank * 0x4000 + 0x201] = (bank >> 8) as u8;
+        }
+        rom[0x147] = 0x1B;
+        rom[0x148] = banks.trailing_zeros() as u8 - 1;
+        rom[0x149] = 0x04;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        mmu
+    }
+
+    #[test]
+    fn mbc5_selects_bank_0() {
+        let mut mmu = mbc5(64);
+        assert_eq!(mmu.read_byte(0x4200), 1);
+        mmu.write_byte(0x2000, 0x00);
+        assert_eq!(mmu.read_byte(0x4200), 0);
+        for address in 0..0x4000 {
+            assert_eq!(mmu.read_byte(0x4000 + address), mmu.read_byte(address));
+        }
+        mmu.write_byte(0x2000, 0x3F);
+        assert_eq!(mmu.read_byte(0x4200), 0x3F);
+    }
+
+    #[test]
+    fn mbc5_nine_bit_rom_bank() {
+        let mut mmu = mbc5(512);
+        for bank in [0x000u16, 0x0FF, 0x100, 0x155, 0x1FF] {
+            mmu.write_byte(0x2000, bank as u8);
+            mmu.write_byte(0x3000, (bank >> 8) as u8);
+            assert_eq!(mmu.read_byte(0x4200), bank as u8, "bank {:#05x}", bank);
+            assert_eq!(
+                mmu.read_byte(0x4201),
+                (bank >> 8) as u8,
+                "bank {:#05x}",
+                bank
+            );
+        }
+        /* The high register only has bit 8, and the low register 0x2000-0x2FFF */
+        mmu.write_byte(0x3000, 0xFE);
+        mmu.write_byte(0x2FFF, 0x12);
+        assert_eq!(mmu.read_byte(0x4200), 0x12);
+        assert_eq!(mmu.read_byte(0x4201), 0x00);
+    }
+
+    #[test]
+    fn mbc5_ram_banks() {
+        let mut mmu = mbc5(64);
+        mmu.write_byte(0xA000, 0x42);
+        assert_eq!(mmu.read_byte(0xA000), 0xFF, "RAM disabled");
+
+        mmu.write_byte(0x0000, 0x0A);
+        for bank in 0..16 {
+            mmu.write_byte(0x4000, bank);
+            mmu.write_byte(0xA000, 0x10 + bank);
+        }
+        for bank in 0..16 {
+            mmu.write_byte(0x4000, bank);
+            assert_eq!(mmu.read_byte(0xA000), 0x10 + bank);
+        }
+    }
+
+    #[test]
+    fn mapper_registers_in_save_state() {
+        use crate::state::{StateReader, StateWriter};
+
+        let mut mmu = mbc5(512);
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0x2000, 0x55);
+        mmu.write_byte(0x3000, 0x01);
+        mmu.write_byte(0x4000, 0x02);
+        mmu.write_byte(0xA000, 0x42);
+        let mut state = StateWriter::new();
+        mmu.save_state(&mut state);
+        let state = state.finish();
+
+        mmu.write_byte(0x3000, 0x00);
+        mmu.write_byte(0x4000, 0x00);
+        mmu.write_byte(0x0000, 0x00);
+        mmu.load_state(&mut StateReader::new(&state)).unwrap();
+
+        assert_eq!(mmu.read_byte(0x4200), 0x55);
+        assert_eq!(mmu.read_byte(0x4201), 0x01);
+        assert_eq!(mmu.read_byte(0xA000), 0x42);
+        /* The mapper's own registers came back too, not just the banks */
+        mmu.write_byte(0x2000, 0x00);
+        assert_eq!(mmu.read_byte(0x4201), 0x01);
+    }
+}
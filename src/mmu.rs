@@ -1,8 +1,207 @@
-use crate::mapper::{Mapper, Mbc1};
-use crate::ppu::Palette;
+use crate::mapper::{Banks, Mapper, Mbc1, Mbc3, Mbc5, RomOnly, RTC_FOOTER_LEN};
+use crate::ppu::{draw_background, draw_sprites, draw_window, ColorPalette, Video};
+use crate::state::{StateError, StateReader, StateWriter};
+use std::cell::Cell;
+use std::fmt;
 use std::io::BufReader;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+#[derive(Debug)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnsupportedMapper(u8),
+    RamOutOfRange { bank: usize, len: usize },
+    HeaderChecksum { stored: u8, computed: u8 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(size) => {
+                write!(
+                    f,
+                    "ROM is {} bytes, too small to be a cartridge (at least 16KiB)",
+                    size
+                )
+            }
+            CartridgeError::InvalidRomSize(value) => {
+                write!(
+                    f,
+                    "Invalid ROM size {:#04x} at 0x0148 (expected 0x00-0x08)",
+                    value
+                )
+            }
+            CartridgeError::InvalidRamSize(value) => {
+                write!(
+                    f,
+                    "Invalid RAM size {:#04x} at 0x0149 (expected 0x00-0x05)",
+                    value
+                )
+            }
+            CartridgeError::UnsupportedMapper(value) => {
+                write!(f, "Unsupported mapper {:#04x} at 0x0147", value)
+            }
+            CartridgeError::RamOutOfRange { bank, len } => {
+                write!(
+                    f,
+                    "{} bytes at RAM bank {} don't fit the cartridge RAM",
+                    len, bank
+                )
+            }
+            CartridgeError::HeaderChecksum { stored, computed } => {
+                write!(
+                    f,
+                    "Header checksum at 0x014D is {:#04x} but the header sums to {:#04x}, the ROM may be a bad dump",
+                    stored, computed
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    WrongSize { expected: usize, actual: usize },
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(err) => write!(f, "{}", err),
+            SaveError::WrongSize { expected, actual } => {
+                write!(
+                    f,
+                    "Save is {} bytes, the cartridge has {} bytes of RAM",
+                    actual, expected
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(err: std::io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+/// Interrupt sources in priority order, matching their bit in IE and IF
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interrupt {
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    pub const ALL: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::Stat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    /// Bit of this interrupt in IE and IF
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Address of the interrupt handler
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self as u16
+    }
+}
+
+/* DMG IO register state when the boot ROM hands over to the cartridge */
+const POST_BOOT_IO: [(u16, u8); 40] = [
+    (0xFF00, 0xCF), /* P1 */
+    (0xFF01, 0x00), /* SB */
+    (0xFF02, 0x7E), /* SC */
+    (0xFF04, 0xAB), /* DIV */
+    (0xFF05, 0x00), /* TIMA */
+    (0xFF06, 0x00), /* TMA */
+    (0xFF07, 0xF8), /* TAC */
+    (0xFF0F, 0xE1), /* IF */
+    (0xFF10, 0x80), /* NR10 */
+    (0xFF11, 0xBF), /* NR11 */
+    (0xFF12, 0xF3), /* NR12 */
+    (0xFF13, 0xFF), /* NR13 */
+    (0xFF14, 0xBF), /* NR14 */
+    (0xFF16, 0x3F), /* NR21 */
+    (0xFF17, 0x00), /* NR22 */
+    (0xFF18, 0xFF), /* NR23 */
+    (0xFF19, 0xBF), /* NR24 */
+    (0xFF1A, 0x7F), /* NR30 */
+    (0xFF1B, 0xFF), /* NR31 */
+    (0xFF1C, 0x9F), /* NR32 */
+    (0xFF1D, 0xFF), /* NR33 */
+    (0xFF1E, 0xBF), /* NR34 */
+    (0xFF20, 0xFF), /* NR41 */
+    (0xFF21, 0x00), /* NR42 */
+    (0xFF22, 0x00), /* NR43 */
+    (0xFF23, 0xBF), /* NR44 */
+    (0xFF24, 0x77), /* NR50 */
+    (0xFF25, 0xF3), /* NR51 */
+    (0xFF26, 0xF1), /* NR52 */
+    (0xFF40, 0x91), /* LCDC */
+    (0xFF41, 0x85), /* STAT */
+    (0xFF42, 0x00), /* SCY */
+    (0xFF43, 0x00), /* SCX */
+    (0xFF44, 0x00), /* LY */
+    (0xFF45, 0x00), /* LYC */
+    (0xFF46, 0xFF), /* DMA */
+    (0xFF47, 0xFC), /* BGP */
+    (0xFF4A, 0x00), /* WY */
+    (0xFF4B, 0x00), /* WX */
+    (0xFF50, 0xFF), /* Boot ROM disabled */
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VramWrite {
+    pub address: u16,
+    pub value: u8,
+    pub line: u8,
+}
+
+/// Which accesses a watchpoint catches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+/// A CPU access to a watched address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+/// An OAM DMA transfer under way, copying a byte per M-cycle
+#[derive(Clone, Copy)]
+struct Dma {
+    source: u16,
+    /// Bytes copied so far, out of 0xA0
+    copied: u16,
+    /// Started by the instruction still being run
+    starting: bool,
+}
+
+#[derive(Default)]
 pub struct Joypad {
     a: bool,
     b: bool,
@@ -16,11 +215,11 @@ pub struct Joypad {
 
 pub struct Mmu {
     // Memory Map
-    bootstrap: [u8; 0x100],
-    pub bank0: usize,
-    pub bank1: usize,
+    /// Mapped over 0x0000-0x00FF until 0xFF50 is written, `None` to always
+    /// start past it
+    bootstrap: Option<[u8; 0x100]>,
+    banks: Banks,
     vram: [u8; 0x2000],
-    pub eram: Option<usize>,
     wram1: [u8; 0x2000],
     wram2: [u8; 0x2000],
     oam: [u8; 0x00A0],
@@ -33,8 +232,35 @@ pub struct Mmu {
     // Misc
     window_counter: u8,
     timer: u16,
+    /// TIMA overflowed and reads 0 until the next M-cycle reloads it
+    tima_overflow: bool,
+    /// This M-cycle reloaded TIMA from TMA
+    tima_reloaded: bool,
+    dma: Option<Dma>,
     joypad: Joypad,
-    mapper: Option<Box<dyn Mapper>>,
+    serial_transfer: bool,
+    serial_sink: Option<Box<dyn Write>>,
+    stat_line: bool,
+    memory_changed: bool,
+    /// Whether writes are compared with memory to set `memory_changed`
+    track_memory: bool,
+    vram_dirty: bool,
+    boot_handoff: bool,
+    audio_writes: Vec<(u16, u8)>,
+    /// Offset of the wave RAM byte channel 3 is playing, `None` while it's
+    /// off
+    wave_byte: Option<u8>,
+    mapper: Box<dyn Mapper>,
+    colors: ColorPalette,
+    /// Refuse cartridges whose header checksum is wrong instead of warning
+    strict_header: bool,
+    // Debug
+    vram_log: Option<Vec<VramWrite>>,
+    unhandled_io: Option<Vec<u16>>,
+    seed: Option<u64>,
+    watchpoints: Vec<(u16, Watch)>,
+    /* Set from read_byte, which only borrows */
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl Joypad {
@@ -76,10 +302,65 @@ impl Joypad {
     }
 }
 
+/// IO registers with behavior behind them, everything else in 0xFF00-0xFF7F
+/// is only stored
+fn io_implemented(address: u16) -> bool {
+    matches!(
+        address,
+        0xFF00..=0xFF02 /* P1, SB, SC */
+        | 0xFF04..=0xFF07 /* DIV, TIMA, TMA, TAC */
+        | 0xFF0F /* IF */
+        | 0xFF10..=0xFF26 /* Sound */
+        | 0xFF30..=0xFF3F /* Wave RAM */
+        | 0xFF40..=0xFF4B /* LCD */
+        | 0xFF50 /* Boot ROM disable */
+    )
+}
+
+/// Bits of NR10-NR52 (0xFF10-0xFF26) that always read back as 1, whether
+/// they are unused or write-only
+const APU_READ_MASK: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, /* NR10-NR14 */
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, /* NR20 (unused), NR21-NR24 */
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, /* NR30-NR34 */
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, /* NR40 (unused), NR41-NR44 */
+    0x00, 0x00, 0x70, /* NR50-NR52 */
+];
+
+/* SplitMix64, good enough for garbage RAM and stable across platforms */
+fn fill_random(memory: &mut [u8], state: &mut u64) {
+    for chunk in memory.chunks_mut(8) {
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut value = *state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        value ^= value >> 31;
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// The byte the boot ROM checks 0x014D against, from 0x0134-0x014C
+fn header_checksum(bank: &[u8; 0x4000]) -> u8 {
+    bank[0x134..=0x14C]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+/// The sum of the first `size` bytes of the ROM except the two at
+/// 0x014E-0x014F that hold it
+fn global_checksum(rom: &[[u8; 0x4000]], size: usize) -> u16 {
+    rom.iter()
+        .flatten()
+        .take(size)
+        .enumerate()
+        .filter(|(index, _)| !matches!(index, 0x14E | 0x14F))
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
 impl Mmu {
     pub fn new() -> Self {
         Self {
-            bootstrap: *include_bytes!("../roms/bootstrap.gb"),
+            bootstrap: None,
             rom: Vec::new(),
             vram: [0; 0x2000],
             ram: Vec::new(),
@@ -92,154 +373,687 @@ impl Mmu {
 
             window_counter: 0,
             timer: 0,
-            joypad: Joypad {
-                a: false,
-                b: false,
-                start: false,
-                select: false,
-                up: false,
-                down: false,
-                left: false,
-                right: false,
-            },
-            bank0: 0,
-            bank1: 1,
-            eram: None,
-            mapper: None,
+            tima_overflow: false,
+            tima_reloaded: false,
+            dma: None,
+            joypad: Joypad::default(),
+            serial_transfer: false,
+            serial_sink: None,
+            stat_line: false,
+            memory_changed: false,
+            track_memory: false,
+            vram_dirty: true,
+            boot_handoff: false,
+            audio_writes: Vec::new(),
+            wave_byte: None,
+            banks: Banks::new(),
+            mapper: Box::new(RomOnly),
+            colors: ColorPalette::default(),
+            strict_header: false,
+            vram_log: None,
+            unhandled_io: None,
+            seed: None,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+        }
+    }
+
+    /// Fills WRAM and HRAM with a pseudo-random pattern like the uninitialized
+    /// RAM of real hardware. The same seed always gives the same contents.
+    pub fn new_with_seed(seed: u64) -> Self {
+        let mut mmu = Self::new();
+        mmu.seed = Some(seed);
+        mmu.power_on_ram();
+        mmu
+    }
+
+    fn power_on_ram(&mut self) {
+        match self.seed {
+            Some(seed) => {
+                let mut state = seed;
+                fill_random(&mut self.wram1, &mut state);
+                fill_random(&mut self.wram2, &mut state);
+                fill_random(&mut self.hram, &mut state);
+            }
+            None => {
+                self.wram1.fill(0);
+                self.wram2.fill(0);
+                self.hram.fill(0);
+            }
+        }
+    }
+
+    /// Removes the cartridge, keeping the ROM and RAM allocations
+    pub fn eject(&mut self) {
+        self.rom.clear();
+        self.ram.clear();
+        self.banks = Banks::new();
+        self.mapper = Box::new(RomOnly);
+    }
+
+    /// Returns to the power-on state with the boot ROM mapped again. The
+    /// cartridge and its RAM stay, but the mapper starts over from bank 1.
+    pub fn reset(&mut self) {
+        self.banks = Banks::new();
+        self.mapper = Box::new(RomOnly);
+        if !self.rom.is_empty() {
+            self.insert_mapper()
+                .expect("cartridge header is checked by load_game");
         }
+        self.vram.fill(0);
+        self.oam.fill(0);
+        self.io.fill(0);
+        self.ie = 0;
+        self.power_on_ram();
+
+        self.window_counter = 0;
+        self.timer = 0;
+        self.tima_overflow = false;
+        self.tima_reloaded = false;
+        self.dma = None;
+        self.joypad = Joypad::default();
+        self.serial_transfer = false;
+        self.stat_line = false;
+        self.memory_changed = false;
+        self.vram_dirty = true;
+        self.boot_handoff = false;
+        self.audio_writes.clear();
+        self.wave_byte = None;
+        if let Some(log) = self.vram_log.as_mut() {
+            log.clear();
+        }
+        if let Some(seen) = self.unhandled_io.as_mut() {
+            seen.clear();
+        }
+    }
+
+    /// Maps `bootstrap` at 0x0000-0x00FF from the next reset, `None` to start
+    /// at the cartridge entry point instead
+    pub fn set_boot_rom(&mut self, bootstrap: Option<[u8; 0x100]>) {
+        self.bootstrap = bootstrap;
+    }
+
+    pub fn has_boot_rom(&self) -> bool {
+        self.bootstrap.is_some()
+    }
+
+    /// Raw copy of the IO registers at 0xFF00-0xFF7F
+    pub fn io_snapshot(&self) -> [u8; 0x80] {
+        self.io
+    }
+
+    /// Puts back a block taken with `io_snapshot` without the side effects of
+    /// writing each register (no DMA, DIV reset or interrupts)
+    pub fn restore_io(&mut self, io: &[u8; 0x80]) {
+        self.io = *io;
+        /* Keep the invariants write_byte maintains */
+        self.io[0x00] &= 0b0011_0000;
+        self.io[0x0F] |= 0b1110_0000;
+        self.stat_line = self.stat_sources();
+    }
+
+    /// Seed of the power-on RAM pattern, `None` when RAM starts zeroed
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Puts the IO registers in the state the boot ROM leaves them in and
+    /// unmaps the boot ROM, for starting directly at the cartridge entry point
+    pub fn skip_boot(&mut self) {
+        for (address, value) in POST_BOOT_IO {
+            self.io[address as usize - 0xFF00] = value;
+        }
+        /* NRx4 is left out so its trigger bit doesn't restart the boot chime */
+        self.queue_sound_registers();
+        /* DIV reads 0xAB, the rest of the internal counter is what the boot ROM leaves */
+        self.timer = 0xABCC;
     }
 
-    pub fn load_game(&mut self, game: impl Read) {
+    pub fn load_game(&mut self, game: impl Read) -> Result<(), CartridgeError> {
+        let mut size = 0;
         for (index, byte) in BufReader::new(game).bytes().enumerate() {
             if self.rom.len() <= index / 0x4000 {
                 self.rom.push([0; 0x4000]);
             }
             self.rom.last_mut().unwrap()[index % 0x4000] = byte.unwrap();
+            size += 1;
+        }
+        /* Bank 0 holds the header and must be complete */
+        if size < 0x4000 {
+            self.rom.clear();
+            return Err(CartridgeError::TooSmall(size));
+        }
+        let computed = header_checksum(&self.rom[0]);
+        let stored = self.rom[0][0x14D];
+        if computed != stored {
+            if self.strict_header {
+                self.rom.clear();
+                return Err(CartridgeError::HeaderChecksum { stored, computed });
+            }
+            eprintln!("{}", CartridgeError::HeaderChecksum { stored, computed });
+        }
+        /* Nothing checks the global checksum on hardware, but a wrong one
+         * still points to a corrupt or overdumped file */
+        let computed = global_checksum(&self.rom, size);
+        let stored = u16::from_be_bytes([self.rom[0][0x14E], self.rom[0][0x14F]]);
+        if computed != stored {
+            eprintln!(
+                "Global checksum at 0x014E is {:#06x} but the ROM sums to {:#06x}",
+                stored, computed
+            );
+        }
+        let (rom_size, ram_size) = self.insert_mapper()?;
+
+        if rom_size != self.rom.len() {
+            eprintln!(
+                "ROM Size ({}) does not match actual size ({})",
+                rom_size,
+                self.rom.len()
+            );
+        }
+        /* Pad to a power of two so bank numbers wrap like on a real ROM chip */
+        let banks = self.rom.len().next_power_of_two().max(2);
+        self.rom.resize(banks, [0xFF; 0x4000]);
+        self.ram = vec![[0; 0x2000]; ram_size as usize];
+        Ok(())
+    }
+
+    /// Loads a different cartridge in place of the current one, which is
+    /// kept, RAM and all, if `game` can't be loaded
+    pub fn swap_game(&mut self, game: impl Read) -> Result<(), CartridgeError> {
+        let rom = std::mem::take(&mut self.rom);
+        let ram = std::mem::take(&mut self.ram);
+        let banks = std::mem::take(&mut self.banks);
+        let mapper = std::mem::replace(&mut self.mapper, Box::new(RomOnly));
+        if let Err(err) = self.load_game(game) {
+            self.rom = rom;
+            self.ram = ram;
+            self.banks = banks;
+            self.mapper = mapper;
+            return Err(err);
         }
-        let rom_size = 2 << self.rom[0][0x148];
+        Ok(())
+    }
+
+    /// Whether a CGB speed switch was armed by setting bit 0 of KEY1
+    /// (0xFF4D), disarming it. STOP performs the switch on CGB.
+    pub fn take_speed_switch(&mut self) -> bool {
+        let armed = self.io[0x4D] & 1 != 0;
+        self.io[0x4D] &= !1;
+        armed
+    }
+
+    /// Make `load_game` refuse cartridges with a wrong header checksum,
+    /// which the boot ROM locks up on, rather than warn and run them
+    pub fn set_strict_header(&mut self, strict: bool) {
+        self.strict_header = strict;
+    }
+
+    /// Checks the cartridge header and sets up its mapper in the power-on
+    /// state, returning the number of ROM and RAM banks
+    fn insert_mapper(&mut self) -> Result<(usize, u8), CartridgeError> {
+        /* 32KiB << n, i.e. 2 << n banks of 16KiB */
+        let rom_size = match self.rom[0][0x148] {
+            n @ 0x00..=0x08 => 2usize << n,
+            n => return Err(CartridgeError::InvalidRomSize(n)),
+        };
+        /* Number of 8KiB banks */
         let ram_size = match self.rom[0][0x149] {
+            0x00 => 0,
+            0x01 => 0, /* Unused, listed as 2KiB in some docs but never used on carts */
             0x02 => 1,
             0x03 => 4,
             0x04 => 16,
             0x05 => 8,
-            _ => 0,
+            n => return Err(CartridgeError::InvalidRamSize(n)),
         };
         self.mapper = match self.rom[0][0x147] {
-            0x00 => None,
-            0x01..=0x03 => Some(Box::new(Mbc1::new(rom_size, ram_size, self))),
-            _ => panic!("Unsupported mapper"),
+            0x00 => Box::new(RomOnly),
+            0x01..=0x03 if rom_size <= 128 => {
+                Box::new(Mbc1::new(rom_size as u8, ram_size, &mut self.banks))
+            }
+            0x01..=0x03 => return Err(CartridgeError::InvalidRomSize(self.rom[0][0x148])),
+            n @ 0x0F..=0x13 => Box::new(Mbc3::new(
+                ram_size,
+                matches!(n, 0x0F | 0x10),
+                &mut self.banks,
+            )),
+            0x19..=0x1E => Box::new(Mbc5::new(ram_size, &mut self.banks)),
+            n => return Err(CartridgeError::UnsupportedMapper(n)),
         };
+        Ok((rom_size, ram_size))
+    }
 
-        if rom_size != self.rom.len() as u8 {
-            eprintln!(
-                "ROM Size ({}) does not match actual size ({})",
-                rom_size,
-                self.rom.len()
-            );
+    /// The external RAM byte behind an address in 0xA000-0xBFFF, `None` when
+    /// nothing drives the bus: RAM disabled, absent, or a bank the
+    /// cartridge doesn't have. Reads then see open bus (0xFF) and writes are
+    /// dropped.
+    fn eram_cell(&self, address: u16) -> Option<&u8> {
+        let bank = self.ram.get(self.banks.eram?)?;
+        bank.get(address as usize - 0xA000)
+    }
+
+    fn eram_cell_mut(&mut self, address: u16) -> Option<&mut u8> {
+        let bank = self.ram.get_mut(self.banks.eram?)?;
+        bank.get_mut(address as usize - 0xA000)
+    }
+
+    /// Fills the start of an external RAM bank, bypassing the mapper's RAM
+    /// enable, e.g. to start from a known save
+    pub fn set_ram(&mut self, bank: usize, data: &[u8]) -> Result<(), CartridgeError> {
+        match self.ram.get_mut(bank) {
+            Some(ram) if data.len() <= ram.len() => {
+                ram[..data.len()].copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(CartridgeError::RamOutOfRange {
+                bank,
+                len: data.len(),
+            }),
         }
-        self.ram = vec![[0; 0x2000]; ram_size as usize];
     }
 
+    /// Whether the cartridge keeps its RAM powered with a battery, so it
+    /// should be saved between sessions
+    pub fn has_battery(&self) -> bool {
+        /* MBC1+RAM+BATTERY, MBC3+TIMER+BATTERY, MBC3+TIMER+RAM+BATTERY,
+         * MBC3+RAM+BATTERY, MBC5+RAM+BATTERY and MBC5+RUMBLE+RAM+BATTERY */
+        matches!(
+            self.rom.first().map(|bank| bank[0x147]),
+            Some(0x03 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+        )
+    }
+
+    /// Replaces the external RAM with a save written by `save_sram`. The file
+    /// must be exactly the size of the RAM, plus the clock footer if it has
+    /// one, otherwise it's left untouched. The clock catches up on the time
+    /// since it was saved.
+    pub fn load_sram(&mut self, path: &Path) -> Result<(), SaveError> {
+        let save = std::fs::read(path)?;
+        self.restore_sram(&save, unix_time())
+    }
+
+    fn restore_sram(&mut self, save: &[u8], now: u64) -> Result<(), SaveError> {
+        let expected = self.ram.len() * 0x2000;
+        let wrong_size = SaveError::WrongSize {
+            expected,
+            actual: save.len(),
+        };
+        let (ram, footer) = match save.len().checked_sub(expected) {
+            Some(0) => (save, None),
+            Some(RTC_FOOTER_LEN) => (&save[..expected], Some(&save[expected..])),
+            _ => return Err(wrong_size),
+        };
+        if let Some(footer) = footer {
+            if !self.mapper.load_rtc_footer(footer.try_into().unwrap(), now) {
+                return Err(wrong_size);
+            }
+        }
+        for (bank, data) in self.ram.iter_mut().zip(ram.chunks_exact(0x2000)) {
+            bank.copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    /// Writes the external RAM banks back to back, then the clock if the
+    /// cartridge has one
+    pub fn save_sram(&self, path: &Path) -> Result<(), SaveError> {
+        std::fs::write(path, self.sram(unix_time()))?;
+        Ok(())
+    }
+
+    fn sram(&self, now: u64) -> Vec<u8> {
+        let mut save = self.ram.concat();
+        if let Some(footer) = self.mapper.rtc_footer(now) {
+            save.extend_from_slice(&footer);
+        }
+        save
+    }
+
+    pub fn get_ram(&self, bank: usize) -> Option<&[u8; 0x2000]> {
+        self.ram.get(bank)
+    }
+
+    /// Title from the cartridge header, up to the first NUL byte. On CGB-aware
+    /// carts 0x143 holds the CGB flag and is not part of the title, and an 11
+    /// byte title may be followed by a 4 character manufacturer code.
+    pub fn game_title(&self) -> String {
+        let Some(bank) = self.rom.first() else {
+            return String::new();
+        };
+        let full = !bank[0x134..0x13F].contains(&0);
+        let code = bank[0x13F..0x143]
+            .iter()
+            .all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit());
+        let end = match (bank[0x143] & 0x80, full && code) {
+            (0, _) => 0x144,
+            (_, true) => 0x13F,
+            (_, false) => 0x143,
+        };
+        bank[0x134..end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| match byte {
+                0x20..=0x7E => byte as char,
+                _ => '?',
+            })
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    /// A byte of a ROM bank, with bank numbers past the end of the ROM
+    /// wrapping around the way the unconnected address lines mirror it.
+    /// Reads open bus with no cartridge.
+    #[inline]
+    fn read_rom(&self, bank: usize, offset: usize) -> u8 {
+        match self.rom.len() {
+            0 => 0xFF,
+            len => self.rom[bank % len][offset],
+        }
+    }
+
+    /// Reads as the CPU does, VRAM and OAM read 0xFF while the PPU is using
+    /// them
     #[inline]
     pub fn read_byte(&self, address: u16) -> u8 {
+        let value = match (self.dma_blocks(address), self.ppu_blocks(address)) {
+            /* The CPU sees whatever the DMA put on the bus */
+            (Some(dma), _) => dma,
+            (None, true) => 0xFF,
+            (None, false) => self.read_raw(address),
+        };
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, false);
+        }
+        value
+    }
+
+    /// Reads memory as it is, ignoring the PPU lock and watchpoints, for
+    /// debuggers and the emulator's own reads
+    pub fn peek(&self, address: u16) -> u8 {
+        self.read_raw(address)
+    }
+
+    /// Whether the PPU has `address` locked: VRAM while drawing (mode 3), OAM
+    /// during the OAM scan too (mode 2). Nothing is locked with the LCD off.
+    fn ppu_blocks(&self, address: u16) -> bool {
+        if self.io[0x40] & 0b1000_0000 == 0 {
+            return false;
+        }
+        match address {
+            0x8000..=0x9FFF => self.get_ppu_mode() == 3,
+            0xFE00..=0xFE9F => self.get_ppu_mode() >= 2,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn read_raw(&self, address: u16) -> u8 {
         let address = address as usize;
         match address as u16 {
             0x0000..=0x00FF => {
-                if self.io[0x50] == 0x00 {
-                    return self.bootstrap[address];
+                if let (0x00, Some(bootstrap)) = (self.io[0x50], &self.bootstrap) {
+                    return bootstrap[address];
                 }
-                self.rom[self.bank0][address]
+                self.read_rom(self.banks.bank0, address)
             }
-            0x0100..=0x3FFF => self.rom[self.bank0][address],
-            0x4000..=0x7FFF => self.rom[self.bank1][address - 0x4000],
+            0x0100..=0x3FFF => self.read_rom(self.banks.bank0, address),
+            0x4000..=0x7FFF => self.read_rom(self.banks.bank1, address - 0x4000),
             0x8000..=0x9FFF => self.vram[address - 0x8000],
-            0xA000..=0xBFFF => match self.eram {
-                Some(bank) => self.ram[bank][address - 0xA000],
-                None => 0xFF,
+            0xA000..=0xBFFF => match self.mapper.read_eram(address as u16) {
+                Some(value) => value,
+                None => self.eram_cell(address as u16).copied().unwrap_or(0xFF),
             },
             0xC000..=0xCFFF => self.wram1[address - 0xC000],
             0xD000..=0xDFFF => self.wram2[address - 0xD000],
-            0xE000..=0xFDFF => 0xFF,
+            /* Echo RAM mirrors 0xC000-0xDDFF */
+            0xE000..=0xEFFF => self.wram1[address - 0xE000],
+            0xF000..=0xFDFF => self.wram2[address - 0xF000],
             0xFE00..=0xFE9F => self.oam[address - 0xFE00],
             0xFEA0..=0xFEFF => 0xFF,
-            0xFF00..=0xFF7F => self.io[address - 0xFF00],
+            0xFF00..=0xFF7F => self.read_io(address),
             0xFF80..=0xFFFE => self.hram[address - 0xFF80],
             0xFFFF => self.ie,
         }
     }
 
+    #[inline]
+    fn read_io(&self, address: usize) -> u8 {
+        match address {
+            0xFF00 => self.read_joypad(),
+            /* DIV is the upper byte of the timer's counter */
+            0xFF04 => (self.timer >> 8) as u8,
+            /* STAT bit 7 is unused and always reads 1 */
+            0xFF41 => self.io[0x41] | 0b1000_0000,
+            0xFF10..=0xFF26 => self.io[address - 0xFF00] | APU_READ_MASK[address - 0xFF10],
+            /* While channel 3 plays, all of wave RAM reads as the byte it's on */
+            0xFF30..=0xFF3F => match self.wave_byte {
+                Some(byte) => self.io[0x30 + byte as usize],
+                None => self.io[address - 0xFF00],
+            },
+            _ => self.io[address - 0xFF00],
+        }
+    }
+
     pub fn read_word(&self, address: u16) -> u16 {
         let low = self.read_byte(address) as u16;
-        let high = self.read_byte(address + 1) as u16;
+        let high = self.read_byte(address.wrapping_add(1)) as u16;
         (high << 8) | low
     }
 
+    /// Writes as the CPU does, ignored for VRAM and OAM while the PPU is
+    /// using them
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, true);
+        }
+        if self.dma_blocks(address).is_none() && !self.ppu_blocks(address) {
+            self.write_raw(address, value);
+        }
+    }
+
+    /// What the CPU reads at `address` instead while an OAM DMA holds its
+    /// bus: the byte being copied on the same bus, 0xFF from OAM. IO and
+    /// HRAM are always free.
+    fn dma_blocks(&self, address: u16) -> Option<u8> {
+        let dma = self.dma.filter(|dma| !dma.starting)?;
+        let vram_bus = |address| matches!(address, 0x8000..=0x9FFF);
+        match address {
+            0xFE00..=0xFEFF => Some(0xFF),
+            0xFF00..=0xFFFF => None,
+            _ if vram_bus(address) == vram_bus(dma.source) => {
+                Some(self.read_raw(dma.source + dma.copied))
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs `cycles` M-cycles of an OAM DMA transfer. The first call after
+    /// 0xFF46 is written covers the instruction that wrote it, the copy
+    /// starts after that and takes 160 M-cycles.
+    pub fn step_dma(&mut self, cycles: u32) {
+        let Some(mut dma) = self.dma else {
+            return;
+        };
+        if std::mem::take(&mut dma.starting) {
+            self.dma = Some(dma);
+            return;
+        }
+        for _ in 0..cycles {
+            let value = self.read_raw(dma.source + dma.copied);
+            self.write_raw(0xFE00 + dma.copied, value);
+            dma.copied += 1;
+            if dma.copied == 0xA0 {
+                self.dma = None;
+                return;
+            }
+        }
+        self.dma = Some(dma);
+    }
+
+    fn write_raw(&mut self, address: u16, value: u8) {
+        if self.track_memory
+            && !matches!(address, 0xFF00..=0xFF7F | 0xFFFF)
+            && self.read_raw(address) != value
+        {
+            self.memory_changed = true;
+        }
+        if matches!(
+            address,
+            0x8000..=0x9FFF /* VRAM */
+            | 0xFE00..=0xFE9F /* OAM */
+            | 0xFF40 /* LCDC */
+            | 0xFF42..=0xFF43 /* SCY, SCX */
+            | 0xFF47..=0xFF4B /* BGP, OBP0, OBP1, WY, WX */
+        ) && self.read_raw(address) != value
+        {
+            self.vram_dirty = true;
+        }
+        if let (0xFF00..=0xFF7F, Some(seen)) = (address, self.unhandled_io.as_mut()) {
+            if !io_implemented(address) && !seen.contains(&address) {
+                seen.push(address);
+                eprintln!("Unhandled IO write: [{:#06x}] = {:#04x}", address, value);
+            }
+        }
         if address == 0xFF00 {
-            self.io[0x00] = value;
-            self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-                0b00 => {
-                    ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                        | self.joypad.read_state(false)
+            /* Only the selection bits are writable */
+            self.io[0x00] = value & 0b0011_0000;
+            return;
+        }
+        if let 0xFF10..=0xFF26 = address {
+            if address != 0xFF26 && self.io[0x26] & 0b1000_0000 == 0 {
+                /* Sound registers are read-only while the APU is off */
+                return;
+            }
+            self.audio_writes.push((address, value));
+        }
+        if let (0xFF30..=0xFF3F, Some(byte)) = (address, self.wave_byte) {
+            /* Writes also go to the byte channel 3 is on */
+            self.io[0x30 + byte as usize] = value;
+            return;
+        }
+        if address == 0xFF26 {
+            /* Only power is writable, bits 0-3 are the channel status.
+             * Turning the APU off stops every channel and clears the other
+             * sound registers. */
+            self.io[0x26] = match value & 0b1000_0000 {
+                0 => {
+                    self.io[0x10..0x26].fill(0);
+                    0
                 }
-                0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-                0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-                0b11 => self.io[0x00] | 0b0000_1111,
-                _ => unreachable!(),
+                power => power | self.io[0x26] & 0b0000_1111,
             };
             return;
         }
         if address == 0xFF04 {
-            let tac_bit = match self.io[0x07] & 0b11 {
-                0b00 => 9,
-                0b01 => 3,
-                0b10 => 5,
-                0b11 => 7,
-                _ => unreachable!(),
-            };
-            if (self.timer >> tac_bit) & 1 == 1 {
-                self.io[0x05] = self.io[0x05].wrapping_add(1);
-                if self.io[0x05] == 0 {
-                    self.io[0x05] = self.io[0x06];
-                    self.io[0x0F] |= 0b0000_0010;
+            /* Resetting the counter can drop the selected bit, which TIMA sees as a tick */
+            let old_signal = self.timer_signal();
+            self.timer = 0;
+            if old_signal {
+                self.tick_tima();
+            }
+        }
+        if address == 0xFF07 {
+            /* Switching frequency or disabling can also drop the signal */
+            let old_signal = self.timer_signal();
+            self.io[0x07] = value;
+            if old_signal && !self.timer_signal() {
+                self.tick_tima();
+            }
+            return;
+        }
+        if address == 0xFF05 {
+            /* Writing TIMA in the M-cycle after it overflowed cancels the
+             * reload and the interrupt, in the cycle it is reloaded the write
+             * is lost */
+            if self.tima_reloaded {
+                return;
+            }
+            self.tima_overflow = false;
+        }
+        if address == 0xFF06 && self.tima_reloaded {
+            /* TIMA is loaded from TMA throughout the reload cycle, so it gets
+             * the new value too */
+            self.io[0x05] = value;
+        }
+        if address == 0xFF02 {
+            /* Transfer start with the internal clock, completed by the link partner */
+            self.io[0x02] = value;
+            self.serial_transfer = value & 0b1000_0001 == 0b1000_0001;
+            if self.serial_transfer && self.serial_sink.is_some() {
+                /* Nothing connected, the line reads high */
+                let byte = self.serial_exchange(0xFF);
+                if let Some(sink) = self.serial_sink.as_mut() {
+                    let _ = sink.write_all(&[byte]);
+                    let _ = sink.flush();
                 }
             }
-            self.timer = 0;
+            return;
         }
         if address == 0xFF0F {
             /* Upper bits of IF are always 1 */
             self.io[0x0F] = value | 0b1110_0000;
             return;
         }
+        if address == 0xFF41 {
+            /* Mode and LYC coincidence bits are read-only */
+            self.io[0x41] = (value & 0b0111_1000) | (self.io[0x41] & 0b0000_0111);
+            self.update_stat_line();
+            return;
+        }
+        if address == 0xFF44 {
+            /* LY is read-only */
+            return;
+        }
+        if address == 0xFF45 {
+            self.io[0x45] = value;
+            self.set_lyc_coincidence(self.io[0x44] == value);
+            return;
+        }
         if address == 0xFF46 {
-            /* DMA Transfer */
-            let start = (value as u16) << 8;
-            for i in 0..0xA0 {
-                self.write_byte(0xFE00 + i, self.read_byte(start + i));
-            }
+            /* DMA Transfer, sources past 0xDFFF read work RAM like echo RAM
+             * does. It isn't locked out of OAM like the CPU. */
+            let source = match value {
+                0xE0..=0xFF => value - 0x20,
+                _ => value,
+            };
+            self.dma = Some(Dma {
+                source: (source as u16) << 8,
+                copied: 0,
+                starting: true,
+            });
         }
         if address == 0xFF50 {
             /* Read-Only after initialization */
+            if self.io[0x50] == 0x00 {
+                self.boot_handoff = true;
+            }
             self.io[0x50] = 0xFF;
             return;
         }
         match address {
-            0x0000..=0x7FFF => {
-                if let Some(mut mapper) = self.mapper.take() {
-                    mapper.write_register(address, value, self);
-                    self.mapper = Some(mapper);
-                }
+            0x0000..=0x7FFF => self.mapper.write_register(address, value, &mut self.banks),
+            0x8000..=0x9FFF => {
+                self.log_vram_write(address, value);
+                self.vram[address as usize - 0x8000] = value;
             }
-            0x8000..=0x9FFF => self.vram[address as usize - 0x8000] = value,
             0xA000..=0xBFFF => {
-                if let Some(bank) = self.eram {
-                    self.ram[bank][address as usize - 0xA000] = value;
+                if self.mapper.write_eram(address, value) {
+                    return;
+                }
+                if let Some(cell) = self.eram_cell_mut(address) {
+                    *cell = value;
                 }
             }
             0xC000..=0xCFFF => self.wram1[address as usize - 0xC000] = value,
             0xD000..=0xDFFF => self.wram2[address as usize - 0xD000] = value,
-            0xE000..=0xFDFF => {}
-            0xFE00..=0xFE9F => self.oam[address as usize - 0xFE00] = value,
+            0xE000..=0xEFFF => self.wram1[address as usize - 0xE000] = value,
+            0xF000..=0xFDFF => self.wram2[address as usize - 0xF000] = value,
+            0xFE00..=0xFE9F => {
+                self.log_vram_write(address, value);
+                self.oam[address as usize - 0xFE00] = value;
+            }
             0xFEA0..=0xFEFF => {}
             0xFF00..=0xFF7F => self.io[address as usize - 0xFF00] = value,
             0xFF80..=0xFFFE => self.hram[address as usize - 0xFF80] = value,
@@ -247,96 +1061,399 @@ impl Mmu {
         }
     }
 
-    pub fn write_word(&mut self, address: u16, value: u16) {
-        let low = value as u8;
-        let high = (value >> 8) as u8;
-        self.write_byte(address, low);
-        self.write_byte(address + 1, high);
+    #[inline]
+    fn log_vram_write(&mut self, address: u16, value: u8) {
+        if let Some(log) = &mut self.vram_log {
+            log.push(VramWrite {
+                address,
+                value,
+                line: self.io[0x44],
+            });
+        }
+    }
+
+    /// Start or stop recording writes to VRAM and OAM
+    pub fn set_vram_log(&mut self, enable: bool) {
+        self.vram_log = enable.then(Vec::new);
+    }
+
+    pub fn vram_log_enabled(&self) -> bool {
+        self.vram_log.is_some()
+    }
+
+    /// Returns the writes recorded since the last call, leaving logging enabled
+    pub fn take_vram_log(&mut self) -> Vec<VramWrite> {
+        self.vram_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// One line of the background on its own, at the current SCX and SCY.
+    /// Drawn even when LCDC bit 0 turns the background off.
+    pub fn render_background_layer(&self, line: u8) -> [u8; 160 * 4] {
+        let mut output = [0; 160 * 4];
+        draw_background(self, line, self.io[0x43], self.io[0x42], &mut output);
+        output
+    }
+
+    /// One line of the window on its own, using the current window line
+    /// counter. Pixels the window doesn't cover are left as zero.
+    pub fn render_window_layer(&self, line: u8) -> [u8; 160 * 4] {
+        let mut output = [0; 160 * 4];
+        draw_window(self, line, &mut output);
+        output
+    }
+
+    /// One line of objects on its own. Pixels no object covers are left as
+    /// zero, and objects behind the background have an alpha of 128.
+    pub fn render_sprite_layer(&self, line: u8) -> [u8; 160 * 4] {
+        let mut output = [0; 160 * 4];
+        draw_sprites(self, line, &mut output);
+        output
+    }
+
+    /// Catches `kind` accesses to `address`, replacing any watchpoint
+    /// already there
+    pub fn set_watchpoint(&mut self, address: u16, kind: Watch) {
+        self.remove_watchpoint(address);
+        self.watchpoints.push((address, kind));
+    }
+
+    /// Returns whether there was a watchpoint at `address`
+    pub fn remove_watchpoint(&mut self, address: u16) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&(watched, _)| watched != address);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[(u16, Watch)] {
+        &self.watchpoints
+    }
+
+    /// The first watched access since the last call
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watchpoints(&self, address: u16, value: u8, write: bool) {
+        let caught = self.watchpoints.iter().any(|&(watched, kind)| {
+            watched == address
+                && match kind {
+                    Watch::Read => !write,
+                    Watch::Write => write,
+                    Watch::Access => true,
+                }
+        });
+        if caught && self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(WatchHit {
+                address,
+                value,
+                write,
+            }));
+        }
+    }
+
+    /// Start or stop reporting the first write to each IO register the
+    /// emulator doesn't implement, such as the audio registers
+    pub fn set_unhandled_io_log(&mut self, enable: bool) {
+        self.unhandled_io = enable.then(Vec::new);
+    }
+
+    /// Unimplemented IO registers written since logging started, in the
+    /// order they were first written
+    pub fn unhandled_io_writes(&self) -> &[u16] {
+        self.unhandled_io.as_deref().unwrap_or_default()
+    }
+
+    pub fn serial_transfer_pending(&self) -> bool {
+        self.serial_transfer
+    }
+
+    pub fn request_interrupt(&mut self, kind: Interrupt) {
+        self.io[0x0F] |= kind.mask();
+    }
+
+    pub fn clear_interrupt(&mut self, kind: Interrupt) {
+        self.io[0x0F] &= !kind.mask();
+    }
+
+    /// Interrupts both requested in IF and enabled in IE
+    pub fn pending_interrupts(&self) -> u8 {
+        self.io[0x0F] & self.ie & 0b0001_1111
+    }
+
+    /// Whether the boot ROM unmapped itself by writing 0xFF50 since the last
+    /// call. Not set by `skip_boot`.
+    pub fn take_boot_handoff(&mut self) -> bool {
+        std::mem::take(&mut self.boot_handoff)
+    }
+
+    /// Queues the sound registers as they are for the APU, which keeps its
+    /// own copy. Power comes first, and NRx4 is left out so no channel is
+    /// triggered.
+    pub fn queue_sound_registers(&mut self) {
+        self.audio_writes.push((0xFF26, self.io[0x26]));
+        for address in 0xFF10..=0xFF25 {
+            if !matches!(address, 0xFF14 | 0xFF19 | 0xFF1E | 0xFF23) {
+                self.audio_writes
+                    .push((address, self.io[address as usize - 0xFF00]));
+            }
+        }
+    }
+
+    /// Sound register writes (0xFF10-0xFF26) since the last call, in order
+    pub fn take_audio_writes(&mut self) -> Vec<(u16, u8)> {
+        std::mem::take(&mut self.audio_writes)
+    }
+
+    /// Sets NR52 bits 0-3 to the channels playing
+    pub fn set_audio_status(&mut self, channels: u8) {
+        self.io[0x26] = self.io[0x26] & 0b1111_0000 | channels & 0b0000_1111;
+    }
+
+    /// Which wave RAM byte channel 3 is playing, `None` when it isn't. Wave
+    /// RAM accesses go to that byte instead while it plays.
+    pub fn set_wave_byte(&mut self, byte: Option<u8>) {
+        self.wave_byte = byte;
+    }
+
+    /// The 32 4-bit samples channel 3 plays, 0xFF30-0xFF3F
+    pub fn wave_ram(&self) -> &[u8] {
+        &self.io[0x30..0x40]
+    }
+
+    /// The 16-bit counter DIV is the upper half of
+    pub fn div_counter(&self) -> u16 {
+        self.timer
     }
 
-    pub fn get_bg_enable(&self) -> bool {
-        self.io[0x40] & 0b0000_0001 == 0b0000_0001
+    /// Whether VRAM, OAM or a register the PPU draws with changed since the
+    /// last `take_vram_dirty`
+    pub fn vram_dirty(&self) -> bool {
+        self.vram_dirty
     }
 
-    pub fn get_window_enable(&self) -> bool {
-        self.io[0x40] & 0b0010_0000 == 0b0010_0000
+    pub fn take_vram_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.vram_dirty)
     }
 
-    pub fn get_obj_enable(&self) -> bool {
-        self.io[0x40] & 0b0000_0010 == 0b0000_0010
+    /// Start or stop noting writes for `take_memory_changed`, which costs a
+    /// read on every write
+    pub fn set_memory_tracking(&mut self, enable: bool) {
+        self.track_memory = enable;
+        self.memory_changed = false;
     }
 
-    pub fn get_bg_map_mode(&self) -> bool {
-        self.io[0x40] & 0b0000_1000 == 0b0000_1000
+    /// Whether anything outside the IO registers was written with a new
+    /// value since the last call, with `set_memory_tracking` on
+    pub fn take_memory_changed(&mut self) -> bool {
+        std::mem::take(&mut self.memory_changed)
     }
 
-    pub fn get_window_map_mode(&self) -> bool {
-        self.io[0x40] & 0b0100_0000 == 0b0100_0000
+    /// Prints every byte sent with the internal clock to stdout and completes
+    /// the transfer immediately, as test ROMs expect with no link partner
+    pub fn set_serial_stdout(&mut self, enable: bool) {
+        self.serial_sink = match enable {
+            true => Some(Box::new(std::io::stdout())),
+            false => None,
+        };
     }
 
-    pub fn get_tile_mode(&self) -> bool {
-        self.io[0x40] & 0b0001_0000 == 0b0001_0000
+    /// Like `set_serial_stdout`, but writes the bytes to `sink`, e.g. to
+    /// check a test ROM's output
+    pub fn set_serial_sink(&mut self, sink: impl Write + 'static) {
+        self.serial_sink = Some(Box::new(sink));
     }
 
-    pub fn get_bg_tile_data(&self) -> &[u8; 0x1000] {
-        if self.get_tile_mode() {
-            self.vram[0..0x1000].try_into().unwrap()
-        } else {
-            self.vram[0x800..0x1800].try_into().unwrap()
+    /// Shifts a byte in from the link partner and returns the byte shifted out.
+    /// Completes a transfer in progress on this side, raising the serial interrupt.
+    pub fn serial_exchange(&mut self, incoming: u8) -> u8 {
+        let outgoing = self.io[0x01];
+        self.io[0x01] = incoming;
+        if self.io[0x02] & 0b1000_0000 != 0 {
+            self.io[0x02] &= 0b0111_1111;
+            self.request_interrupt(Interrupt::Serial);
         }
+        self.serial_transfer = false;
+        outgoing
     }
 
-    pub fn get_bg_tile_map(&self) -> &[u8; 0x400] {
-        if self.get_bg_map_mode() {
-            self.vram[0x1C00..0x2000].try_into().unwrap()
-        } else {
-            self.vram[0x1800..0x1C00].try_into().unwrap()
+    /// Appends memory, registers and the mapper state to a save state,
+    /// identifying the game by its header checksums
+    pub fn save_state(&self, state: &mut StateWriter) {
+        match self.rom.first() {
+            Some(bank) => state.bytes(&bank[0x14D..0x150]),
+            None => state.bytes(&[0; 3]),
         }
+        state.u16(self.ram.len() as u16);
+        for bank in &self.ram {
+            state.bytes(bank);
+        }
+        state.bytes(&self.vram);
+        state.bytes(&self.wram1);
+        state.bytes(&self.wram2);
+        state.bytes(&self.oam);
+        state.bytes(&self.io);
+        state.bytes(&self.hram);
+        state.u8(self.ie);
+        state.u8(self.window_counter);
+        state.u16(self.timer);
+        state.bool(self.tima_overflow);
+        state.bool(self.tima_reloaded);
+        state.bool(self.dma.is_some());
+        if let Some(dma) = self.dma {
+            state.u16(dma.source);
+            state.u16(dma.copied);
+            state.bool(dma.starting);
+        }
+        state.bool(self.serial_transfer);
+        state.bool(self.stat_line);
+        state.u16(self.banks.bank0 as u16);
+        state.u16(self.banks.bank1 as u16);
+        state.bool(self.banks.eram.is_some());
+        state.u8(self.banks.eram.unwrap_or(0) as u8);
+        self.mapper.save_state(state);
     }
 
-    pub fn get_window_tile_map(&self) -> &[u8; 0x400] {
-        if self.get_window_map_mode() {
-            self.vram[0x1C00..0x2000].try_into().unwrap()
-        } else {
-            self.vram[0x1800..0x1C00].try_into().unwrap()
+    /// Restores what `save_state` wrote. On error some of the state may
+    /// already be replaced.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let checksums = state.bytes(3)?;
+        let game = match self.rom.first() {
+            Some(bank) => &bank[0x14D..0x150],
+            None => &[0; 3],
+        };
+        if checksums != game || state.u16()? as usize != self.ram.len() {
+            return Err(StateError::WrongGame);
+        }
+        for bank in self.ram.iter_mut() {
+            state.read_into(bank)?;
         }
+        state.read_into(&mut self.vram)?;
+        state.read_into(&mut self.wram1)?;
+        state.read_into(&mut self.wram2)?;
+        state.read_into(&mut self.oam)?;
+        state.read_into(&mut self.io)?;
+        state.read_into(&mut self.hram)?;
+        self.ie = state.u8()?;
+        self.window_counter = state.u8()?;
+        self.timer = state.u16()?;
+        self.tima_overflow = state.bool()?;
+        self.tima_reloaded = state.bool()?;
+        self.dma = match state.bool()? {
+            true => {
+                let dma = Dma {
+                    source: state.u16()?,
+                    copied: state.u16()?,
+                    starting: state.bool()?,
+                };
+                /* Sources past 0xDF00 are mapped down when the transfer starts */
+                if dma.source > 0xDF00 || dma.copied >= 0xA0 {
+                    return Err(StateError::Corrupt);
+                }
+                Some(dma)
+            }
+            false => None,
+        };
+        self.serial_transfer = state.bool()?;
+        self.stat_line = state.bool()?;
+        self.banks.bank0 = state.u16()? as usize;
+        self.banks.bank1 = state.u16()? as usize;
+        let eram_mapped = state.bool()?;
+        let eram = state.u8()? as usize;
+        self.banks.eram = eram_mapped.then_some(eram);
+        self.mapper.load_state(state)?;
+
+        self.memory_changed = true;
+        self.vram_dirty = true;
+        self.boot_handoff = false;
+        self.audio_writes.clear();
+        self.wave_byte = None;
+        Ok(())
     }
 
-    pub fn get_window_pos(&self) -> (u8, u8) {
-        (self.io[0x4A], self.io[0x4B])
+    /// Changes the colors shades are drawn in, the next frame is redrawn
+    pub fn set_colors(&mut self, colors: ColorPalette) {
+        self.colors = colors;
+        self.vram_dirty = true;
     }
 
-    pub fn get_oam(&self) -> &[u8; 0xA0] {
-        &self.oam
+    pub fn get_ppu_mode(&self) -> u8 {
+        self.io[0x41] & 0b0000_0011
     }
 
-    pub fn get_oam_tile_data(&self) -> &[u8; 0x1000] {
-        self.vram[0..0x1000].try_into().unwrap()
+    pub fn set_ppu_mode(&mut self, mode: u8) {
+        self.io[0x41] = (self.io[0x41] & !0b0000_0011) | (mode & 0b0000_0011);
+        self.update_stat_line();
     }
 
-    pub fn get_obj_size(&self) -> bool {
-        self.io[0x40] & 0b0000_0100 == 0b0000_0100
+    /// Sets LY to the line the PPU is on and compares it with LYC
+    pub fn set_ly(&mut self, line: u8) {
+        self.io[0x44] = line;
+        self.set_lyc_coincidence(line == self.io[0x45]);
     }
 
-    pub fn get_bg_palette(&self) -> [Palette; 4] {
-        Palette::from_u8(self.io[0x47])
+    pub fn set_lyc_coincidence(&mut self, coincidence: bool) {
+        match coincidence {
+            true => self.io[0x41] |= 0b0000_0100,
+            false => self.io[0x41] &= !0b0000_0100,
+        }
+        self.update_stat_line();
     }
 
-    pub fn get_obj_palette(&self, palette: usize) -> [Palette; 4] {
-        Palette::from_u8(self.io[0x48 + (palette & 0x1)])
+    fn stat_sources(&self) -> bool {
+        let stat = self.io[0x41];
+        let mode = match stat & 0b0000_0011 {
+            0 => stat & 0b0000_1000 != 0,
+            1 => stat & 0b0001_0000 != 0,
+            2 => stat & 0b0010_0000 != 0,
+            _ => false,
+        };
+        mode || stat & 0b0100_0100 == 0b0100_0100
     }
 
-    pub fn get_window_counter(&self) -> u8 {
-        self.window_counter
+    /* The enabled STAT sources are ORed into a single line and only its
+     * rising edge requests an interrupt, so a source becoming active while
+     * another one already holds the line high goes unnoticed */
+    fn update_stat_line(&mut self) {
+        let line = self.stat_sources();
+        if line && !self.stat_line {
+            self.request_interrupt(Interrupt::Stat);
+        }
+        self.stat_line = line;
     }
 
     pub fn set_window_counter(&mut self, value: u8) {
         self.window_counter = value;
     }
 
-    pub fn increment_timer(&mut self, cycles: u32, tac_enable: bool) -> bool {
-        let cycles = cycles * 4;
-        let mut return_value = false;
+    /// Advances the DIV counter by `cycles` M-cycles, ticking TIMA while TAC
+    /// enables it. Returns whether TIMA was reloaded from TMA, which requests
+    /// the timer interrupt.
+    pub fn increment_timer(&mut self, cycles: u32) -> bool {
+        let mut reloaded = false;
+        for _ in 0..cycles {
+            /* TIMA stays 0 for the M-cycle after it overflows */
+            self.tima_reloaded = std::mem::take(&mut self.tima_overflow);
+            if self.tima_reloaded {
+                self.io[0x05] = self.io[0x06];
+                reloaded = true;
+            }
+            for _ in 0..4 {
+                let old_signal = self.timer_signal();
+                self.timer = self.timer.wrapping_add(1);
+                if old_signal && !self.timer_signal() {
+                    self.tick_tima();
+                }
+            }
+        }
+        reloaded
+    }
+
+    /* TIMA counts falling edges of the DIV counter bit selected by TAC,
+     * ANDed with the TAC enable bit */
+    fn timer_signal(&self) -> bool {
         let bit_select = match self.io[0x07] & 0b0000_0011 {
             0b00 => 9,
             0b01 => 3,
@@ -344,148 +1461,968 @@ impl Mmu {
             0b11 => 7,
             _ => unreachable!(),
         };
-        if tac_enable {
-            for _ in 0..cycles {
-                let old_bit = self.timer >> bit_select & 1;
-                self.timer = self.timer.wrapping_add(1);
-                let new_bit = self.timer >> bit_select & 1;
-                if old_bit == 1 && new_bit == 0 {
-                    self.io[0x05] = self.io[0x05].wrapping_add(1);
-                    if self.io[0x05] == 0 {
-                        self.io[0x05] = self.io[0x06];
-                        return_value = true;
-                    }
-                }
-            }
+        self.io[0x07] & 0b0000_0100 != 0 && self.timer >> bit_select & 1 == 1
+    }
+
+    /// Increments TIMA. On overflow it is left at 0 and reloaded from TMA
+    /// in the next M-cycle.
+    fn tick_tima(&mut self) {
+        self.io[0x05] = self.io[0x05].wrapping_add(1);
+        if self.io[0x05] == 0 {
+            self.tima_overflow = true;
         }
-        return_value
     }
-    pub fn joypad_a(&mut self, pressed: bool) {
-        self.joypad.a = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
+    fn read_joypad(&self) -> u8 {
+        let select = self.io[0x00] & 0b0011_0000;
+        let mut state = 0x0F;
+        if select & 0b0010_0000 == 0 {
+            state &= self.joypad.read_state(true);
+        }
+        if select & 0b0001_0000 == 0 {
+            state &= self.joypad.read_state(false);
+        }
+        /* Bits 6-7 are unused and always read 1 */
+        0b1100_0000 | select | state
+    }
+
+    fn joypad_interrupt(&mut self, pressed: bool) {
         if pressed {
-            self.io[0x0F] |= 0b0001_0000;
+            self.request_interrupt(Interrupt::Joypad);
         }
     }
+
+    pub fn joypad_a(&mut self, pressed: bool) {
+        self.joypad.a = pressed;
+        self.joypad_interrupt(pressed);
+    }
     pub fn joypad_b(&mut self, pressed: bool) {
         self.joypad.b = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
-        }
+        self.joypad_interrupt(pressed);
     }
     pub fn joypad_start(&mut self, pressed: bool) {
         self.joypad.start = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
-        }
+        self.joypad_interrupt(pressed);
     }
     pub fn joypad_select(&mut self, pressed: bool) {
         self.joypad.select = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
-        }
+        self.joypad_interrupt(pressed);
     }
     pub fn joypad_up(&mut self, pressed: bool) {
         self.joypad.up = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
-        }
+        self.joypad_interrupt(pressed);
     }
     pub fn joypad_down(&mut self, pressed: bool) {
         self.joypad.down = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
-        }
+        self.joypad_interrupt(pressed);
     }
     pub fn joypad_left(&mut self, pressed: bool) {
         self.joypad.left = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
-        };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
-        }
+        self.joypad_interrupt(pressed);
     }
     pub fn joypad_right(&mut self, pressed: bool) {
         self.joypad.right = pressed;
-        self.io[0x00] = match (self.io[0x00] & 0b0011_0000) >> 4 {
-            0b00 => {
-                ((self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true))
-                    | self.joypad.read_state(false)
-            }
-            0b01 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(true),
-            0b10 => (self.io[0x0] & 0b1111_0000) + self.joypad.read_state(false),
-            0b11 => self.io[0x00] | 0b0000_1111,
-            _ => unreachable!(),
+        self.joypad_interrupt(pressed);
+    }
+}
+
+/// Seconds since 1970, which the clock footer in saves is stamped with
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+impl Video for Mmu {
+    fn vram(&self) -> &[u8; 0x2000] {
+        &self.vram
+    }
+
+    fn get_oam(&self) -> &[u8; 0xA0] {
+        &self.oam
+    }
+
+    fn lcd_registers(&self) -> &[u8; 12] {
+        self.io[0x40..0x4C].try_into().unwrap()
+    }
+
+    fn get_window_counter(&self) -> u8 {
+        self.window_counter
+    }
+
+    fn colors(&self) -> &ColorPalette {
+        &self.colors
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_boot_io_defaults() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        assert_eq!(mmu.read_byte(0xFF40), 0x91);
+        assert_eq!(mmu.read_byte(0xFF47), 0xFC);
+        assert_eq!(mmu.read_byte(0xFF26), 0xF1);
+        assert_eq!(mmu.read_byte(0xFF0F), 0xE1);
+        assert_eq!(mmu.read_byte(0xFF50), 0xFF);
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xC000, 0x42);
+        assert_eq!(mmu.read_byte(0xE000), 0x42);
+        mmu.write_byte(0xFDFF, 0x24);
+        assert_eq!(mmu.read_byte(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn lyc_write_updates_coincidence() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        mmu.set_ly(10);
+        mmu.write_byte(0xFF44, 20);
+        assert_eq!(mmu.read_byte(0xFF44), 10);
+        mmu.write_byte(0xFF45, 10);
+        assert_eq!(mmu.read_byte(0xFF41) & 0b100, 0b100);
+        mmu.write_byte(0xFF45, 11);
+        assert_eq!(mmu.read_byte(0xFF41) & 0b100, 0);
+    }
+
+    #[test]
+    fn ppu_locks_vram_and_oam() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xFE00, 0x34);
+
+        mmu.set_ppu_mode(2);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.write_byte(0xFE00, 0x56);
+
+        mmu.set_ppu_mode(3);
+        assert_eq!(mmu.read_byte(0x8000), 0xFF);
+        mmu.write_byte(0x8000, 0x78);
+        /* DMA still reaches OAM */
+        mmu.write_byte(0xC000, 0x9A);
+        mmu.write_byte(0xFF46, 0xC0);
+        run_dma(&mut mmu);
+
+        mmu.set_ppu_mode(0);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0x9A);
+
+        /* Nothing is locked with the LCD off */
+        mmu.set_ppu_mode(3);
+        mmu.write_byte(0xFF40, 0);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+    }
+
+    #[test]
+    fn interrupt_bits() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF0F, 0);
+        for (bit, kind) in Interrupt::ALL.into_iter().enumerate() {
+            mmu.request_interrupt(kind);
+            assert_eq!(mmu.read_byte(0xFF0F), 0xE0 | 1 << bit);
+            assert_eq!(kind.vector(), 0x40 + 8 * bit as u16);
+            mmu.clear_interrupt(kind);
+        }
+        assert_eq!(mmu.read_byte(0xFF0F), 0xE0);
+    }
+
+    #[test]
+    fn pending_interrupts_need_ie_and_if() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF0F, 0xFF);
+        assert_eq!(mmu.pending_interrupts(), 0);
+        mmu.write_byte(0xFFFF, 0xFF);
+        assert_eq!(mmu.pending_interrupts(), 0x1F);
+        mmu.write_byte(0xFF0F, Interrupt::Timer.mask());
+        mmu.write_byte(0xFFFF, Interrupt::Timer.mask() | Interrupt::Joypad.mask());
+        assert_eq!(mmu.pending_interrupts(), Interrupt::Timer.mask());
+    }
+
+    #[test]
+    fn game_title_from_header() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x144].copy_from_slice(b"POKEMON RED\0\0\0\0\0");
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "POKEMON RED");
+
+        /* CGB flag at 0x143 */
+        rom[0x134..0x144].copy_from_slice(b"POKEMON YELLOW\0\x80");
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "POKEMON YELLOW");
+
+        /* 15 characters that don't end in a manufacturer code */
+        rom[0x134..0x144].copy_from_slice(b"ABCDEFGHIJK LMN\x80");
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "ABCDEFGHIJK LMN");
+
+        /* 11 characters followed by a manufacturer code */
+        rom[0x134..0x144].copy_from_slice(b"MARIOTENNISAM8E\x80");
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "MARIOTENNIS");
+
+        /* Without the CGB flag all 16 bytes are the title */
+        rom[0x134..0x144].copy_from_slice(b"MARIOTENNISAM8EX");
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "MARIOTENNISAM8EX");
+
+        /* Shorter title followed by a manufacturer code */
+        rom[0x134..0x144].copy_from_slice(b"ZELDA\0\0\0\0\0\0AZ7E\x80");
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "ZELDA");
+
+        assert_eq!(Mmu::new().game_title(), "");
+    }
+
+    #[test]
+    fn seeded_ram_is_reproducible() {
+        let a = Mmu::new_with_seed(0x1234);
+        let b = Mmu::new_with_seed(0x1234);
+        let c = Mmu::new_with_seed(0x4321);
+        assert_eq!(a.wram1, b.wram1);
+        assert_eq!(a.wram2, b.wram2);
+        assert_eq!(a.hram, b.hram);
+        assert_ne!(a.wram1, c.wram1);
+        assert_ne!(a.wram1, [0; 0x2000]);
+        assert_eq!(a.seed(), Some(0x1234));
+        assert_eq!(Mmu::new().seed(), None);
+    }
+
+    #[test]
+    fn reset_clears_previous_game() {
+        let mut mmu = Mmu::new_with_seed(7);
+        mmu.load_game(crate::test_rom::build(&[]).as_slice())
+            .unwrap();
+        mmu.skip_boot();
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xC000, 0x34);
+        mmu.write_byte(0xFE00, 0x56);
+        mmu.write_byte(0xFF80, 0x78);
+        mmu.write_byte(0xFFFF, 0x1F);
+
+        mmu.eject();
+        mmu.reset();
+
+        let fresh = Mmu::new_with_seed(7);
+        assert!(mmu.rom.is_empty());
+        assert_eq!(mmu.vram, fresh.vram);
+        assert_eq!(mmu.wram1, fresh.wram1);
+        assert_eq!(mmu.oam, fresh.oam);
+        assert_eq!(mmu.io, fresh.io);
+        assert_eq!(mmu.hram, fresh.hram);
+        assert_eq!(mmu.ie, 0);
+        assert_eq!(mmu.timer, 0);
+    }
+
+    #[test]
+    fn reset_keeps_cartridge() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x03; /* MBC1+RAM+BATTERY */
+        rom[0x148] = 0x01;
+        rom[0x149] = 0x02;
+        rom[0xC000] = 0x99;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        mmu.write_byte(0xFF50, 0x01);
+        mmu.write_byte(0x0000, 0x0A);
+        mmu.write_byte(0xA000, 0x55);
+        mmu.write_byte(0x2000, 0x03);
+        assert_eq!(mmu.read_byte(0x4000), 0x99);
+
+        mmu.reset();
+
+        assert_eq!(mmu.read_byte(0xFF50), 0x00);
+        assert_eq!(mmu.read_byte(0x4000), 0x00);
+        assert_eq!(mmu.read_byte(0xA000), 0xFF);
+        mmu.write_byte(0x0000, 0x0A);
+        assert_eq!(mmu.read_byte(0xA000), 0x55);
+    }
+
+    #[test]
+    fn stat_interrupt_on_rising_edge() {
+        let mut mmu = Mmu::new();
+        let mut interrupts = 0;
+        let mut count = |mmu: &mut Mmu| {
+            if mmu.read_byte(0xFF0F) & Interrupt::Stat.mask() != 0 {
+                mmu.clear_interrupt(Interrupt::Stat);
+                interrupts += 1;
+            }
         };
-        if pressed {
-            self.io[0x0F] |= 0b0001_0000;
+        /* HBlank and LYC sources */
+        mmu.set_ppu_mode(2);
+        mmu.write_byte(0xFF41, 0b0100_1000);
+        for _ in 0..3 {
+            mmu.set_ppu_mode(2);
+            count(&mut mmu);
+            mmu.set_ppu_mode(3);
+            count(&mut mmu);
+            /* LYC matches from HBlank until the next HBlank, keeping the line up */
+            mmu.set_ppu_mode(0);
+            count(&mut mmu);
+            mmu.set_lyc_coincidence(true);
+            count(&mut mmu);
+            mmu.set_ppu_mode(2);
+            count(&mut mmu);
+            mmu.set_ppu_mode(3);
+            count(&mut mmu);
+            mmu.set_ppu_mode(0);
+            count(&mut mmu);
+            mmu.set_lyc_coincidence(false);
+            count(&mut mmu);
+        }
+        /* One edge per HBlank that follows mode 3 with LYC inactive */
+        assert_eq!(interrupts, 3);
+    }
+
+    #[test]
+    fn oam_dma_from_work_ram() {
+        let mut mmu = Mmu::new();
+        for i in 0..0xA0 {
+            mmu.write_byte(0xC100 + i, i as u8);
+            mmu.write_byte(0xDE00 + i, !i as u8);
+        }
+
+        mmu.write_byte(0xFF46, 0xC1);
+        run_dma(&mut mmu);
+        assert_eq!(mmu.oam, std::array::from_fn(|i| i as u8));
+
+        mmu.oam.fill(0);
+        mmu.write_byte(0xFF46, 0xE1);
+        run_dma(&mut mmu);
+        assert_eq!(mmu.oam, std::array::from_fn(|i| i as u8));
+
+        mmu.write_byte(0xFF46, 0xFE);
+        run_dma(&mut mmu);
+        assert_eq!(mmu.oam, std::array::from_fn(|i| !i as u8));
+    }
+
+    /// Runs the instruction that started a DMA and the whole transfer
+    fn run_dma(mmu: &mut Mmu) {
+        mmu.step_dma(1);
+        mmu.step_dma(160);
+    }
+
+    #[test]
+    fn dma_out_of_range_in_state_is_rejected() {
+        let save = |mmu: &Mmu| {
+            let mut state = StateWriter::new();
+            mmu.save_state(&mut state);
+            state.finish()
+        };
+        let mut mmu = Mmu::new();
+        let idle = save(&mmu);
+        mmu.write_byte(0xFF46, 0xC0);
+        let busy = save(&mmu);
+        /* Everything after the transfer is the same, it ends where the
+         * states stop matching from the back */
+        let tail = (1..idle.len())
+            .take_while(|&i| idle[idle.len() - i] == busy[busy.len() - i])
+            .count();
+        let at = busy.len() - tail - 6;
+        assert_eq!(busy[at..at + 6], [1, 0x00, 0xC0, 0x00, 0x00, 1]);
+
+        let mut bad_copied = busy.clone();
+        bad_copied[at + 3] = 0xA0;
+        let mut bad_source = busy.clone();
+        bad_source[at + 2] = 0xFF;
+        for state in [bad_copied, bad_source] {
+            let result = mmu.load_state(&mut StateReader::new(&state));
+            assert_eq!(result, Err(StateError::Corrupt));
+        }
+        assert_eq!(mmu.load_state(&mut StateReader::new(&busy)), Ok(()));
+    }
+
+    #[test]
+    fn oam_dma_holds_the_bus() {
+        let mut mmu = Mmu::new();
+        for i in 0..0xA0 {
+            mmu.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        mmu.write_byte(0x8000, 0x12);
+        mmu.write_byte(0xFF80, 0x34);
+
+        mmu.write_byte(0xFF46, 0xC0);
+        /* Free until the writing instruction is done */
+        assert_eq!(mmu.read_byte(0xC010), 0x11);
+        mmu.step_dma(3);
+
+        mmu.step_dma(0x10);
+        assert_eq!(mmu.oam[0x0F], 0x10);
+        assert_eq!(mmu.oam[0x10], 0);
+        /* Work RAM and ROM share the bus with the source, VRAM doesn't */
+        assert_eq!(mmu.read_byte(0xC050), 0x11);
+        assert_eq!(mmu.read_byte(0x0150), 0x11);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        assert_eq!(mmu.read_byte(0xFF80), 0x34);
+        assert_eq!(mmu.read_byte(0xFF46), 0xC0);
+        mmu.write_byte(0xC050, 0);
+        mmu.write_byte(0xFE00, 0);
+        mmu.write_byte(0xFF80, 0x56);
+        assert_eq!(mmu.read_byte(0xFF80), 0x56);
+
+        mmu.step_dma(0x8F);
+        assert_eq!(mmu.oam[0x9E], 0x9F);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.step_dma(1);
+        assert_eq!(mmu.oam, std::array::from_fn(|i| i as u8 + 1));
+        assert_eq!(mmu.read_byte(0xC050), 0x51);
+        assert_eq!(mmu.read_byte(0xFE00), 0x01);
+    }
+
+    #[test]
+    fn unhandled_io_reported_once() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF4F, 0x01);
+        mmu.set_unhandled_io_log(true);
+
+        mmu.write_byte(0xFF4F, 0x01);
+        mmu.write_byte(0xFF40, 0x91);
+        mmu.write_byte(0xFF56, 0x77);
+        mmu.write_byte(0xFF4F, 0x00);
+        mmu.write_byte(0xFF80, 0x01);
+
+        assert_eq!(mmu.unhandled_io_writes(), [0xFF4F, 0xFF56]);
+        /* Unhandled registers still store the value */
+        assert_eq!(mmu.read_byte(0xFF56), 0x77);
+    }
+
+    #[test]
+    fn serial_stdout_completes_transfer() {
+        let mut mmu = Mmu::new();
+        mmu.set_serial_stdout(true);
+        mmu.write_byte(0xFF01, b'A');
+        mmu.write_byte(0xFF02, 0x81);
+        assert_eq!(mmu.read_byte(0xFF01), 0xFF);
+        assert_eq!(mmu.read_byte(0xFF02) & 0x80, 0);
+        assert_ne!(mmu.read_byte(0xFF0F) & Interrupt::Serial.mask(), 0);
+        assert!(!mmu.serial_transfer_pending());
+    }
+
+    /* Collects serial output where the test can still read it */
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serial_sink_receives_bytes() {
+        let output = SharedBuffer::default();
+        let mut mmu = Mmu::new();
+        mmu.set_serial_sink(output.clone());
+        for &byte in b"Passed\n" {
+            mmu.write_byte(0xFF01, byte);
+            mmu.write_byte(0xFF02, 0x81);
+            assert_eq!(mmu.read_byte(0xFF02) & 0x80, 0);
+        }
+        /* External clock transfers wait for the other side */
+        mmu.write_byte(0xFF01, b'!');
+        mmu.write_byte(0xFF02, 0x80);
+
+        assert_eq!(output.0.borrow().as_slice(), b"Passed\n");
+        assert_ne!(mmu.read_byte(0xFF0F) & Interrupt::Serial.mask(), 0);
+    }
+
+    #[test]
+    fn io_snapshot_round_trip() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        mmu.write_byte(0xFF00, 0x20);
+        mmu.set_ppu_mode(0);
+        mmu.write_byte(0xFF41, 0b0000_1000);
+        let snapshot = mmu.io_snapshot();
+        let p1 = mmu.read_byte(0xFF00);
+        let stat = mmu.read_byte(0xFF41);
+
+        let mut other = Mmu::new();
+        other.write_byte(0xFF0F, 0);
+        other.restore_io(&snapshot);
+
+        assert_eq!(other.io_snapshot(), snapshot);
+        assert_eq!(other.read_byte(0xFF00), p1);
+        assert_eq!(other.read_byte(0xFF41), stat);
+        assert_eq!(other.read_byte(0xFF0F), mmu.read_byte(0xFF0F));
+        /* Restoring with the HBlank source active is not a new edge */
+        other.set_ppu_mode(0);
+        assert_eq!(other.read_byte(0xFF0F), mmu.read_byte(0xFF0F));
+    }
+
+    #[test]
+    fn header_checksum() {
+        let mut rom = vec![0; 0x8000];
+        /* 25 zero bytes, each taking away one more */
+        rom[0x14D] = 0xE7;
+        let mut mmu = Mmu::new();
+        mmu.set_strict_header(true);
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(global_checksum(&mmu.rom, 0x8000), 0xE7);
+
+        rom[0x134..0x139].copy_from_slice(b"TETRS");
+        let mut mmu = Mmu::new();
+        mmu.set_strict_header(true);
+        assert!(matches!(
+            mmu.load_game(rom.as_slice()),
+            Err(CartridgeError::HeaderChecksum {
+                stored: 0xE7,
+                computed: 0x55
+            })
+        ));
+        assert!(mmu.rom.is_empty());
+
+        /* Only a warning by default */
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.game_title(), "TETRS");
+    }
+
+    #[test]
+    fn tiny_rom_is_an_error() {
+        let mut mmu = Mmu::new();
+        assert!(matches!(
+            mmu.load_game([0u8; 10].as_slice()),
+            Err(CartridgeError::TooSmall(10))
+        ));
+        assert!(matches!(
+            mmu.load_game([0u8; 0].as_slice()),
+            Err(CartridgeError::TooSmall(0))
+        ));
+        assert!(mmu.rom.is_empty());
+        assert_eq!(mmu.game_title(), "");
+    }
+
+    #[test]
+    fn rom_banks_wrap_past_the_end() {
+        /* 48KiB of ROM, padded to 4 banks */
+        let mut rom = vec![0u8; 0xC000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        for bank in 0..3 {
+            rom[bank * 0x4000 + 0x1000] = bank as u8;
         }
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert_eq!(mmu.rom.len(), 4);
+
+        mmu.write_byte(0x2000, 3);
+        assert_eq!(mmu.read_byte(0x5000), 0xFF);
+        mmu.write_byte(0x2000, 6);
+        assert_eq!(mmu.read_byte(0x5000), 2);
+
+        /* A bank no mapper should select still reads a mirror */
+        mmu.banks.bank0 = 0x81;
+        mmu.banks.bank1 = usize::MAX;
+        assert_eq!(mmu.read_byte(0x1000), 1);
+        assert_eq!(mmu.read_byte(0x5000), 0xFF);
+    }
+
+    #[test]
+    fn no_cartridge_reads_open_bus() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        assert_eq!(mmu.read_byte(0x0100), 0xFF);
+        assert_eq!(mmu.read_byte(0x4000), 0xFF);
+    }
+
+    #[test]
+    fn preset_external_ram() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x03;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+
+        mmu.set_ram(0, &[0x12, 0x34]).unwrap();
+        mmu.write_byte(0x0000, 0x0A);
+        assert_eq!(mmu.read_byte(0xA000), 0x12);
+        mmu.write_byte(0xA001, 0x56);
+        assert_eq!(mmu.get_ram(0).unwrap()[..2], [0x12, 0x56]);
+
+        assert!(mmu.set_ram(3, &[0; 0x2000]).is_ok());
+        assert!(mmu.set_ram(4, &[0]).is_err());
+        assert!(mmu.set_ram(0, &[0; 0x2001]).is_err());
+        assert!(mmu.get_ram(4).is_none());
+    }
+
+    #[test]
+    fn tac_write_glitch() {
+        let mut mmu = Mmu::new();
+        /* 262144 Hz selects bit 3, 4096 Hz selects bit 9 */
+        mmu.write_byte(0xFF07, 0b101);
+        mmu.timer = 0x0008;
+        mmu.write_byte(0xFF07, 0b100);
+        assert_eq!(mmu.read_byte(0xFF05), 1);
+
+        /* Bit 9 is high too, the signal stays up */
+        mmu.write_byte(0xFF07, 0b101);
+        mmu.timer = 0x0208;
+        mmu.write_byte(0xFF07, 0b100);
+        assert_eq!(mmu.read_byte(0xFF05), 1);
+
+        /* Disabling the timer while the bit is high */
+        mmu.write_byte(0xFF07, 0b000);
+        assert_eq!(mmu.read_byte(0xFF05), 2);
+
+        /* Nothing happens when the selected bit was low */
+        mmu.write_byte(0xFF07, 0b101);
+        mmu.timer = 0x0000;
+        mmu.write_byte(0xFF07, 0b100);
+        assert_eq!(mmu.read_byte(0xFF05), 2);
+    }
+
+    #[test]
+    fn tac_write_glitch_overflow() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF05, 0xFF);
+        mmu.write_byte(0xFF06, 0x42);
+        mmu.write_byte(0xFF0F, 0);
+        mmu.write_byte(0xFF07, 0b101);
+        mmu.timer = 0x0008;
+        mmu.write_byte(0xFF07, 0b110);
+        /* Reloaded a cycle later like any overflow */
+        assert_eq!(mmu.read_byte(0xFF05), 0x00);
+        assert!(mmu.increment_timer(1));
+        assert_eq!(mmu.read_byte(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn external_ram_open_bus() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        mmu.set_ram(0, &[0x00]).unwrap();
+
+        /* Disabled */
+        assert_eq!(mmu.read_byte(0xA000), 0xFF);
+        mmu.write_byte(0xA000, 0x12);
+        assert_eq!(mmu.get_ram(0).unwrap()[0], 0x00);
+
+        mmu.write_byte(0x0000, 0x0A);
+        assert_eq!(mmu.read_byte(0xA000), 0x00);
+
+        /* Enabled, but a bank the cartridge doesn't have */
+        mmu.banks.eram = Some(3);
+        assert_eq!(mmu.read_byte(0xA000), 0xFF);
+        mmu.write_byte(0xA000, 0x34);
+        assert_eq!(mmu.get_ram(0).unwrap()[0], 0x00);
+    }
+
+    #[test]
+    fn tile_bytes_addressing_modes() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0x8000, 0x11);
+        mmu.write_byte(0x8800, 0x22);
+        mmu.write_byte(0x8FF0, 0x33);
+        mmu.write_byte(0x9000, 0x44);
+        mmu.write_byte(0x97F0, 0x55);
+
+        assert_eq!(mmu.tile_bytes(0x00, false)[0], 0x11);
+        assert_eq!(mmu.tile_bytes(0x80, false)[0], 0x22);
+        assert_eq!(mmu.tile_bytes(0xFF, false)[0], 0x33);
+        assert_eq!(mmu.tile_bytes(0x00, true)[0], 0x44);
+        assert_eq!(mmu.tile_bytes(0x7F, true)[0], 0x55);
+        assert_eq!(mmu.tile_bytes(0x80, true)[0], 0x22);
+        assert_eq!(mmu.tile_bytes(0xFF, true)[0], 0x33);
+    }
+
+    #[test]
+    fn stat_read_only_bits() {
+        let mut mmu = Mmu::new();
+        mmu.set_ppu_mode(3);
+        mmu.set_lyc_coincidence(true);
+        mmu.write_byte(0xFF41, 0x00);
+        assert_eq!(mmu.read_byte(0xFF41), 0b1000_0111);
+
+        mmu.write_byte(0xFF41, 0xFF);
+        assert_eq!(mmu.read_byte(0xFF41), 0xFF);
+
+        mmu.set_ppu_mode(0);
+        mmu.set_lyc_coincidence(false);
+        mmu.write_byte(0xFF41, 0b0000_0111);
+        assert_eq!(mmu.read_byte(0xFF41), 0b1000_0000);
+    }
+
+    #[test]
+    fn joypad_selection() {
+        let mut mmu = Mmu::new();
+        mmu.joypad_a(true);
+        mmu.joypad_start(true);
+        mmu.joypad_left(true);
+
+        /* Nothing selected */
+        mmu.write_byte(0xFF00, 0x30);
+        assert_eq!(mmu.read_byte(0xFF00), 0xFF);
+
+        /* Buttons */
+        mmu.write_byte(0xFF00, 0x10);
+        assert_eq!(mmu.read_byte(0xFF00), 0xD0 | 0b0110);
+
+        /* Directions */
+        mmu.write_byte(0xFF00, 0x20);
+        assert_eq!(mmu.read_byte(0xFF00), 0xE0 | 0b1101);
+
+        /* Both, lines are shared so either group pulls a bit low */
+        mmu.write_byte(0xFF00, 0x00);
+        assert_eq!(mmu.read_byte(0xFF00), 0xC0 | 0b0100);
+
+        /* The read follows the live button state */
+        mmu.joypad_start(false);
+        mmu.write_byte(0xFF00, 0x10);
+        assert_eq!(mmu.read_byte(0xFF00), 0xD0 | 0b1110);
+    }
+
+    #[test]
+    fn apu_registers_read_back_masked() {
+        let mut mmu = Mmu::new();
+        for address in 0xFF10..=0xFF26 {
+            mmu.write_byte(address, 0x00);
+        }
+        let read: Vec<u8> = (0xFF10..=0xFF26).map(|a| mmu.read_byte(a)).collect();
+        assert_eq!(read, APU_READ_MASK);
+
+        mmu.write_byte(0xFF26, 0x80);
+        /* Frequency registers are write-only */
+        mmu.write_byte(0xFF13, 0x12);
+        assert_eq!(mmu.read_byte(0xFF13), 0xFF);
+        /* Duty reads back, length doesn't */
+        mmu.write_byte(0xFF11, 0b1001_0101);
+        assert_eq!(mmu.read_byte(0xFF11), 0b1011_1111);
+        mmu.write_byte(0xFF12, 0xF3);
+        assert_eq!(mmu.read_byte(0xFF12), 0xF3);
+
+        /* Channel status can't be written, only cleared by powering off */
+        mmu.write_byte(0xFF26, 0xFF);
+        assert_eq!(mmu.read_byte(0xFF26), 0xF0);
+        mmu.skip_boot();
+        mmu.write_byte(0xFF26, 0x80);
+        assert_eq!(mmu.read_byte(0xFF26), 0xF1);
+        mmu.write_byte(0xFF26, 0x0F);
+        assert_eq!(mmu.read_byte(0xFF26), 0x70);
+    }
+
+    fn battery_cart(ram_size: u8) -> Mmu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = ram_size;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        mmu
+    }
+
+    #[test]
+    fn sram_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("trashgb-{}-round-trip.sav", std::process::id()));
+        let mut mmu = battery_cart(0x03);
+        assert!(mmu.has_battery());
+        mmu.set_ram(0, &[0x12, 0x34]).unwrap();
+        mmu.set_ram(3, &[0x56]).unwrap();
+        mmu.save_sram(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 0x2000);
+
+        let mut loaded = battery_cart(0x03);
+        loaded.load_sram(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get_ram(0).unwrap()[..2], [0x12, 0x34]);
+        assert_eq!(loaded.get_ram(3).unwrap()[0], 0x56);
+    }
+
+    #[test]
+    fn rtc_is_saved_and_catches_up() {
+        /* MBC3+TIMER+RAM+BATTERY with one RAM bank */
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x02;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        let mut loaded = Mmu::new();
+        loaded.load_game(rom.as_slice()).unwrap();
+        let clock = |mmu: &mut Mmu| -> Vec<u8> {
+            mmu.write_byte(0x6000, 0x00);
+            mmu.write_byte(0x6000, 0x01);
+            (0x08..=0x0C)
+                .map(|register| {
+                    mmu.write_byte(0x4000, register);
+                    mmu.read_byte(0xA000)
+                })
+                .collect()
+        };
+
+        /* Day 5, 03:20:10 */
+        mmu.write_byte(0x0000, 0x0A);
+        for (register, value) in [(0x08, 10), (0x09, 20), (0x0A, 3), (0x0B, 5), (0x0C, 0)] {
+            mmu.write_byte(0x4000, register);
+            mmu.write_byte(0xA000, value);
+        }
+        mmu.write_byte(0x4000, 0x00);
+        mmu.write_byte(0xA000, 0x42);
+        let save = mmu.sram(1_000_000);
+        assert_eq!(save.len(), 0x2000 + RTC_FOOTER_LEN);
+
+        /* An hour, a minute and a second later */
+        loaded.restore_sram(&save, 1_000_000 + 3661).unwrap();
+        loaded.write_byte(0x0000, 0x0A);
+        assert_eq!(clock(&mut loaded), [11, 21, 4, 5, 0]);
+        loaded.write_byte(0x4000, 0x00);
+        assert_eq!(loaded.read_byte(0xA000), 0x42);
+
+        /* Saves from before the footer still load */
+        loaded.restore_sram(&save[..0x2000], 0).unwrap();
+
+        /* Carts without a clock refuse one */
+        let mut no_rtc = battery_cart(0x02);
+        assert!(matches!(
+            no_rtc.restore_sram(&save, 0),
+            Err(SaveError::WrongSize { .. })
+        ));
+    }
+
+    #[test]
+    fn sram_size_mismatch_is_an_error() {
+        let path =
+            std::env::temp_dir().join(format!("trashgb-{}-mismatch.sav", std::process::id()));
+        std::fs::write(&path, vec![0xAA; 0x2000]).unwrap();
+        let mut mmu = battery_cart(0x03);
+        mmu.set_ram(0, &[0x12]).unwrap();
+
+        let result = mmu.load_sram(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(SaveError::WrongSize {
+                expected: 0x8000,
+                actual: 0x2000
+            })
+        ));
+        assert_eq!(mmu.get_ram(0).unwrap()[0], 0x12);
+    }
+
+    #[test]
+    fn only_battery_carts_have_saves() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x02;
+        rom[0x149] = 0x02;
+        let mut mmu = Mmu::new();
+        mmu.load_game(rom.as_slice()).unwrap();
+        assert!(!mmu.has_battery());
+        assert!(!Mmu::new().has_battery());
+    }
+
+    #[test]
+    fn tac_enable_gates_tima() {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF05, 0xFF);
+        /* Fastest rate, every 4 M-cycles, but disabled */
+        mmu.write_byte(0xFF07, 0b001);
+        assert!(!mmu.increment_timer(64));
+        assert_eq!(mmu.read_byte(0xFF05), 0xFF);
+        /* The counter behind DIV runs regardless */
+        assert_eq!(mmu.div_counter(), 256);
+
+        mmu.write_byte(0xFF07, 0b101);
+        /* Overflows at the end of the 4th M-cycle, reloads in the 5th */
+        assert!(!mmu.increment_timer(4));
+        assert_eq!(mmu.read_byte(0xFF05), 0x00);
+        assert!(mmu.increment_timer(8));
+        assert_eq!(mmu.read_byte(0xFF05), 0x02);
+    }
+
+    /* TIMA at 0xFF one M-cycle before it overflows at the fastest rate,
+     * reloading from TMA = 0x42 */
+    fn about_to_overflow() -> Mmu {
+        let mut mmu = Mmu::new();
+        mmu.write_byte(0xFF05, 0xFF);
+        mmu.write_byte(0xFF06, 0x42);
+        mmu.write_byte(0xFF07, 0b101);
+        mmu.timer = 0x000C;
+        mmu
+    }
+
+    #[test]
+    fn tima_reload_is_delayed() {
+        let mut mmu = about_to_overflow();
+        assert!(!mmu.increment_timer(1));
+        assert_eq!(mmu.read_byte(0xFF05), 0x00);
+        assert!(mmu.increment_timer(1));
+        assert_eq!(mmu.read_byte(0xFF05), 0x42);
+
+        /* Writing TIMA while it reads 0 cancels the reload and interrupt */
+        let mut mmu = about_to_overflow();
+        mmu.increment_timer(1);
+        mmu.write_byte(0xFF05, 0x10);
+        assert!(!mmu.increment_timer(1));
+        assert_eq!(mmu.read_byte(0xFF05), 0x10);
+
+        /* Writing it during the reload is lost */
+        let mut mmu = about_to_overflow();
+        mmu.increment_timer(2);
+        mmu.write_byte(0xFF05, 0x10);
+        assert_eq!(mmu.read_byte(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn tma_write_while_reloading() {
+        /* Before the reload, the new TMA is what gets loaded */
+        let mut mmu = about_to_overflow();
+        mmu.increment_timer(1);
+        mmu.write_byte(0xFF06, 0x69);
+        assert!(mmu.increment_timer(1));
+        assert_eq!(mmu.read_byte(0xFF05), 0x69);
+
+        /* During the reload cycle TIMA follows TMA */
+        let mut mmu = about_to_overflow();
+        mmu.increment_timer(2);
+        mmu.write_byte(0xFF06, 0x69);
+        assert_eq!(mmu.read_byte(0xFF05), 0x69);
+
+        /* After it, TIMA keeps the old value */
+        mmu.increment_timer(1);
+        mmu.write_byte(0xFF06, 0x77);
+        assert_eq!(mmu.read_byte(0xFF05), 0x69);
+    }
+
+    #[test]
+    fn div_reads_the_counter() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        assert_eq!(mmu.read_byte(0xFF04), 0xAB);
+        /* Counts up every 64 M-cycles */
+        mmu.increment_timer(64);
+        assert_eq!(mmu.read_byte(0xFF04), 0xAC);
+
+        mmu.write_byte(0xFF04, 0x12);
+        assert_eq!(mmu.read_byte(0xFF04), 0);
+        mmu.increment_timer(63);
+        assert_eq!(mmu.read_byte(0xFF04), 0);
+        mmu.increment_timer(1);
+        assert_eq!(mmu.read_byte(0xFF04), 1);
     }
 }
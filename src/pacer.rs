@@ -0,0 +1,98 @@
+#[cfg(target_arch = "wasm32")]
+use instant::{Duration, Instant};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+
+const CLOCK_RATE: f64 = 4_194_304.0;
+const DOTS_PER_FRAME: f64 = 70224.0;
+
+/// Frames per second at normal speed, about 59.73
+pub const FRAME_RATE: f64 = CLOCK_RATE / DOTS_PER_FRAME;
+
+/// Schedules frames at `FRAME_RATE` times a speed multiplier. Deadlines are
+/// worked out from the number of frames since `start` rather than by adding
+/// up a rounded frame time, so they don't drift.
+pub struct FramePacer {
+    start: Instant,
+    frames: u32,
+    speed: f64,
+}
+
+impl FramePacer {
+    /// Starts counting frames from `now` at normal speed
+    pub fn new(now: Instant) -> Self {
+        FramePacer {
+            start: now,
+            frames: 0,
+            speed: 1.0,
+        }
+    }
+
+    /// Changes the rate frames are scheduled at, counting again from the
+    /// current frame's deadline
+    pub fn set_speed(&mut self, speed: f64) {
+        self.start = self.deadline();
+        self.frames = 0;
+        self.speed = speed;
+    }
+
+    /// Moves on to the next frame, returning when it is due. Counts from
+    /// `now` instead if it is already a frame behind, rather than rushing
+    /// to catch up.
+    pub fn next_frame(&mut self, now: Instant) -> Instant {
+        self.frames += 1;
+        let deadline = self.deadline();
+        if deadline >= now {
+            return deadline;
+        }
+        self.start = now;
+        self.frames = 1;
+        self.deadline()
+    }
+
+    fn deadline(&self) -> Instant {
+        let seconds = self.frames as f64 / (FRAME_RATE * self.speed);
+        self.start + Duration::from_secs_f64(seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Duration, b: Duration) -> bool {
+        a.abs_diff(b) < Duration::from_micros(1)
+    }
+
+    #[test]
+    fn no_drift() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let mut deadline = start;
+        for _ in 0..0x40000 {
+            deadline = pacer.next_frame(start);
+        }
+        /* 2^18 frames of 70224 dots at 2^22 Hz are exactly 4389 seconds */
+        assert!(close(deadline - start, Duration::from_secs(4389)));
+    }
+
+    #[test]
+    fn falling_behind_restarts_from_now() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let late = start + Duration::from_secs(1);
+        let deadline = pacer.next_frame(late);
+        assert!(deadline > late);
+        assert!(deadline - late < Duration::from_millis(17));
+    }
+
+    #[test]
+    fn speed_scales_the_frame_time() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(start);
+        let normal = pacer.next_frame(start) - start;
+        pacer.set_speed(0.5);
+        let slow = pacer.next_frame(start) - (start + normal);
+        assert!(close(slow, normal * 2));
+    }
+}
@@ -1,8 +1,16 @@
-use std::num::Wrapping;
-
+#[cfg(feature = "parallel")]
 use crate::mmu::Mmu;
 
-#[derive(Debug)]
+/// RGBA for each DMG shade, lightest first
+pub const DEFAULT_PALETTE: [[u8; 4]; 4] = [
+    [232, 252, 204, 255],
+    [172, 212, 144, 255],
+    [84, 140, 112, 255],
+    [20, 44, 56, 255],
+];
+
+/// The four DMG shades, lightest first so `as u8` gives the shade number
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Palette {
     White,
     LightGray,
@@ -22,6 +30,60 @@ impl Palette {
     }
 }
 
+/// The RGBA colors the four shades are shown in, lightest first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorPalette(pub [[u8; 4]; 4]);
+
+impl ColorPalette {
+    /// trashgb's own greens, the default
+    pub const TRASHGB: Self = ColorPalette(DEFAULT_PALETTE);
+    /// The yellowish greens of the original DMG screen
+    pub const DMG: Self = ColorPalette([
+        [155, 188, 15, 255],
+        [139, 172, 15, 255],
+        [48, 98, 48, 255],
+        [15, 56, 15, 255],
+    ]);
+    /// The Game Boy Pocket's black and white screen
+    pub const POCKET: Self = ColorPalette([
+        [196, 207, 161, 255],
+        [139, 149, 109, 255],
+        [77, 83, 60, 255],
+        [31, 31, 31, 255],
+    ]);
+    pub const GRAYSCALE: Self = ColorPalette([
+        [255, 255, 255, 255],
+        [170, 170, 170, 255],
+        [85, 85, 85, 255],
+        [0, 0, 0, 255],
+    ]);
+
+    /// The presets by the names `from_name` takes
+    pub const PRESETS: [(&'static str, ColorPalette); 4] = [
+        ("trashgb", Self::TRASHGB),
+        ("dmg", Self::DMG),
+        ("pocket", Self::POCKET),
+        ("grayscale", Self::GRAYSCALE),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|&(_, colors)| colors)
+    }
+
+    pub fn rgba(&self, shade: Palette) -> &[u8; 4] {
+        &self.0[shade as usize]
+    }
+}
+
+impl Default for ColorPalette {
+    fn default() -> Self {
+        Self::TRASHGB
+    }
+}
+
 #[derive(Debug)]
 pub struct ObjectAttribute {
     pub y: i16,
@@ -47,223 +109,983 @@ impl ObjectAttribute {
     }
 }
 
-pub fn draw_sprites(mapper: &Mmu, line: u8, output: &mut [u8]) {
-    let tiles = mapper.get_oam_tile_data();
-    let offset = if mapper.get_obj_size() { 16 } else { 8 };
-    let oam_table = mapper.get_oam();
-    let mut tile_count = 0;
-    let line = line as i16;
-    let mut x_values = Vec::<i16>::new();
+/// LCDC (0xFF40) split into its bits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lcdc {
+    /// Bit 7, the PPU is on
+    pub lcd_enable: bool,
+    /// Bit 6, the window uses the tile map at 0x9C00 instead of 0x9800
+    pub window_map_mode: bool,
+    /// Bit 5
+    pub window_enable: bool,
+    /// Bit 4, background and window tiles use unsigned indexes from 0x8000
+    /// instead of signed indexes from 0x9000
+    pub tile_mode: bool,
+    /// Bit 3, the background uses the tile map at 0x9C00 instead of 0x9800
+    pub bg_map_mode: bool,
+    /// Bit 2, objects are 8x16 instead of 8x8
+    pub obj_size: bool,
+    /// Bit 1
+    pub obj_enable: bool,
+    /// Bit 0, on DMG clearing it blanks the background and window
+    pub bg_enable: bool,
+}
 
-    for sprite in oam_table
-        .chunks_exact(4)
-        .map(|sprite| ObjectAttribute::from_bytes(sprite.try_into().unwrap()))
-    {
-        if sprite.y >= 144 || sprite.y == -16 {
-            continue;
+impl Lcdc {
+    pub fn from_u8(value: u8) -> Self {
+        Self {
+            lcd_enable: value >> 7 & 0b1 == 1,
+            window_map_mode: value >> 6 & 0b1 == 1,
+            window_enable: value >> 5 & 0b1 == 1,
+            tile_mode: value >> 4 & 0b1 == 1,
+            bg_map_mode: value >> 3 & 0b1 == 1,
+            obj_size: value >> 2 & 0b1 == 1,
+            obj_enable: value >> 1 & 0b1 == 1,
+            bg_enable: value & 0b1 == 1,
         }
-        if line >= sprite.y.wrapping_add(offset) || line < sprite.y {
-            continue;
+    }
+
+    /// Height of an object in pixels
+    pub fn obj_height(&self) -> i16 {
+        if self.obj_size {
+            16
+        } else {
+            8
         }
-        if sprite.x >= 160 || sprite.x == -8 {
-            tile_count += 1;
-            continue;
+    }
+}
+
+/// What lines are drawn from: VRAM, OAM, the LCD registers and the window
+/// line counter
+pub trait Video {
+    fn vram(&self) -> &[u8; 0x2000];
+
+    fn get_oam(&self) -> &[u8; 0xA0];
+
+    /// 0xFF40-0xFF4B, LCDC to WX
+    fn lcd_registers(&self) -> &[u8; 12];
+
+    fn get_window_counter(&self) -> u8;
+
+    /// The colors shades are drawn in
+    fn colors(&self) -> &ColorPalette;
+
+    fn lcdc(&self) -> Lcdc {
+        Lcdc::from_u8(self.lcd_registers()[0x0])
+    }
+
+    /// The 16 bytes of a tile, either from 0x8000 with an unsigned index, or
+    /// from 0x9000 with a signed index (the 0x8800 addressing mode)
+    fn tile_bytes(&self, index: u8, signed: bool) -> &[u8; 16] {
+        let start = match signed {
+            true => (0x1000 + index as i8 as i16 * 16) as usize,
+            false => index as usize * 16,
+        };
+        self.vram()[start..start + 16].try_into().unwrap()
+    }
+
+    /// Tile data for a background or window tile map entry, addressed the
+    /// way LCDC bit 4 selects
+    fn bg_tile_bytes(&self, index: u8) -> &[u8; 16] {
+        self.tile_bytes(index, !self.lcdc().tile_mode)
+    }
+
+    fn get_bg_tile_map(&self) -> &[u8; 0x400] {
+        if self.lcdc().bg_map_mode {
+            self.vram()[0x1C00..0x2000].try_into().unwrap()
+        } else {
+            self.vram()[0x1800..0x1C00].try_into().unwrap()
         }
+    }
 
-        if x_values.contains(&sprite.x) {
-            tile_count += 1;
-            continue;
+    fn get_window_tile_map(&self) -> &[u8; 0x400] {
+        if self.lcdc().window_map_mode {
+            self.vram()[0x1C00..0x2000].try_into().unwrap()
+        } else {
+            self.vram()[0x1800..0x1C00].try_into().unwrap()
         }
+    }
+
+    fn get_window_pos(&self) -> (u8, u8) {
+        (self.lcd_registers()[0xA], self.lcd_registers()[0xB])
+    }
 
+    fn get_bg_palette(&self) -> [Palette; 4] {
+        Palette::from_u8(self.lcd_registers()[0x7])
+    }
+
+    fn get_obj_palette(&self, palette: usize) -> [Palette; 4] {
+        Palette::from_u8(self.lcd_registers()[0x8 + (palette & 0x1)])
+    }
+}
+
+/// The registers a line was drawn with, for drawing it later
+#[cfg(feature = "parallel")]
+#[derive(Debug, Clone, Copy)]
+pub struct DeferredLine {
+    line: u8,
+    registers: [u8; 12],
+    window_counter: u8,
+}
+
+#[cfg(feature = "parallel")]
+impl DeferredLine {
+    /// The registers `line` is drawn with as it reaches HBlank
+    pub fn new(mapper: &impl Video, line: u8) -> Self {
+        DeferredLine {
+            line,
+            registers: *mapper.lcd_registers(),
+            window_counter: mapper.get_window_counter(),
+        }
+    }
+}
+
+/// A copy of the frame's VRAM and OAM with one line's registers. Unlike the
+/// `Mmu` it can be shared between threads.
+#[cfg(feature = "parallel")]
+struct LineVideo<'a> {
+    vram: &'a [u8; 0x2000],
+    oam: &'a [u8; 0xA0],
+    colors: &'a ColorPalette,
+    line: &'a DeferredLine,
+}
+
+#[cfg(feature = "parallel")]
+impl Video for LineVideo<'_> {
+    fn vram(&self) -> &[u8; 0x2000] {
+        self.vram
+    }
+
+    fn get_oam(&self) -> &[u8; 0xA0] {
+        self.oam
+    }
+
+    fn lcd_registers(&self) -> &[u8; 12] {
+        &self.line.registers
+    }
+
+    fn get_window_counter(&self) -> u8 {
+        self.line.window_counter
+    }
+
+    fn colors(&self) -> &ColorPalette {
+        self.colors
+    }
+}
+
+/// Approximate length of mode 3 (pixel transfer) in dots.
+///
+/// The renderer works a scanline at a time, so this doesn't model the pixel
+/// FIFO. It takes the 172 dot minimum, adds the `SCX % 8` pixels discarded at
+/// the start of the line and a flat 6 dot penalty per object on the line (the
+/// real penalty is 6 to 11 dots depending on the object's alignment with the
+/// background fetcher). This is enough to move the start of HBlank, and the
+/// HBlank STAT interrupt, later on busy lines.
+pub fn mode3_length(scx: u8, sprites: u8) -> u32 {
+    172 + (scx % 8) as u32 + 6 * sprites as u32
+}
+
+/// Number of objects selected by the OAM scan for a line, at most 10.
+pub fn count_sprites(mapper: &impl Video, line: u8) -> u8 {
+    oam_scan(mapper, line).len() as u8
+}
+
+/// The first 10 objects in OAM that cover `line`. Only Y is checked, objects
+/// off either side of the screen still take up a slot.
+fn oam_scan(mapper: &impl Video, line: u8) -> Vec<ObjectAttribute> {
+    let height = mapper.lcdc().obj_height();
+    let line = line as i16;
+    mapper
+        .get_oam()
+        .chunks_exact(4)
+        .map(|sprite| ObjectAttribute::from_bytes(sprite.try_into().unwrap()))
+        .filter(|sprite| line >= sprite.y && line < sprite.y + height)
+        .take(10)
+        .collect()
+}
+
+/// An object pixel that isn't transparent
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SpritePixel {
+    /// Color index 1 to 3, before the object palette
+    color: u8,
+    /// OBP0 or OBP1
+    palette: usize,
+    /// Only drawn over background and window color 0
+    behind_bg: bool,
+}
+
+/// The object pixels on a line, `None` where no object is drawn
+fn fetch_sprites(mapper: &impl Video, line: u8) -> [Option<SpritePixel>; 160] {
+    let mut pixels = [None; 160];
+    let offset = mapper.lcdc().obj_height();
+    let mut sprites = oam_scan(mapper, line);
+    let line = line as i16;
+    /* The object with the lower X is on top, or the one earlier in OAM if
+     * they're level. The sort is stable, so drawing back to front leaves
+     * that one over the others. */
+    sprites.sort_by_key(|sprite| sprite.x);
+
+    for sprite in sprites.iter().rev() {
+        /* Flipping 8x16 objects flips the pair of tiles as a whole, so the
+         * second tile's last row is drawn first */
         let tile_line = match sprite.y_flip {
-            true => (offset - (line - sprite.y) - 1) % offset,
+            true => offset - 1 - (line - sprite.y),
             false => line - sprite.y,
         };
 
-        let tile_start = match offset {
-            8 => (sprite.tile as usize * 16).wrapping_add(tile_line as usize * 2),
-            16 => ((sprite.tile & 0xFE) as usize * 16).wrapping_add(tile_line as usize * 2),
+        let tile = match offset {
+            8 => sprite.tile,
+            16 => (sprite.tile & 0xFE) | (tile_line / 8) as u8,
             _ => unreachable!(),
         };
-
-        let tile_end = tile_start + 2;
+        let row = (tile_line % 8) as usize * 2;
+        let tile = mapper.tile_bytes(tile, false);
 
         let tile = match sprite.x_flip {
-            true => {
-                let mut tile = [0u8; 2];
-                for i in 0..8 {
-                    tile[0] |= (tiles[tile_start] >> i & 0b1) << (7 - i);
-                    tile[1] |= (tiles[tile_start + 1] >> i & 0b1) << (7 - i);
-                }
-                tile
-            }
-            false => tiles[tile_start..tile_end].try_into().unwrap(),
+            true => [tile[row].reverse_bits(), tile[row + 1].reverse_bits()],
+            false => [tile[row], tile[row + 1]],
         };
 
         for x in 0..8 {
-            if sprite.x.wrapping_add(x) >= 160 {
-                continue;
-            }
-            if sprite.x.wrapping_add(x) < 0 {
+            let screen_x = sprite.x.wrapping_add(x);
+            if !(0..160).contains(&screen_x) {
                 continue;
             }
-            let start = (sprite.x as usize).wrapping_add(x as usize) * 4;
-            let end = start + 4;
 
             let color = ((tile[1] >> (7 - x) & 0b1) << 1) | (tile[0] >> (7 - x) & 0b1);
 
             if color != 0 {
-                output[start..end].copy_from_slice(
-                    match mapper.get_obj_palette(sprite.palette)[color as usize] {
-                        Palette::White => &[232, 252, 204, 255],
-                        Palette::LightGray => &[172, 212, 144, 255],
-                        Palette::DarkGray => &[84, 140, 112, 255],
-                        Palette::Black => &[20, 44, 56, 255],
-                    },
-                );
-                if sprite.priority {
-                    output[start + 3] = 128;
-                }
+                pixels[screen_x as usize] = Some(SpritePixel {
+                    color,
+                    palette: sprite.palette,
+                    behind_bg: sprite.priority,
+                });
             }
         }
+    }
+    pixels
+}
 
-        tile_count += 1;
-        x_values.push(sprite.x);
-        if tile_count >= 10 {
-            break;
+/// Draws the objects on a line alone. Pixels without an object are left
+/// untouched, objects behind the background get an alpha of 128.
+pub fn draw_sprites(mapper: &impl Video, line: u8, output: &mut [u8]) {
+    for (pixel, sprite) in output.chunks_exact_mut(4).zip(fetch_sprites(mapper, line)) {
+        if let Some(sprite) = sprite {
+            let shade = mapper.get_obj_palette(sprite.palette)[sprite.color as usize];
+            pixel.copy_from_slice(mapper.colors().rgba(shade));
+            if sprite.behind_bg {
+                pixel[3] = 128;
+            }
         }
     }
 }
 
-pub fn draw_window(mapper: &Mmu, line: u8, output: &mut [u8]) {
-    let tiles = mapper.get_bg_tile_data();
-    let tilemap = mapper.get_window_tile_map();
+/// Whether any part of the window is on screen for a line. The window line
+/// counter only advances on these lines.
+pub fn window_visible(mapper: &impl Video, line: u8) -> bool {
     let (win_y, win_x) = mapper.get_window_pos();
+    mapper.lcdc().window_enable && win_y <= line && win_y < 144 && win_x < 167
+}
+
+/// The window's color indexes on a line, `None` left of the window or on
+/// lines it doesn't cover
+fn fetch_window(mapper: &impl Video, line: u8) -> [Option<u8>; 160] {
+    let mut pixels = [None; 160];
+    let tilemap = mapper.get_window_tile_map();
+    let (_, win_x) = mapper.get_window_pos();
 
-    if line < win_y {
-        return;
+    if !window_visible(mapper, line) {
+        return pixels;
     }
 
     let y = mapper.get_window_counter();
 
-    for (index, pixel) in output.chunks_exact_mut(4).enumerate() {
-        if index < (win_x as usize).saturating_sub(7) {
+    /* WX is the left edge plus 7, WX < 7 shifts the window's first pixels off screen */
+    let left = win_x as i16 - 7;
+
+    for (index, pixel) in pixels.iter_mut().enumerate() {
+        let x = index as i16 - left;
+        if x < 0 {
             continue;
         }
-        let x = index.wrapping_sub(win_x as usize).wrapping_add(7);
+        let x = x as usize;
         let start = (y as usize / 8) * 32 + (x / 8);
-        let start = tilemap[start] as usize;
-        let tile = match mapper.get_tile_mode() {
-            true => &tiles[start * 16..start * 16 + 16],
-            false => {
-                let start = start as i8 as i16;
-                let start = (start * 16 + 0x800) as usize;
-                &tiles[start..start + 16]
-            }
-        };
+        let tile = mapper.bg_tile_bytes(tilemap[start]);
         let y = y % 8;
         let x = x % 8;
         let z = ((tile[y as usize * 2 + 1] >> (7 - x) & 0b1) << 1)
             | (tile[y as usize * 2] >> (7 - x) & 0b1);
 
-        pixel.copy_from_slice(match mapper.get_bg_palette()[z as usize] {
-            Palette::White => &[232, 252, 204, 255],
-            Palette::LightGray => &[172, 212, 144, 255],
-            Palette::DarkGray => &[84, 140, 112, 255],
-            Palette::Black => &[20, 44, 56, 255],
-        });
+        *pixel = Some(z);
     }
+    pixels
 }
 
-pub fn draw_scanline(mapper: &Mmu, frame: &mut [u8], scx: u8, scy: u8, line: u8) {
-    let tiles = mapper.get_bg_tile_data();
+pub fn draw_window(mapper: &impl Video, line: u8, output: &mut [u8]) {
+    let palette = mapper.get_bg_palette();
+    for (pixel, color) in output.chunks_exact_mut(4).zip(fetch_window(mapper, line)) {
+        if let Some(color) = color {
+            pixel.copy_from_slice(mapper.colors().rgba(palette[color as usize]));
+        }
+    }
+}
+
+/// Color index of the background pixel at screen position `x` on `line`,
+/// scrolled by SCX and SCY with the 256x256 map wrapping on both axes
+fn bg_color(mapper: &impl Video, tilemap: &[u8; 0x400], x: u8, line: u8, scx: u8, scy: u8) -> u8 {
+    let x = x.wrapping_add(scx) as usize;
+    let y = line.wrapping_add(scy) as usize;
+    let tile = mapper.bg_tile_bytes(tilemap[(y / 8) * 32 + x / 8]);
+    let (x, y) = (x % 8, y % 8);
+    (tile[y * 2 + 1] >> (7 - x) & 0b1) << 1 | (tile[y * 2] >> (7 - x) & 0b1)
+}
+
+/// Draws one line of the background alone, ignoring LCDC bit 0
+pub fn draw_background(mapper: &impl Video, line: u8, scx: u8, scy: u8, output: &mut [u8]) {
     let tilemap = mapper.get_bg_tile_map();
-    let sprites = &mut [0u8; 160 * 4];
-    let window = &mut [0u8; 160 * 4];
+    let palette = mapper.get_bg_palette();
+    for (x, pixel) in output.chunks_exact_mut(4).enumerate() {
+        let color = bg_color(mapper, tilemap, x as u8, line, scx, scy);
+        pixel.copy_from_slice(mapper.colors().rgba(palette[color as usize]));
+    }
+}
+
+/// What each pixel of a line was drawn from, for comparisons that don't
+/// depend on the colors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineShades {
+    /// Shade after the palettes, 0 is the lightest
+    pub shades: [u8; 160],
+    /// Color index 0 to 3 from the tile, before the palettes. 0 where LCDC
+    /// bit 0 blanks the background.
+    pub colors: [u8; 160],
+}
 
+/// Draws a line into the RGBA frame, returning what each pixel was drawn
+/// from
+pub fn draw_scanline(
+    mapper: &impl Video,
+    frame: &mut [u8],
+    scx: u8,
+    scy: u8,
+    line: u8,
+) -> LineShades {
     let start = line as usize * 160 * 4;
-    let end = start + 160 * 4;
+    draw_line(mapper, &mut frame[start..start + 160 * 4], scx, scy, line)
+}
 
-    if mapper.get_obj_enable() {
-        draw_sprites(mapper, line, sprites);
-    }
-    if mapper.get_window_enable() {
-        draw_window(mapper, line, window);
-    }
+/// Draws `lines` into the RGBA frame in parallel, from VRAM and OAM as they
+/// are now and the registers each line saw. Returns what each line was
+/// drawn from like `draw_scanline`.
+#[cfg(feature = "parallel")]
+pub fn draw_deferred(
+    mapper: &Mmu,
+    frame: &mut [u8],
+    lines: &[DeferredLine],
+) -> Vec<(u8, LineShades)> {
+    use rayon::prelude::*;
 
-    let sprites = sprites.chunks_exact(4);
-    let window = window.chunks_exact(4);
+    let vram = Box::new(*mapper.vram());
+    let oam = *mapper.get_oam();
+    let colors = *mapper.colors();
+    let mut rows: Vec<Option<&mut [u8]>> = frame.chunks_exact_mut(160 * 4).map(Some).collect();
+    let jobs: Vec<(&DeferredLine, &mut [u8])> = lines
+        .iter()
+        .filter_map(|line| Some((line, rows.get_mut(line.line as usize)?.take()?)))
+        .collect();
+    jobs.into_par_iter()
+        .map(|(line, output)| {
+            let video = LineVideo {
+                vram: &vram,
+                oam: &oam,
+                colors: &colors,
+                line,
+            };
+            let (scx, scy) = (line.registers[0x3], line.registers[0x2]);
+            (line.line, draw_line(&video, output, scx, scy, line.line))
+        })
+        .collect()
+}
 
-    for (real_idx, ((pixel, sprite), win)) in frame[start..end]
+/// Draws a line into `output`, its 160 RGBA pixels
+fn draw_line(mapper: &impl Video, output: &mut [u8], scx: u8, scy: u8, line: u8) -> LineShades {
+    let lcdc = mapper.lcdc();
+    let tilemap = mapper.get_bg_tile_map();
+    let bg_palette = mapper.get_bg_palette();
+    let sprites = match lcdc.obj_enable {
+        true => fetch_sprites(mapper, line),
+        false => [None; 160],
+    };
+    let window = fetch_window(mapper, line);
+    let colors = mapper.colors();
+
+    let mut drawn = LineShades {
+        shades: [0; 160],
+        colors: [0; 160],
+    };
+
+    for (x, ((pixel, sprite), win)) in output
         .chunks_exact_mut(4)
         .zip(sprites)
         .zip(window)
         .enumerate()
     {
-        if sprite.iter().any(|x| *x != 0) {
-            pixel.copy_from_slice(sprite);
-            if pixel[3] == 255 {
-                continue;
-            }
+        /* With LCDC bit 0 clear the background and window are blank white,
+         * and objects show over them regardless of priority */
+        let bg = match lcdc.bg_enable {
+            true => Some(win.unwrap_or_else(|| bg_color(mapper, tilemap, x as u8, line, scx, scy))),
+            false => None,
+        };
+
+        let (color, shade) = match sprite {
+            Some(sprite) if !sprite.behind_bg || bg.unwrap_or(0) == 0 => (
+                sprite.color,
+                mapper.get_obj_palette(sprite.palette)[sprite.color as usize],
+            ),
+            _ => match bg {
+                Some(color) => (color, bg_palette[color as usize]),
+                None => (0, Palette::White),
+            },
+        };
+        pixel.copy_from_slice(colors.rgba(shade));
+        drawn.shades[x] = shade as u8;
+        drawn.colors[x] = color;
+    }
+    drawn
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::{Interrupt, Mmu};
+    use crate::test_rom;
+    use crate::Cpu;
+
+    /* Frame with a window of tile 1 (top row dark gray, left column black,
+     * light gray elsewhere) over a white background, as gray levels where 3
+     * is white like the reference images */
+    fn render_window(wx: u8, wy: u8) -> Vec<u8> {
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        for i in 0..8 {
+            let (low, high) = if i == 0 { (0x00, 0xFF) } else { (0xFF, 0x80) };
+            cpu.mmu.write_byte(0x8010 + i * 2, low);
+            cpu.mmu.write_byte(0x8011 + i * 2, high);
+        }
+        for i in 0..0x400 {
+            cpu.mmu.write_byte(0x9C00 + i, 1);
         }
+        cpu.mmu.write_byte(0xFF40, 0b1111_0001);
+        cpu.mmu.write_byte(0xFF47, 0b1110_0100);
+        cpu.mmu.write_byte(0xFF4A, wy);
+        cpu.mmu.write_byte(0xFF4B, wx);
 
-        if !mapper.get_bg_enable() {
-            pixel.copy_from_slice(&[232, 252, 204, 255]);
-            continue;
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        frame
+            .chunks_exact(4)
+            .map(|pixel| match pixel[0] {
+                232 => 3,
+                172 => 2,
+                84 => 1,
+                20 => 0,
+                _ => panic!("unexpected color {:?}", pixel),
+            })
+            .collect()
+    }
+
+    /* Plain (P2) PGM, 160x144 with a maximum value of 3 */
+    fn load_reference(pgm: &str) -> Vec<u8> {
+        let mut values = pgm
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(str::split_whitespace)
+            .skip(1)
+            .map(|value| value.parse::<u8>().unwrap());
+        assert_eq!(values.next(), Some(160));
+        assert_eq!(values.next(), Some(144));
+        assert_eq!(values.next(), Some(3));
+        values.collect()
+    }
+
+    fn assert_matches_reference(wx: u8, wy: u8, pgm: &str) {
+        let frame = render_window(wx, wy);
+        let reference = load_reference(pgm);
+        assert_eq!(frame.len(), reference.len());
+        if let Some(index) = (0..frame.len()).find(|&i| frame[i] != reference[i]) {
+            panic!(
+                "WX={} WY={}: first mismatch at ({}, {}), got {} expected {}",
+                wx,
+                wy,
+                index % 160,
+                index / 160,
+                frame[index],
+                reference[index]
+            );
         }
+    }
 
-        if win.iter().copied().map(Wrapping).sum::<Wrapping<u8>>().0 != 0 {
-            match pixel[3] {
-                0 => {
-                    pixel.copy_from_slice(win);
-                    continue;
-                }
-                128 => {
-                    if win[0] != 232 {
-                        pixel.copy_from_slice(win);
-                        continue;
-                    }
-                }
-                _ => {
-                    pixel.copy_from_slice(win);
-                    continue;
-                }
-            };
+    #[test]
+    fn window_left_edge_off_screen() {
+        let mut mmu = Mmu::new();
+        /* Tile 1, row 1: black first column, light gray elsewhere */
+        mmu.write_byte(0x8012, 0xFF);
+        mmu.write_byte(0x8013, 0x80);
+        for i in 0..0x400 {
+            mmu.write_byte(0x9C00 + i, 1);
         }
+        mmu.write_byte(0xFF40, 0b1111_0001);
+        mmu.write_byte(0xFF47, 0b1110_0100);
+        mmu.write_byte(0xFF4A, 0);
+        mmu.set_window_counter(1);
 
-        let real_idx = real_idx.wrapping_add(start / 4);
-        let idx = (real_idx as u16 % 160)
-            .wrapping_add(scx as u16)
-            .wrapping_add(
-                (real_idx as u16 / 160)
-                    .wrapping_add(scy as u16)
-                    .wrapping_mul(256),
+        for wx in 0..7 {
+            mmu.write_byte(0xFF4B, wx);
+            let mut output = [0; 160 * 4];
+            draw_window(&mmu, 1, &mut output);
+            /* The first black column lands where window pixel 8 is */
+            let black = (0..160).find(|x| output[x * 4] == 20).unwrap();
+            assert_eq!(black, wx as usize + 1, "WX={}", wx);
+            assert_eq!(output[0], 172, "WX={}", wx);
+            /* No gap on either side */
+            assert!(
+                output.chunks_exact(4).all(|pixel| pixel[3] != 0),
+                "WX={}",
+                wx
             );
-        let y = idx / 256;
-        let x = idx % 256;
-        let tilenum = ((y / 8) * 32 + x / 8) as usize;
-        let tile = tilemap[tilenum];
-        let tile = match mapper.get_tile_mode() {
-            true => &tiles[tile as usize * 16..tile as usize * 16 + 16],
-            false => {
-                let tile = tile as i8 as i16;
-                let tile = (tile * 16 + 0x800) as usize;
-                &tiles[tile..tile + 16]
+        }
+    }
+
+    #[test]
+    fn window_right_edge() {
+        for (wx, columns) in [(160, 7), (166, 1), (167, 0)] {
+            let mut mmu = Mmu::new();
+            mmu.write_byte(0x8012, 0xFF);
+            mmu.write_byte(0x8013, 0x80);
+            for i in 0..0x400 {
+                mmu.write_byte(0x9C00 + i, 1);
             }
+            mmu.write_byte(0xFF40, 0b1111_0001);
+            mmu.write_byte(0xFF47, 0b1110_0100);
+            mmu.write_byte(0xFF4A, 0);
+            mmu.write_byte(0xFF4B, wx);
+            mmu.set_window_counter(1);
+
+            let mut output = [0; 160 * 4];
+            draw_window(&mmu, 1, &mut output);
+            let drawn = output.chunks_exact(4).filter(|pixel| pixel[3] != 0).count();
+            assert_eq!(drawn, columns, "WX={}", wx);
+            if columns > 0 {
+                /* Window column 0 is black */
+                assert_eq!(output[(160 - columns) * 4], 20, "WX={}", wx);
+            }
+            assert_eq!(window_visible(&mmu, 1), columns > 0, "WX={}", wx);
+        }
+
+        /* The window line counter advances while a single column shows */
+        let mut cpu = test_rom::boot(&[0x18, 0xFE]);
+        cpu.mmu.write_byte(0xFF40, 0b1111_0001);
+        cpu.mmu.write_byte(0xFF4A, 0);
+        cpu.mmu.write_byte(0xFF4B, 166);
+        let mut frame = vec![0; 160 * 144 * 4];
+        cpu.game_loop(&mut frame);
+        assert_eq!(cpu.mmu.get_window_counter(), 144);
+    }
+
+    #[test]
+    fn signed_tile_addressing_renders_0x8800() {
+        let mut mmu = Mmu::new();
+        /* Tile 0x80 is at 0x8800 in the signed mode, black on its first row.
+         * Tile 0x00 at 0x9000 is black too and must not be picked instead. */
+        mmu.write_byte(0x8800, 0xFF);
+        mmu.write_byte(0x8801, 0xFF);
+        mmu.write_byte(0x9000, 0xFF);
+        mmu.write_byte(0x9001, 0xFF);
+        for map in [0x9800, 0x9C00] {
+            mmu.write_byte(map, 0x80);
+            mmu.write_byte(map + 1, 0x81);
+        }
+        mmu.write_byte(0xFF47, 0b1110_0100);
+
+        for lcdc in [0b1000_0001, 0b1110_0001] {
+            /* Background, then the window at the left edge over it */
+            mmu.write_byte(0xFF40, lcdc);
+            mmu.write_byte(0xFF4B, 7);
+            let mut frame = vec![0; 160 * 144 * 4];
+            draw_scanline(&mmu, &mut frame, 0, 0, 0);
+            assert!(frame[..8 * 4].chunks_exact(4).all(|pixel| pixel[0] == 20));
+            assert!(frame[8 * 4..16 * 4]
+                .chunks_exact(4)
+                .all(|pixel| pixel[0] == 232));
+        }
+    }
+
+    #[test]
+    fn background_wraps_horizontally_on_the_same_row() {
+        let mut mmu = Mmu::new();
+        /* Tile 1 is black, row 0 of the map starts with it and row 1 doesn't */
+        for address in 0x8010..0x8020 {
+            mmu.write_byte(address, 0xFF);
+        }
+        mmu.write_byte(0x9800, 0x01);
+        mmu.write_byte(0xFF40, 0b1001_0001);
+        mmu.write_byte(0xFF47, 0b1110_0100);
+        mmu.write_byte(0xFF43, 200);
+
+        /* Screen X 56 is map X 256, back at the start of row 0 */
+        let layer = mmu.render_background_layer(0);
+        assert!(layer[..56 * 4].chunks_exact(4).all(|pixel| pixel[0] == 232));
+        assert!(layer[56 * 4..64 * 4]
+            .chunks_exact(4)
+            .all(|pixel| pixel[0] == 20));
+
+        let mut frame = vec![0; 160 * 144 * 4];
+        draw_scanline(&mmu, &mut frame, 200, 0, 0);
+        assert_eq!(frame[..160 * 4], layer);
+    }
+
+    #[test]
+    fn color_palette_changes_colors_not_shades() {
+        let mut mmu = Mmu::new();
+        mmu.skip_boot();
+        /* Solid color 1 tiles, shown as shade 2 through BGP */
+        for address in (0x8000..0x8010).step_by(2) {
+            mmu.write_byte(address, 0xFF);
+        }
+        mmu.write_byte(0xFF47, 0b0000_1000);
+        let mut frame = vec![0; 160 * 144 * 4];
+        let shades = draw_scanline(&mmu, &mut frame, 0, 0, 0);
+        assert_eq!(frame[..4], DEFAULT_PALETTE[2]);
+
+        mmu.set_colors(ColorPalette::GRAYSCALE);
+        let mut gray = vec![0; 160 * 144 * 4];
+        assert_eq!(draw_scanline(&mmu, &mut gray, 0, 0, 0), shades);
+        assert_eq!(gray[..4], [85, 85, 85, 255]);
+
+        assert_eq!(
+            ColorPalette::from_name("Pocket"),
+            Some(ColorPalette::POCKET)
+        );
+        assert_eq!(ColorPalette::from_name("sepia"), None);
+    }
+
+    #[test]
+    fn layers_render_in_isolation() {
+        let mut mmu = Mmu::new();
+        for address in 0x8010..0x8020 {
+            mmu.write_byte(address, 0xFF);
+        }
+        /* Window map filled with the black tile, shown from X 80 */
+        for address in 0x9C00..0x9C20 {
+            mmu.write_byte(address, 0x01);
+        }
+        mmu.write_byte(0xFF40, 0b1111_0011);
+        mmu.write_byte(0xFF47, 0b1110_0100);
+        mmu.write_byte(0xFF48, 0b1110_0100);
+        mmu.write_byte(0xFF4B, 87);
+        /* One black object at X 8 on lines 0-7 */
+        for (address, value) in [(0xFE00, 16), (0xFE01, 16), (0xFE02, 0x01), (0xFE03, 0)] {
+            mmu.write_byte(address, value);
+        }
+
+        let window = mmu.render_window_layer(0);
+        assert!(window[..80 * 4].iter().all(|&byte| byte == 0));
+        assert!(window[80 * 4..].chunks_exact(4).all(|pixel| pixel[0] == 20));
+
+        let sprites = mmu.render_sprite_layer(0);
+        assert!(sprites[..8 * 4].iter().all(|&byte| byte == 0));
+        assert!(sprites[8 * 4..16 * 4]
+            .chunks_exact(4)
+            .all(|pixel| pixel[0] == 20));
+        assert!(sprites[16 * 4..].iter().all(|&byte| byte == 0));
+
+        let background = mmu.render_background_layer(0);
+        assert!(background.chunks_exact(4).all(|pixel| pixel[0] == 232));
+    }
+
+    /* A light gray object behind the background at X 4-11, over a window
+     * whose color 0 is black under BGP and color 3 is white */
+    fn render_behind_bg(lcdc: u8) -> Vec<u8> {
+        let mut mmu = Mmu::new();
+        for address in 0x8010..0x8020 {
+            mmu.write_byte(address, 0xFF);
+        }
+        mmu.write_byte(0x9801, 0x01);
+        mmu.write_byte(0xFF47, 0b0001_1011);
+        mmu.write_byte(0xFF48, 0b0100_0000);
+        mmu.write_byte(0xFF4B, 7);
+        for (address, value) in [(0xFE00, 16), (0xFE01, 12), (0xFE02, 0x01), (0xFE03, 0x80)] {
+            mmu.write_byte(address, value);
+        }
+        mmu.write_byte(0xFF40, lcdc);
+        let mut frame = vec![0; 160 * 144 * 4];
+        draw_scanline(&mmu, &mut frame, 0, 0, 0);
+        frame[..24 * 4].to_vec()
+    }
+
+    #[test]
+    fn behind_bg_priority_uses_color_indexes() {
+        let shades =
+            |frame: &[u8]| -> Vec<u8> { frame.chunks_exact(4).map(|pixel| pixel[0]).collect() };
+        let (black, gray, white) = (20, 172, 232);
+
+        /* Shown over window color 0 even though it is drawn black, hidden
+         * by color 3 even though it is drawn white */
+        let mut expected = vec![black; 4];
+        expected.extend([gray; 4]);
+        expected.extend([white; 8]);
+        expected.extend([black; 8]);
+        assert_eq!(shades(&render_behind_bg(0b1011_0011)), expected);
+
+        /* With the background and window off the object is always on top */
+        let mut expected = vec![white; 4];
+        expected.extend([gray; 8]);
+        expected.extend([white; 12]);
+        assert_eq!(shades(&render_behind_bg(0b1011_0010)), expected);
+    }
+
+    /* Two 8x16 black objects at lines 20-35 and 50-65, with the LYC
+     * interrupt handler flipping `lcdc_bits` in LCDC from line 40 on */
+    /* Flips `lcdc_bits` of LCDC at the LYC interrupt on line 40, with an
+     * object above and below it */
+    fn lcdc_split(lcdc_bits: u8) -> Cpu {
+        #[rustfmt::skip]
+        let mut cpu = test_rom::boot_with(&[
+            (0x48, &[
+                0xF0, 0x40,       /* ldh a, [LCDC] */
+                0xEE, lcdc_bits,  /* xor lcdc_bits */
+                0xE0, 0x40,       /* ldh [LCDC], a */
+                0xD9,             /* reti */
+            ]),
+            (0x100, &[
+                0xFB,             /* ei */
+                0x18, 0xFE,       /* jr -2 */
+            ]),
+        ]);
+        for i in 0..0x20 {
+            cpu.mmu.write_byte(0x8020 + i, 0xFF);
+        }
+        for (index, y) in [20, 50].into_iter().enumerate() {
+            let sprite = 0xFE00 + index as u16 * 4;
+            cpu.mmu.write_byte(sprite, y + 16);
+            cpu.mmu.write_byte(sprite + 1, 8 + index as u8 * 16);
+            cpu.mmu.write_byte(sprite + 2, 0x02);
+        }
+        cpu.mmu.write_byte(0xFF40, 0b1000_0011);
+        cpu.mmu.write_byte(0xFF41, 0b0100_0000);
+        cpu.mmu.write_byte(0xFF45, 40);
+        cpu.mmu.write_byte(0xFF47, 0b1110_0100);
+        cpu.mmu.write_byte(0xFF48, 0b1110_0100);
+        cpu.mmu.write_byte(0xFFFF, Interrupt::Stat.mask());
+        /* LY = LYC = 0 after the boot ROM, drop the edge from enabling the source */
+        cpu.mmu.write_byte(0xFF0F, 0);
+        cpu
+    }
+
+    fn render_lcdc_split(lcdc_bits: u8) -> Vec<u8> {
+        let mut frame = vec![0; 160 * 144 * 4];
+        lcdc_split(lcdc_bits).game_loop(&mut frame);
+        frame
+    }
+
+    fn is_black(frame: &[u8], x: usize, y: usize) -> bool {
+        frame[(y * 160 + x) * 4] == 20
+    }
+
+    #[test]
+    fn object_size_changes_mid_frame() {
+        let frame = render_lcdc_split(0b0000_0100);
+        /* 8x8 above line 40 */
+        assert!(is_black(&frame, 0, 27));
+        assert!(!is_black(&frame, 0, 28));
+        /* 8x16 from line 40 */
+        assert!(is_black(&frame, 16, 50));
+        assert!(is_black(&frame, 16, 65));
+        assert!(!is_black(&frame, 16, 66));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_render_keeps_mid_frame_registers() {
+        for lcdc_bits in [0b0000_0010, 0b0000_0100] {
+            let mut cpu = lcdc_split(lcdc_bits);
+            cpu.set_parallel_render(true);
+            let mut frame = vec![0; 160 * 144 * 4];
+            cpu.game_loop(&mut frame);
+            assert!(frame == render_lcdc_split(lcdc_bits));
+        }
+    }
+
+    #[test]
+    fn object_enable_changes_mid_frame() {
+        let frame = render_lcdc_split(0b0000_0010);
+        assert!(is_black(&frame, 0, 20));
+        assert!(!is_black(&frame, 16, 50));
+    }
+
+    #[test]
+    fn window_reference_frames() {
+        assert_matches_reference(7, 0, include_str!("../tests/reference/window_wx7_wy0.pgm"));
+        assert_matches_reference(
+            3,
+            10,
+            include_str!("../tests/reference/window_wx3_wy10.pgm"),
+        );
+        assert_matches_reference(
+            80,
+            72,
+            include_str!("../tests/reference/window_wx80_wy72.pgm"),
+        );
+        assert_matches_reference(
+            166,
+            0,
+            include_str!("../tests/reference/window_wx166_wy0.pgm"),
+        );
+        assert_matches_reference(
+            0,
+            140,
+            include_str!("../tests/reference/window_wx0_wy140.pgm"),
+        );
+    }
+
+    #[test]
+    fn mode3_length_minimum() {
+        assert_eq!(mode3_length(0, 0), 172);
+        assert_eq!(mode3_length(8, 0), 172);
+        assert_eq!(mode3_length(7, 0), 179);
+    }
+
+    #[test]
+    fn mode3_length_grows_with_sprites() {
+        let mut last = mode3_length(0, 0);
+        for sprites in 1..=10 {
+            let length = mode3_length(0, sprites);
+            assert!(length > last);
+            last = length;
+        }
+        assert_eq!(mode3_length(0, 10), 232);
+    }
+
+    #[test]
+    fn lcdc_bits() {
+        let lcdc = Lcdc::from_u8(0x91);
+        assert_eq!(
+            lcdc,
+            Lcdc {
+                lcd_enable: true,
+                window_map_mode: false,
+                window_enable: false,
+                tile_mode: true,
+                bg_map_mode: false,
+                obj_size: false,
+                obj_enable: false,
+                bg_enable: true,
+            }
+        );
+        assert_eq!(lcdc.obj_height(), 8);
+        assert_eq!(Lcdc::from_u8(0b0110_1110).obj_height(), 16);
+        assert!(Lcdc::from_u8(0b0110_1110).window_map_mode);
+    }
+
+    #[test]
+    fn lower_x_sprite_on_top() {
+        let mut mmu = Mmu::new();
+        for row in 0..8 {
+            /* Tile 1 color 3, tile 2 color 1, tile 3 color 1 on the right half */
+            mmu.write_byte(0x8010 + row * 2, 0xFF);
+            mmu.write_byte(0x8011 + row * 2, 0xFF);
+            mmu.write_byte(0x8020 + row * 2, 0xFF);
+            mmu.write_byte(0x8030 + row * 2, 0x0F);
+        }
+        /* (X on screen, tile) in OAM order: the later object overlaps the
+         * right of the first, then two level objects where the first is
+         * transparent on the left */
+        for (index, (x, tile)) in [(0, 2), (4, 1), (20, 3), (20, 1)].into_iter().enumerate() {
+            let sprite = 0xFE00 + index as u16 * 4;
+            mmu.write_byte(sprite, 16);
+            mmu.write_byte(sprite + 1, x + 8);
+            mmu.write_byte(sprite + 2, tile);
+        }
+
+        let colors: Vec<u8> = fetch_sprites(&mmu, 0)
+            .iter()
+            .map(|pixel| pixel.map_or(0, |pixel| pixel.color))
+            .collect();
+        assert_eq!(colors[0..12], [1, 1, 1, 1, 1, 1, 1, 1, 3, 3, 3, 3]);
+        assert_eq!(colors[20..28], [3, 3, 3, 3, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn eleventh_sprite_on_a_line_is_dropped() {
+        let mut mmu = Mmu::new();
+        for row in 0..8 {
+            mmu.write_byte(0x8010 + row * 2, 0xFF);
+        }
+        /* 10 objects off the left of the screen still use up the line's
+         * slots, leaving none for the 11th */
+        for index in 0..11 {
+            let sprite = 0xFE00 + index * 4;
+            mmu.write_byte(sprite, 16);
+            mmu.write_byte(sprite + 1, if index < 10 { 0 } else { 8 });
+            mmu.write_byte(sprite + 2, 1);
+        }
+        assert_eq!(count_sprites(&mmu, 0), 10);
+        assert_eq!(fetch_sprites(&mmu, 0), [None; 160]);
+
+        /* With one off-screen object moved down the 11th is the 10th */
+        mmu.write_byte(0xFE00, 32);
+        assert_eq!(count_sprites(&mmu, 0), 10);
+        assert!(fetch_sprites(&mmu, 0)[0].is_some());
+    }
+
+    #[test]
+    fn tall_sprite_y_flip() {
+        let rows = |y_flip: bool| -> Vec<Vec<u8>> {
+            let mut mmu = Mmu::new();
+            /* Tiles 2 and 3 with a different pattern on each row, colors
+             * 0 and 1 in the top tile and 2 and 3 in the bottom one */
+            for row in 0..16 {
+                mmu.write_byte(0x8020 + row * 2, row as u8 * 17);
+                mmu.write_byte(0x8021 + row * 2, if row < 8 { 0 } else { 0xFF });
+            }
+            mmu.write_byte(0xFE00, 16);
+            mmu.write_byte(0xFE01, 8);
+            /* The low bit of the tile index is ignored */
+            mmu.write_byte(0xFE02, 3);
+            mmu.write_byte(0xFE03, (y_flip as u8) << 6);
+            mmu.write_byte(0xFF40, 0b0000_0100);
+            (0..16)
+                .map(|line| {
+                    fetch_sprites(&mmu, line)[..8]
+                        .iter()
+                        .map(|pixel| pixel.map_or(0, |pixel| pixel.color))
+                        .collect()
+                })
+                .collect()
         };
-        let y = y % 8;
-        let x = x % 8;
-        let z = ((tile[y as usize * 2 + 1] >> (7 - x) & 0b1) << 1)
-            | (tile[y as usize * 2] >> (7 - x) & 0b1);
-        if z == 0 && pixel[3] == 128 {
-            pixel[3] = 255;
-            continue;
+        let (upright, flipped) = (rows(false), rows(true));
+        assert!(upright[0].iter().all(|&color| color < 2));
+        assert!(flipped[0].iter().all(|&color| color >= 2));
+        for line in 0..16 {
+            assert_eq!(flipped[line], upright[15 - line], "line {}", line);
+        }
+    }
+
+    #[test]
+    fn count_sprites_on_line() {
+        let mut mmu = Mmu::new();
+        /* 12 objects on lines 0-7, one on lines 8-15 */
+        for i in 0..12 {
+            mmu.write_byte(0xFE00 + i * 4, 16);
+            mmu.write_byte(0xFE01 + i * 4, 8 + i as u8);
         }
-        pixel.copy_from_slice(match mapper.get_bg_palette()[z as usize] {
-            Palette::White => &[232, 252, 204, 255],
-            Palette::LightGray => &[172, 212, 144, 255],
-            Palette::DarkGray => &[84, 140, 112, 255],
-            Palette::Black => &[20, 44, 56, 255],
-        });
+        mmu.write_byte(0xFE00 + 12 * 4, 24);
+        mmu.write_byte(0xFE01 + 12 * 4, 8);
+
+        assert_eq!(count_sprites(&mmu, 0), 10);
+        assert_eq!(count_sprites(&mmu, 7), 10);
+        assert_eq!(count_sprites(&mmu, 8), 1);
+        assert_eq!(count_sprites(&mmu, 16), 0);
+        assert!(mode3_length(0, count_sprites(&mmu, 0)) > mode3_length(0, count_sprites(&mmu, 8)));
     }
 }
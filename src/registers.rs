@@ -140,6 +140,21 @@ pub enum R16OrSP<'a> {
     SP,
 }
 
+/// Plain copy of every CPU register, F packed from the flags
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RegisterFile {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
 pub struct Registers {
     pub a: Cell<u8>,
     pub b: Cell<u8>,
@@ -210,3 +225,9 @@ impl Registers {
         }
     }
 }
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+/// Start of every save state, followed by the format version
+pub const MAGIC: [u8; 4] = *b"TGBS";
+pub const VERSION: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u8),
+    WrongGame,
+    Truncated,
+    /// A value is out of range, the file is damaged
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "Not a trashgb save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Save state version {} isn't supported (expected {})",
+                    version, VERSION
+                )
+            }
+            StateError::WrongGame => write!(f, "Save state is for a different game"),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Builds a save state, fields are read back in the same order by
+/// `StateReader`
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Fills `output` with the next `output.len()` bytes
+    pub fn read_into(&mut self, output: &mut [u8]) -> Result<(), StateError> {
+        output.copy_from_slice(self.bytes(output.len())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+use crate::cpu::Cpu;
+
+/// A 32KiB ROM-only cartridge with `program` at the 0x0100 entry point
+pub fn build(program: &[u8]) -> Vec<u8> {
+    build_with(&[(0x100, program)])
+}
+
+/// A 32KiB ROM-only cartridge with each code section at its address, e.g.
+/// interrupt handlers next to the program
+pub fn build_with(sections: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    for (address, code) in sections {
+        rom[*address..*address + code.len()].copy_from_slice(code);
+    }
+    rom
+}
+
+/// A CPU running `program`, past the boot ROM
+pub fn boot(program: &[u8]) -> Cpu {
+    boot_with(&[(0x100, program)])
+}
+
+/// A CPU running the code sections from `build_with`, past the boot ROM
+pub fn boot_with(sections: &[(usize, &[u8])]) -> Cpu {
+    let mut cpu = Cpu::new();
+    cpu.mmu.load_game(build_with(sections).as_slice()).unwrap();
+    cpu
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::SharedBuffer;
+use std::path::Path;
+use trashgb::{Emulator, FrameStatus};
+
+/// Directory holding the cpu_instrs `individual/` ROMs, which can't be
+/// bundled. The test is skipped when it isn't set.
+const ROMS_VAR: &str = "BLARGG_CPU_INSTRS";
+
+const CPU_INSTRS: [&str; 11] = [
+    "01-special.gb",
+    "02-interrupts.gb",
+    "03-op sp,hl.gb",
+    "04-op r,imm.gb",
+    "05-op rp.gb",
+    "06-ld r,r.gb",
+    "07-jr,jp,call,ret,rst.gb",
+    "08-misc instrs.gb",
+    "09-op r,r.gb",
+    "10-bit ops.gb",
+    "11-op a,(hl).gb",
+];
+
+/// The longest sub-test takes about 20 seconds
+const FRAME_LIMIT: u32 = 60 * 60;
+
+/// Frames a ROM can sit in a loop without writing anything before it's
+/// given up on
+const HANG_FRAMES: u32 = 5 * 60;
+
+/// Runs `rom` until it reports a result over serial, hangs or the frame
+/// limit is reached, returning what it printed
+fn run(rom: &[u8]) -> String {
+    let output = SharedBuffer::default();
+    let mut emulator = Emulator::new(rom).unwrap();
+    emulator.cpu_mut().mmu.set_serial_sink(output.clone());
+    emulator.cpu_mut().set_hang_frames(Some(HANG_FRAMES));
+    let mut frame = vec![0; 160 * 144 * 4];
+    let mut text = String::new();
+    for _ in 0..FRAME_LIMIT {
+        let status = emulator.run_frame(&mut frame);
+        text = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+        if text.contains("Passed") || text.contains("Failed") {
+            break;
+        }
+        if status == FrameStatus::Hung {
+            text.push_str("\n(hung)");
+            break;
+        }
+    }
+    text
+}
+
+#[test]
+fn cpu_instrs() {
+    let Some(dir) = std::env::var_os(ROMS_VAR) else {
+        eprintln!("{} isn't set, skipping the cpu_instrs ROMs", ROMS_VAR);
+        return;
+    };
+    let mut failures = Vec::new();
+    for name in CPU_INSTRS {
+        let path = Path::new(&dir).join(name);
+        let rom = std::fs::read(&path)
+            .unwrap_or_else(|err| panic!("Couldn't read {}: {}", path.display(), err));
+        let output = run(&rom);
+        if !output.contains("Passed") {
+            failures.push(format!("{}:\n{}", name, output.trim_end()));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use common::rom;
+use trashgb::ppu::DEFAULT_PALETTE;
+use trashgb::{CartridgeError, Emulator};
+
+/// An emulator running `program`, past the boot ROM
+fn boot(program: &[u8]) -> Emulator {
+    Emulator::new(&rom(program)).unwrap()
+}
+
+#[test]
+fn runs_the_game() {
+    #[rustfmt::skip]
+    let mut emulator = boot(&[
+        0x3E, 0x42, /* ld a, 0x42 */
+        0xE0, 0x80, /* ldh [0xFF80], a */
+        0x18, 0xFE, /* jr -2 */
+    ]);
+    let mut frame = vec![0; 160 * 144 * 4];
+    assert!(emulator.step_frame(&mut frame));
+    assert_eq!(emulator.cpu().mmu.read_byte(0xFF80), 0x42);
+    /* Post-boot BGP maps the blank background to the lightest shade */
+    assert_eq!(frame[..4], DEFAULT_PALETTE[0]);
+}
+
+#[test]
+fn frame_callback_gets_each_frame() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut emulator = boot(&[0x18, 0xFE]);
+    let frames = Rc::new(RefCell::new(Vec::new()));
+    let seen = frames.clone();
+    emulator.set_frame_callback(move |frame| seen.borrow_mut().push(frame.to_vec()));
+    let mut frame = vec![0; 160 * 144 * 4];
+
+    emulator.step_frame(&mut frame);
+    emulator.step_frame(&mut frame);
+
+    let frames = frames.borrow();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[1], frame);
+}
+
+#[test]
+fn joypad_reaches_the_game() {
+    let mut emulator = boot(&[0x18, 0xFE]);
+    emulator.cpu_mut().mmu.write_byte(0xFF00, 0x10);
+
+    emulator.set_start(true);
+    assert_eq!(emulator.cpu().mmu.read_byte(0xFF00) & 0x0F, 0b0111);
+    emulator.set_start(false);
+    emulator.set_a(true);
+    assert_eq!(emulator.cpu().mmu.read_byte(0xFF00) & 0x0F, 0b1110);
+}
+
+#[test]
+fn bad_cartridge_is_an_error() {
+    assert!(matches!(
+        Emulator::new(&[0; 0x100]),
+        Err(CartridgeError::TooSmall(0x100))
+    ));
+}